@@ -0,0 +1,165 @@
+//! Opt-in query analytics (`query_analytics` in the config). Kept in memory
+//! only and never sent anywhere: a latency histogram plus per-query counts,
+//! zero-result hits and timings, reviewed through `/admin/analytics` to find
+//! missing content and slow query patterns.
+
+use crate::AppState;
+use actix_web::{HttpRequest, HttpResponse, Responder, delete, get, web};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Upper bounds (in ms) of the latency histogram buckets; slower queries fall
+/// into a final overflow bucket.
+const LATENCY_BUCKETS_MS: &[u64] = &[10, 25, 50, 100, 250, 500, 1000, 2500, 5000];
+/// New distinct queries are no longer tracked individually past this many.
+const MAX_TRACKED_QUERIES: usize = 10_000;
+const DEFAULT_REPORT_LIMIT: usize = 20;
+
+#[derive(Serialize, Clone, Default)]
+struct QueryStats {
+    count: u64,
+    zero_results: u64,
+    total_ms: u64,
+    max_ms: u64,
+}
+
+#[derive(Default)]
+struct AnalyticsData {
+    latency_counts: Vec<u64>,
+    searches: u64,
+    queries: HashMap<String, QueryStats>,
+}
+
+#[derive(Clone, Default)]
+pub struct QueryAnalytics {
+    data: Arc<Mutex<AnalyticsData>>,
+}
+
+#[derive(Serialize)]
+struct LatencyBucket {
+    /// `None` for the overflow bucket.
+    le_ms: Option<u64>,
+    count: u64,
+}
+
+#[derive(Serialize)]
+struct QueryReport {
+    query: String,
+    count: u64,
+    zero_results: u64,
+    avg_ms: u64,
+    max_ms: u64,
+}
+
+impl QueryReport {
+    fn new(query: &str, stats: &QueryStats) -> QueryReport {
+        QueryReport {
+            query: query.to_string(),
+            count: stats.count,
+            zero_results: stats.zero_results,
+            avg_ms: stats.total_ms / stats.count.max(1),
+            max_ms: stats.max_ms,
+        }
+    }
+}
+
+impl QueryAnalytics {
+    pub fn record(&self, query: &str, took_ms: u64, total_results: u64) {
+        let query = query.trim().to_lowercase();
+        let mut data = self.data.lock().unwrap();
+        if data.latency_counts.is_empty() {
+            data.latency_counts = vec![0; LATENCY_BUCKETS_MS.len() + 1];
+        }
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|&le| took_ms <= le)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        data.latency_counts[bucket] += 1;
+        data.searches += 1;
+
+        if !data.queries.contains_key(&query) && data.queries.len() >= MAX_TRACKED_QUERIES {
+            return;
+        }
+        let stats = data.queries.entry(query).or_default();
+        stats.count += 1;
+        stats.total_ms += took_ms;
+        stats.max_ms = stats.max_ms.max(took_ms);
+        if total_results == 0 {
+            stats.zero_results += 1;
+        }
+    }
+
+    fn report(&self, limit: usize) -> serde_json::Value {
+        let data = self.data.lock().unwrap();
+        let mut latency: Vec<LatencyBucket> = LATENCY_BUCKETS_MS
+            .iter()
+            .map(|&le| LatencyBucket {
+                le_ms: Some(le),
+                count: 0,
+            })
+            .chain([LatencyBucket {
+                le_ms: None,
+                count: 0,
+            }])
+            .collect();
+        for (bucket, count) in latency.iter_mut().zip(&data.latency_counts) {
+            bucket.count = *count;
+        }
+
+        let top = |key: fn(&QueryStats) -> u64, keep: fn(&QueryStats) -> bool| {
+            let mut queries: Vec<(&String, &QueryStats)> =
+                data.queries.iter().filter(|(_, s)| keep(s)).collect();
+            queries.sort_by(|a, b| key(b.1).cmp(&key(a.1)).then_with(|| a.0.cmp(b.0)));
+            queries
+                .into_iter()
+                .take(limit)
+                .map(|(q, s)| QueryReport::new(q, s))
+                .collect::<Vec<_>>()
+        };
+
+        json!({
+            "searches": data.searches,
+            "distinct_queries": data.queries.len(),
+            "latency_histogram": latency,
+            "most_frequent": top(|s| s.count, |_| true),
+            "zero_results": top(|s| s.zero_results, |s| s.zero_results > 0),
+            "slowest": top(|s| s.total_ms / s.count.max(1), |_| true),
+        })
+    }
+
+    fn reset(&self) {
+        *self.data.lock().unwrap() = AnalyticsData::default();
+    }
+}
+
+#[derive(Deserialize)]
+struct ReportQuery {
+    limit: Option<usize>,
+}
+
+#[get("/admin/analytics")]
+async fn analytics_report(
+    req: HttpRequest,
+    query: web::Query<ReportQuery>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    if !state.is_admin(&req) {
+        return HttpResponse::Unauthorized().json(json!({"error": "Invalid or missing token"}));
+    }
+    if !state.config.read().unwrap().query_analytics {
+        return HttpResponse::NotFound().json(json!({"error": "Query analytics are disabled"}));
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_REPORT_LIMIT);
+    HttpResponse::Ok().json(state.analytics.report(limit))
+}
+
+#[delete("/admin/analytics")]
+async fn reset_analytics(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    if !state.is_admin(&req) {
+        return HttpResponse::Unauthorized().json(json!({"error": "Invalid or missing token"}));
+    }
+    state.analytics.reset();
+    HttpResponse::Ok().json(json!({"reset": true}))
+}
@@ -0,0 +1,159 @@
+//! Directory-like view of a ZIM archive. Entry paths are split on `/`, so an
+//! entry `A/Foo/Bar` shows up as the file `Bar` inside the directories `A` and
+//! `Foo`. Shared by the WebDAV and FUSE front ends.
+
+use anyhow::{Result, anyhow};
+use zim_rs::archive::Archive;
+
+#[derive(Clone, Debug)]
+pub enum Node {
+    Dir,
+    File { size: u64, mimetype: String },
+}
+
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub name: String,
+    pub node: Node,
+}
+
+/// Normalizes a request path to the archive's form: no leading or trailing `/`.
+pub fn normalize(path: &str) -> &str {
+    path.trim_matches('/')
+}
+
+fn entry_path_at(zim: &Archive, idx: u32) -> Option<String> {
+    zim.get_entry_bypath_index(idx).ok().map(|e| e.get_path())
+}
+
+/// Index of the first entry whose path is not lower than `prefix`. Entries are
+/// sorted by path, so everything under a directory is one contiguous range.
+pub fn lower_bound(zim: &Archive, prefix: &str) -> u32 {
+    let (mut lo, mut hi) = (0, zim.get_all_entrycount());
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        match entry_path_at(zim, mid) {
+            Some(path) if path.as_str() < prefix => lo = mid + 1,
+            _ => hi = mid,
+        }
+    }
+    lo
+}
+
+fn file_node(zim: &Archive, path: &str) -> Option<Node> {
+    let entry = zim.get_entry_bypath_str(path).ok()?;
+    let item = entry.get_item(true).ok()?;
+    Some(Node::File {
+        size: item.get_size(),
+        mimetype: item
+            .get_mimetype()
+            .unwrap_or_else(|_| "application/octet-stream".to_string()),
+    })
+}
+
+fn dir_prefix(path: &str) -> String {
+    if path.is_empty() {
+        String::new()
+    } else {
+        format!("{}/", path)
+    }
+}
+
+/// Looks up what lives at `path`, an entry or a directory implied by entry paths.
+pub fn stat(zim: &Archive, path: &str) -> Option<Node> {
+    let path = normalize(path);
+    if path.is_empty() {
+        return Some(Node::Dir);
+    }
+    if zim.has_entry_bypath(path) {
+        return file_node(zim, path);
+    }
+    let prefix = dir_prefix(path);
+    let idx = lower_bound(zim, &prefix);
+    match entry_path_at(zim, idx) {
+        Some(first) if first.starts_with(&prefix) => Some(Node::Dir),
+        _ => None,
+    }
+}
+
+/// Lists the direct children of the directory at `path`.
+pub fn list_dir(zim: &Archive, path: &str) -> Result<Vec<DirEntry>> {
+    list_dir_page(zim, path, None, usize::MAX).map(|(children, _)| children)
+}
+
+/// Lists up to `limit` children of the directory at `path`, starting at the
+/// entry index `start` (a previous page's cursor). Also returns the cursor of
+/// the next page, if any.
+pub fn list_dir_page(
+    zim: &Archive,
+    path: &str,
+    start: Option<u32>,
+    limit: usize,
+) -> Result<(Vec<DirEntry>, Option<u32>)> {
+    let prefix = dir_prefix(normalize(path));
+    let total = zim.get_all_entrycount();
+    let mut children: Vec<DirEntry> = Vec::new();
+
+    let mut idx = start.unwrap_or_else(|| lower_bound(zim, &prefix));
+    while idx < total {
+        let Some(entry_path) = entry_path_at(zim, idx) else {
+            idx += 1;
+            continue;
+        };
+        let Some(rest) = entry_path.strip_prefix(&prefix) else {
+            break;
+        };
+
+        match rest.split_once('/') {
+            Some((dir_name, _)) => {
+                let is_new = children
+                    .last()
+                    .is_none_or(|last| last.name != dir_name || !matches!(last.node, Node::Dir));
+                if is_new && !dir_name.is_empty() {
+                    // Stopping only before a new child keeps a directory's
+                    // entries from spilling onto the next page.
+                    if children.len() >= limit {
+                        return Ok((children, Some(idx)));
+                    }
+                    children.push(DirEntry {
+                        name: dir_name.to_string(),
+                        node: Node::Dir,
+                    });
+                }
+            }
+            None if !rest.is_empty() => {
+                if let Some(node) = file_node(zim, &entry_path) {
+                    if children.len() >= limit {
+                        return Ok((children, Some(idx)));
+                    }
+                    children.push(DirEntry {
+                        name: rest.to_string(),
+                        node,
+                    });
+                }
+            }
+            None => {}
+        }
+        idx += 1;
+    }
+
+    if children.is_empty() && start.is_none() && stat(zim, path).is_none() {
+        return Err(anyhow!("No such directory: {}", path));
+    }
+    Ok((children, None))
+}
+
+/// Reads the content of the entry at `path`, following redirects.
+pub fn read_file(zim: &Archive, path: &str) -> Result<Vec<u8>> {
+    let path = normalize(path);
+    let entry = zim
+        .get_entry_bypath_str(path)
+        .map_err(|e| anyhow!("Entry not found: {:?}", e))?;
+    let item = entry
+        .get_item(true)
+        .map_err(|e| anyhow!("Failed to resolve entry: {:?}", e))?;
+    let blob = item
+        .get_data()
+        .map_err(|e| anyhow!("Failed to read entry data: {:?}", e))?;
+    Ok(blob.data().to_vec())
+}
@@ -0,0 +1,254 @@
+//! Archive metadata lookups. `POST /archives/metadata` answers for many
+//! archives at once so library views don't need a request per book;
+//! `GET /archives/{id}/capabilities` reports which optional features a book has.
+//! `DELETE /archives/{id}` removes a book once no request or job is reading it.
+
+use crate::AppState;
+use crate::cluster::{self, Change};
+use crate::library::Manifest;
+use crate::tags;
+use actix_web::{HttpRequest, HttpResponse, Responder, delete, get, post, web};
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use zim_rs::archive::Archive;
+
+/// Upper bound on ids per batch request.
+const MAX_BATCH_SIZE: usize = 500;
+/// How long a deletion waits for in-flight readers by default.
+const DEFAULT_DELETE_TIMEOUT_SECS: u64 = 30;
+/// Longest wait a deletion may ask for; each one holds a blocking thread.
+const MAX_DELETE_TIMEOUT_SECS: u64 = 300;
+
+#[derive(Deserialize)]
+struct MetadataRequest {
+    ids: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct ArchiveMetadata {
+    pub id: String,
+    pub uuid: String,
+    pub file_name: String,
+    pub file_size: u64,
+    /// Library directory the archive was found in.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub root: Option<PathBuf>,
+    pub article_count: u32,
+    pub media_count: u32,
+    pub has_fulltext_index: bool,
+    /// Tags assigned in the manifest plus the archive's own.
+    pub tags: BTreeSet<String>,
+    /// Every text metadata entry (`Title`, `Language`, `Date`, ...).
+    pub metadata: BTreeMap<String, String>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum MetadataResult {
+    Found(ArchiveMetadata),
+    Failed { id: String, error: String },
+}
+
+pub fn read_metadata(
+    id: &str,
+    zim_path: &Path,
+    root: Option<PathBuf>,
+    assigned_tags: Option<&BTreeSet<String>>,
+) -> Result<ArchiveMetadata> {
+    let zim = Archive::new(zim_path.to_str().unwrap())
+        .map_err(|e| anyhow!("Failed to open archive: {:?}", e))?;
+    let metadata = zim
+        .get_metadata_keys()
+        .unwrap_or_default()
+        .into_iter()
+        // Illustrations are binary PNGs, served by the favicon routes instead.
+        .filter(|key| !key.starts_with("Illustration_"))
+        .filter_map(|key| zim.get_metadata(&key).ok().map(|value| (key, value)))
+        .collect();
+
+    Ok(ArchiveMetadata {
+        id: id.to_string(),
+        uuid: zim.get_uuid().to_string(),
+        file_name: zim_path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("unknown")
+            .to_string(),
+        file_size: fs::metadata(zim_path).map(|m| m.len()).unwrap_or(0),
+        root,
+        article_count: zim.get_articlecount(),
+        media_count: zim.get_mediacount(),
+        has_fulltext_index: zim.has_fulltext_index(),
+        tags: tags::archive_tags(&zim, assigned_tags),
+        metadata,
+    })
+}
+
+/// Optional parts of an archive, so clients can hide features a book cannot
+/// support.
+#[derive(Serialize)]
+struct Capabilities {
+    id: String,
+    has_fulltext_index: bool,
+    has_title_index: bool,
+    has_main_page: bool,
+    /// Sizes of the square illustrations (favicons) the archive carries.
+    illustration_sizes: Vec<u32>,
+    has_checksum: bool,
+}
+
+fn read_capabilities(id: &str, zim_path: &Path) -> Result<Capabilities> {
+    let zim = Archive::new(zim_path.to_str().unwrap())
+        .map_err(|e| anyhow!("Failed to open archive: {:?}", e))?;
+    let mut illustration_sizes = zim.get_illustration_sizes().unwrap_or_default();
+    illustration_sizes.sort();
+    Ok(Capabilities {
+        id: id.to_string(),
+        has_fulltext_index: zim.has_fulltext_index(),
+        has_title_index: zim.has_title_index(),
+        has_main_page: zim.has_main_entry(),
+        illustration_sizes,
+        has_checksum: zim.has_checksum(),
+    })
+}
+
+#[get("/archives/{id}/capabilities")]
+async fn archive_capabilities(
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let Some(zim_path) = state.archive_path(&id) else {
+        return HttpResponse::NotFound().json(json!({"error": "Archive not found"}));
+    };
+    match web::block(move || read_capabilities(&id, &zim_path)).await {
+        Ok(Ok(capabilities)) => HttpResponse::Ok().json(capabilities),
+        Ok(Err(e)) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+    }
+}
+
+#[post("/archives/metadata")]
+async fn batch_metadata(
+    req: web::Json<MetadataRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    if req.ids.len() > MAX_BATCH_SIZE {
+        return HttpResponse::BadRequest().json(json!({
+            "error": format!("At most {} ids per request", MAX_BATCH_SIZE)
+        }));
+    }
+    let mut not_found = Vec::new();
+    let archives: Vec<(String, PathBuf, Option<PathBuf>)> = req
+        .ids
+        .iter()
+        .filter_map(|id| match state.archive_path(id) {
+            Some(path) => Some((id.clone(), path, state.archive_root(id))),
+            None => {
+                not_found.push(id.clone());
+                None
+            }
+        })
+        .collect();
+
+    let library_dir = state.library_dir();
+    let result = web::block(move || {
+        // Tags are extra; a manifest that can't be read just leaves them out.
+        let manifest = Manifest::load(&library_dir).unwrap_or_default();
+        archives
+            .into_iter()
+            .map(
+                |(id, path, root)| match read_metadata(&id, &path, root, manifest.tags.get(&id)) {
+                    Ok(metadata) => MetadataResult::Found(metadata),
+                    Err(e) => MetadataResult::Failed {
+                        id,
+                        error: e.to_string(),
+                    },
+                },
+            )
+            .collect::<Vec<_>>()
+    })
+    .await;
+
+    match result {
+        Ok(archives) => HttpResponse::Ok().json(json!({
+            "archives": archives,
+            "not_found": not_found,
+        })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+    }
+}
+
+#[derive(Deserialize)]
+struct DeleteQuery {
+    timeout_secs: Option<u64>,
+}
+
+/// Hides the archive from new requests, waits for the ones reading it, then
+/// deletes it. Files inside a library root are removed from disk; archives
+/// registered from elsewhere are only unregistered.
+#[delete("/archives/{id}")]
+async fn delete_archive(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<DeleteQuery>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    if !state.is_admin(&req) {
+        return HttpResponse::Unauthorized().json(json!({"error": "Invalid or missing token"}));
+    }
+    let id = path.into_inner();
+    let root = state.archive_root(&id);
+    let Some(zim_path) = state.file_cache.lock().unwrap().remove(&id) else {
+        return HttpResponse::NotFound().json(json!({"error": "Archive not found"}));
+    };
+
+    let timeout_secs = query
+        .timeout_secs
+        .unwrap_or(DEFAULT_DELETE_TIMEOUT_SECS)
+        .min(MAX_DELETE_TIMEOUT_SECS);
+    let timeout = Duration::from_secs(timeout_secs);
+    let (leases, waiting_path) = (state.leases.clone(), zim_path.clone());
+    let released = web::block(move || leases.wait_released(&waiting_path, timeout))
+        .await
+        .unwrap_or(false);
+    if !released {
+        state
+            .file_cache
+            .lock()
+            .unwrap()
+            .insert(id.clone(), zim_path.clone());
+        return HttpResponse::Conflict().json(json!({
+            "error": "Archive is still in use, try again later",
+            "in_use": state.leases.in_use(&zim_path),
+        }));
+    }
+
+    let library_dir = state.library_dir();
+    let result = Manifest::update(&library_dir, |manifest| {
+        let registered = manifest.remove(&id);
+        let was_active = manifest.active.as_ref() == Some(&zim_path);
+        if was_active {
+            manifest.active = None;
+        }
+        if root.is_some() {
+            fs::remove_file(&zim_path)?;
+        }
+        Ok(registered || was_active)
+    });
+    if let Err(e) = result {
+        return HttpResponse::InternalServerError().json(json!({"error": e.to_string()}));
+    }
+
+    state.forget_archive(&id, &zim_path);
+    cluster::publish(&state, vec![Change::Removed(id.clone())]);
+    HttpResponse::Ok().json(json!({
+        "deleted": id,
+        "file_removed": root.is_some(),
+    }))
+}
@@ -0,0 +1,203 @@
+//! Shared lookup of an article's HTML by archive id and entry path, used by
+//! the endpoints that analyse or transform a single article.
+
+use crate::AppState;
+use crate::blocklist::{self, Blocklist};
+use crate::charset;
+use actix_web::{HttpResponse, web};
+use anyhow::{Result, anyhow};
+use scraper::{ElementRef, Html, Selector};
+use std::path::Path;
+use zim_rs::archive::Archive;
+
+pub enum ArticleHtml {
+    Found {
+        title: String,
+        path: String,
+        html: String,
+    },
+    Blocked,
+    Missing,
+}
+
+/// Reads the HTML article at `entry_path`, following redirects. Entries that
+/// are not `text/html` count as missing.
+pub fn read_article(
+    zim_path: &Path,
+    entry_path: &str,
+    blocklist: &Blocklist,
+) -> Result<ArticleHtml> {
+    let zim = Archive::new(zim_path.to_str().unwrap())
+        .map_err(|e| anyhow!("Failed to open archive: {:?}", e))?;
+    let Ok(entry) = zim.get_entry_bypath_str(entry_path) else {
+        return Ok(ArticleHtml::Missing);
+    };
+    if blocklist.is_blocked(&entry.get_title(), &entry.get_path()) {
+        return Ok(ArticleHtml::Blocked);
+    }
+    let item = entry
+        .get_item(true)
+        .map_err(|e| anyhow!("Failed to resolve {}: {:?}", entry_path, e))?;
+    let (title, path) = (item.get_title(), item.get_path());
+    if blocklist.is_blocked(&title, &path) {
+        return Ok(ArticleHtml::Blocked);
+    }
+    let mimetype = item.get_mimetype().unwrap_or_default();
+    if !mimetype.starts_with("text/html") {
+        return Ok(ArticleHtml::Missing);
+    }
+    let blob = item
+        .get_data()
+        .map_err(|e| anyhow!("Failed to read {}: {:?}", path, e))?;
+    Ok(ArticleHtml::Found {
+        title,
+        path,
+        html: charset::decode(blob.data(), &mimetype).into_owned(),
+    })
+}
+
+/// The article's readable text: everything inside `<body>` except scripts,
+/// styles and other non-content elements, with whitespace collapsed.
+pub fn visible_text(html: &str) -> String {
+    let document = Html::parse_document(html);
+    let root = Selector::parse("body").unwrap();
+    let Some(body) = document.select(&root).next() else {
+        return String::new();
+    };
+    body.descendants()
+        .filter(|node| {
+            !node.ancestors().filter_map(ElementRef::wrap).any(|e| {
+                matches!(
+                    e.value().name(),
+                    "script" | "style" | "noscript" | "template"
+                )
+            })
+        })
+        .filter_map(|node| node.value().as_text().map(|t| &**t))
+        .flat_map(str::split_whitespace)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The article's text split into lines, breaking at block-level elements and
+/// at newlines inside `<pre>` blocks, for line-oriented matching.
+pub fn text_lines(html: &str) -> Vec<String> {
+    let document = Html::parse_document(html);
+    let root = Selector::parse("body").unwrap();
+    let Some(body) = document.select(&root).next() else {
+        return Vec::new();
+    };
+    let mut text = String::new();
+    for node in body.descendants() {
+        if let Some(element) = node.value().as_element() {
+            if matches!(
+                element.name(),
+                "p" | "div"
+                    | "li"
+                    | "pre"
+                    | "br"
+                    | "tr"
+                    | "dt"
+                    | "dd"
+                    | "table"
+                    | "section"
+                    | "h1"
+                    | "h2"
+                    | "h3"
+                    | "h4"
+                    | "h5"
+                    | "h6"
+                    | "blockquote"
+            ) {
+                text.push('\n');
+            }
+        } else if let Some(t) = node.value().as_text() {
+            let hidden = node.ancestors().filter_map(ElementRef::wrap).any(|e| {
+                matches!(
+                    e.value().name(),
+                    "script" | "style" | "noscript" | "template"
+                )
+            });
+            if !hidden {
+                text.push_str(t);
+            }
+        }
+    }
+    text.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+/// Resolves a link or image URL found in the article at `article_path` to an
+/// entry path, or `None` for URLs outside the archive.
+pub fn resolve_entry_path(article_path: &str, src: &str) -> Option<String> {
+    // ZIM content links relatively; server-absolute paths and other schemes
+    // point outside the archive.
+    const SCHEMES: &[&str] = &["data:", "mailto:", "tel:", "javascript:"];
+    if src.contains("://") || src.starts_with('/') || SCHEMES.iter().any(|s| src.starts_with(s)) {
+        return None;
+    }
+    // A bare `#anchor` or `?query` points back at the article itself.
+    let src = src.split(['#', '?']).next().unwrap_or("");
+    if src.is_empty() {
+        return None;
+    }
+    let mut segments: Vec<&str> = article_path.split('/').collect();
+    segments.pop();
+    for segment in src.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            s => segments.push(s),
+        }
+    }
+    let path = segments.join("/");
+    let path = urlencoding::decode(&path)
+        .map(|p| p.into_owned())
+        .unwrap_or(path);
+    (!path.is_empty()).then_some(path)
+}
+
+/// Runs `work` on the article `entry_path` of archive `id` in the blocking
+/// pool, turning a missing or blocked article into a 404/403 response.
+pub async fn with_article<T, F>(
+    state: &AppState,
+    id: &str,
+    entry_path: String,
+    work: F,
+) -> Result<T, HttpResponse>
+where
+    T: Send + 'static,
+    F: FnOnce(&str, &str, &str) -> T + Send + 'static,
+{
+    let Some(zim_path) = state.archive_path(id) else {
+        return Err(HttpResponse::NotFound().body("Archive not found"));
+    };
+    let _lease = state.leases.acquire(&zim_path);
+    let blocklist = state.blocklist_for(Some(id));
+    // `HttpResponse` is not `Send`, so the outcome is only turned into one
+    // back on the async side.
+    enum Outcome<T> {
+        Done(T),
+        Blocked,
+        Missing,
+    }
+    let result = web::block(move || {
+        read_article(&zim_path, &entry_path, &blocklist).map(|article| match article {
+            ArticleHtml::Found { title, path, html } => Outcome::Done(work(&title, &path, &html)),
+            ArticleHtml::Blocked => Outcome::Blocked,
+            ArticleHtml::Missing => Outcome::Missing,
+        })
+    })
+    .await;
+    match result {
+        Ok(Ok(Outcome::Done(value))) => Ok(value),
+        Ok(Ok(Outcome::Blocked)) => Err(HttpResponse::Forbidden().body(blocklist::BLOCKED_MESSAGE)),
+        Ok(Ok(Outcome::Missing)) => Err(HttpResponse::NotFound().body("Article not found")),
+        Ok(Err(e)) => Err(HttpResponse::InternalServerError().body(e.to_string())),
+        Err(e) => Err(HttpResponse::InternalServerError().body(e.to_string())),
+    }
+}
@@ -0,0 +1,61 @@
+//! Admin-configured content filtering. Blocked entries are left out of browse,
+//! search and suggestion results and answered with 403 when fetched directly.
+
+use serde::{Deserialize, Serialize};
+
+pub const BLOCKED_MESSAGE: &str = "This content has been blocked by the administrator";
+
+/// Patterns may contain `*` wildcards. Title patterns are matched
+/// case-insensitively, path patterns exactly.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+#[serde(default)]
+pub struct Blocklist {
+    pub title_patterns: Vec<String>,
+    pub paths: Vec<String>,
+}
+
+impl Blocklist {
+    pub fn is_empty(&self) -> bool {
+        self.title_patterns.is_empty() && self.paths.is_empty()
+    }
+
+    pub fn is_blocked(&self, title: &str, path: &str) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+        let title = title.to_lowercase();
+        self.title_patterns
+            .iter()
+            .any(|p| wildcard_match(&p.to_lowercase(), &title))
+            || self.paths.iter().any(|p| wildcard_match(p, path))
+    }
+
+    /// Combines two blocklists, e.g. the global one with an archive's own.
+    pub fn merged(mut self, other: &Blocklist) -> Blocklist {
+        self.title_patterns
+            .extend(other.title_patterns.iter().cloned());
+        self.paths.extend(other.paths.iter().cloned());
+        self
+    }
+}
+
+/// Matches `text` against `pattern`, where `*` stands for any run of characters.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No wildcard at all: exact match.
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
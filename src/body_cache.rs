@@ -0,0 +1,192 @@
+//! Compressed copies of `/content` bodies kept on disk (`body_cache` in the
+//! config). Files are keyed by archive UUID and entry path, so a repeat
+//! request is answered straight from disk without decompressing the cluster
+//! or compressing the body again. A UUID never changes content, so entries
+//! never need invalidating; once `max_bytes` is reached new bodies are simply
+//! compressed on the fly without being stored.
+
+use crate::config::BodyCacheConfig;
+use anyhow::{Result, anyhow};
+use log::warn;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tempfile::NamedTempFile;
+
+const BROTLI_QUALITY: u32 = 9;
+const BROTLI_WINDOW: u32 = 22;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    pub fn header_value(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gz",
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut out = Vec::new();
+                {
+                    let mut writer = brotli::CompressorWriter::new(
+                        &mut out,
+                        4096,
+                        BROTLI_QUALITY,
+                        BROTLI_WINDOW,
+                    );
+                    writer
+                        .write_all(data)
+                        .map_err(|e| anyhow!("Brotli compression failed: {:?}", e))?;
+                }
+                Ok(out)
+            }
+            Encoding::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+                encoder
+                    .write_all(data)
+                    .map_err(|e| anyhow!("Gzip compression failed: {:?}", e))?;
+                encoder
+                    .finish()
+                    .map_err(|e| anyhow!("Gzip compression failed: {:?}", e))
+            }
+        }
+    }
+}
+
+/// The best encoding the client accepts, preferring brotli over gzip.
+pub fn negotiate(accept_encoding: Option<&str>) -> Option<Encoding> {
+    let accepted: Vec<&str> = accept_encoding?
+        .split(',')
+        .filter_map(|part| {
+            let mut params = part.split(';');
+            let name = params.next()?.trim();
+            let refused = params.any(|p| {
+                p.trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    == Some(0.0)
+            });
+            (!refused).then_some(name)
+        })
+        .collect();
+    [Encoding::Brotli, Encoding::Gzip]
+        .into_iter()
+        .find(|e| accepted.contains(&e.header_value()))
+}
+
+/// Text formats worth compressing; images, video and fonts already are.
+pub fn is_compressible(mimetype: &str) -> bool {
+    let mimetype = mimetype.split(';').next().unwrap_or("").trim();
+    mimetype.starts_with("text/")
+        || mimetype.ends_with("+xml")
+        || mimetype.ends_with("+json")
+        || matches!(
+            mimetype,
+            "application/javascript" | "application/json" | "application/xml"
+        )
+}
+
+#[derive(Default)]
+struct Usage {
+    dir: PathBuf,
+    bytes: u64,
+    scanned: bool,
+}
+
+/// Tracks how much the cache directory holds, so `max_bytes` can be enforced
+/// without walking the directory on every store.
+#[derive(Clone, Default)]
+pub struct BodyCache {
+    usage: Arc<Mutex<Usage>>,
+}
+
+fn dir_size(dir: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .map(|e| match e.file_type() {
+            Ok(t) if t.is_dir() => dir_size(&e.path()),
+            _ => e.metadata().map(|m| m.len()).unwrap_or(0),
+        })
+        .sum()
+}
+
+fn entry_file(config: &BodyCacheConfig, uuid: &str, path: &str, encoding: Encoding) -> PathBuf {
+    let key = hex::encode(Sha256::digest(path.as_bytes()));
+    config
+        .dir
+        .join(uuid)
+        .join(format!("{}.{}", key, encoding.extension()))
+}
+
+impl BodyCache {
+    /// The stored body for `(uuid, path)`, if there is one.
+    pub fn get(
+        &self,
+        config: &BodyCacheConfig,
+        uuid: &str,
+        path: &str,
+        encoding: Encoding,
+    ) -> Option<Vec<u8>> {
+        fs::read(entry_file(config, uuid, path, encoding)).ok()
+    }
+
+    /// Compresses `data`, storing the result if the cache has room. Failing
+    /// to store is not an error; the compressed body is returned either way.
+    pub fn insert(
+        &self,
+        config: &BodyCacheConfig,
+        uuid: &str,
+        path: &str,
+        encoding: Encoding,
+        data: &[u8],
+    ) -> Result<Vec<u8>> {
+        let compressed = encoding.compress(data)?;
+        let mut usage = self.usage.lock().unwrap();
+        if !usage.scanned || usage.dir != config.dir {
+            *usage = Usage {
+                dir: config.dir.clone(),
+                bytes: dir_size(&config.dir),
+                scanned: true,
+            };
+        }
+        if usage.bytes + compressed.len() as u64 > config.max_bytes {
+            return Ok(compressed);
+        }
+        let file = entry_file(config, uuid, path, encoding);
+        let stored = file
+            .parent()
+            .ok_or_else(|| anyhow!("Invalid cache path"))
+            .and_then(|dir| {
+                fs::create_dir_all(dir)?;
+                let mut tmp = NamedTempFile::new_in(dir)?;
+                tmp.write_all(&compressed)?;
+                tmp.persist(&file)?;
+                Ok(())
+            });
+        match stored {
+            Ok(()) => usage.bytes += compressed.len() as u64,
+            Err(e) => warn!("Failed to store {} in body cache: {}", file.display(), e),
+        }
+        Ok(compressed)
+    }
+}
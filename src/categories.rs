@@ -0,0 +1,453 @@
+//! Category tree of MediaWiki-derived archives. Category pages (`Category:…`
+//! and its common translations) list their subcategories and member pages
+//! (`#mw-subcategories`, `#mw-pages`) and their own parents (`#catlinks`);
+//! they are read once per archive into a graph that the endpoints walk, and
+//! the graphs of the few archives browsed most recently are kept.
+//! Archives scraped without category pages simply report no categories.
+
+use crate::AppState;
+use crate::archive_tree;
+use crate::article_html;
+use crate::charset;
+use actix_web::{HttpResponse, Responder, get, web};
+use anyhow::{Result, anyhow};
+use scraper::{ElementRef, Html, Selector};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use zim_rs::archive::Archive;
+
+/// Path prefixes of the category namespace in the larger Wikipedias.
+const CATEGORY_PREFIXES: &[&str] = &[
+    "Category:",
+    "Categoria:",
+    "Categoría:",
+    "Catégorie:",
+    "Categorie:",
+    "Kategorie:",
+    "Kategori:",
+    "Kategoria:",
+    "Kategória:",
+    "Категория:",
+    "Категорія:",
+    "Κατηγορία:",
+    "분류:",
+    "カテゴリ:",
+    "分类:",
+    "Thể_loại:",
+];
+/// Deepest `depth` a request may ask for.
+const MAX_DEPTH: u32 = 5;
+
+struct Category {
+    name: String,
+    parents: BTreeSet<String>,
+    subcategories: BTreeSet<String>,
+    /// `(path, title)` of member pages, in page order.
+    members: Vec<(String, String)>,
+}
+
+#[derive(Default)]
+pub struct CategoryTree {
+    categories: BTreeMap<String, Category>,
+}
+
+/// Trees kept; the one used least recently is dropped first.
+const MAX_TREES: usize = 8;
+
+/// The tree of one archive, built by the first request asking for it while
+/// later ones wait for that build.
+struct TreeSlot {
+    uuid: String,
+    tree: OnceLock<Arc<CategoryTree>>,
+}
+
+/// Category graphs by archive path, built on first use.
+#[derive(Clone, Default)]
+pub struct CategoryIndex {
+    trees: Arc<Mutex<CachedTrees>>,
+}
+
+#[derive(Default)]
+struct CachedTrees {
+    /// Slots with the use count at which they were last asked for.
+    slots: HashMap<PathBuf, (u64, Arc<TreeSlot>)>,
+    uses: u64,
+}
+
+fn category_name(path: &str) -> String {
+    let name = CATEGORY_PREFIXES
+        .iter()
+        .find_map(|prefix| path.strip_prefix(prefix))
+        .unwrap_or(path);
+    name.replace('_', " ")
+}
+
+fn is_category(path: &str) -> bool {
+    CATEGORY_PREFIXES
+        .iter()
+        .any(|prefix| path.starts_with(prefix))
+}
+
+/// Internal links inside `scope`, as `(entry path, link text)`.
+fn links(page_path: &str, scope: ElementRef) -> Vec<(String, String)> {
+    let selector = Selector::parse("a[href]").unwrap();
+    scope
+        .select(&selector)
+        .filter_map(|a| {
+            let target = article_html::resolve_entry_path(page_path, a.value().attr("href")?)?;
+            let text = a.text().flat_map(str::split_whitespace).collect::<Vec<_>>();
+            Some((target, text.join(" ")))
+        })
+        .collect()
+}
+
+fn read_category(zim: &Archive, path: &str) -> Option<Category> {
+    let entry = zim.get_entry_bypath_str(path).ok()?;
+    if entry.is_redirect() {
+        return None;
+    }
+    let item = entry.get_item(false).ok()?;
+    let mimetype = item.get_mimetype().ok()?;
+    if !mimetype.starts_with("text/html") {
+        return None;
+    }
+    let blob = item.get_data().ok()?;
+    let document = Html::parse_document(&charset::decode(blob.data(), &mimetype));
+    let section = |id: &str| {
+        let selector = Selector::parse(&format!("#{}", id)).unwrap();
+        document.select(&selector).next()
+    };
+
+    let mut category = Category {
+        name: category_name(path),
+        parents: BTreeSet::new(),
+        subcategories: BTreeSet::new(),
+        members: Vec::new(),
+    };
+    if let Some(catlinks) = section("catlinks") {
+        for (target, _) in links(path, catlinks) {
+            if is_category(&target) && target != path {
+                category.parents.insert(target);
+            }
+        }
+    }
+    let listed: Vec<(String, String)> = match (section("mw-subcategories"), section("mw-pages")) {
+        (None, None) => {
+            // No MediaWiki markup: take every link outside the parent list.
+            let body = Selector::parse("body").unwrap();
+            let catlinks: HashSet<String> = category.parents.iter().cloned().collect();
+            document
+                .select(&body)
+                .next()
+                .map(|body| links(path, body))
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|(target, _)| !catlinks.contains(target))
+                .collect()
+        }
+        (subcategories, pages) => subcategories
+            .into_iter()
+            .chain(pages)
+            .flat_map(|scope| links(path, scope))
+            .collect(),
+    };
+    let mut seen = HashSet::new();
+    for (target, text) in listed {
+        if target == path || !seen.insert(target.clone()) {
+            continue;
+        }
+        if is_category(&target) {
+            category.subcategories.insert(target);
+        } else if zim.has_entry_bypath(&target) {
+            let title = if text.is_empty() {
+                target.clone()
+            } else {
+                text
+            };
+            category.members.push((target, title));
+        }
+    }
+    Some(category)
+}
+
+fn build_tree(zim: &Archive) -> CategoryTree {
+    let mut tree = CategoryTree::default();
+    let total = zim.get_all_entrycount();
+    for prefix in CATEGORY_PREFIXES {
+        let mut idx = archive_tree::lower_bound(zim, prefix);
+        while idx < total {
+            let Ok(entry) = zim.get_entry_bypath_index(idx) else {
+                break;
+            };
+            let path = entry.get_path();
+            if !path.starts_with(prefix) {
+                break;
+            }
+            if let Some(category) = read_category(zim, &path) {
+                tree.categories.insert(path, category);
+            }
+            idx += 1;
+        }
+    }
+
+    // Links can point at categories the archive doesn't contain; keep only
+    // edges between known ones, and make both directions agree.
+    let known: HashSet<String> = tree.categories.keys().cloned().collect();
+    let mut edges = Vec::new();
+    for (path, category) in &tree.categories {
+        edges.extend(
+            category
+                .parents
+                .iter()
+                .map(|parent| (parent.clone(), path.clone())),
+        );
+        edges.extend(
+            category
+                .subcategories
+                .iter()
+                .map(|child| (path.clone(), child.clone())),
+        );
+    }
+    for category in tree.categories.values_mut() {
+        category.parents.clear();
+        category.subcategories.clear();
+    }
+    for (parent, child) in edges {
+        if parent == child || !known.contains(&parent) || !known.contains(&child) {
+            continue;
+        }
+        tree.categories
+            .get_mut(&parent)
+            .unwrap()
+            .subcategories
+            .insert(child.clone());
+        tree.categories
+            .get_mut(&child)
+            .unwrap()
+            .parents
+            .insert(parent);
+    }
+    tree
+}
+
+impl CachedTrees {
+    /// The slot for the archive at `path`, a fresh one if there is none yet or
+    /// the file was replaced by another archive.
+    fn slot(&mut self, path: &Path, uuid: &str) -> Arc<TreeSlot> {
+        self.uses += 1;
+        let slot = match self.slots.get(path) {
+            Some((_, slot)) if slot.uuid == uuid => slot.clone(),
+            _ => Arc::new(TreeSlot {
+                uuid: uuid.to_string(),
+                tree: OnceLock::new(),
+            }),
+        };
+        self.slots
+            .insert(path.to_path_buf(), (self.uses, slot.clone()));
+        while self.slots.len() > MAX_TREES {
+            let oldest = self
+                .slots
+                .iter()
+                .min_by_key(|(_, (last_used, _))| *last_used)
+                .map(|(path, _)| path.clone())
+                .unwrap();
+            self.slots.remove(&oldest);
+        }
+        slot
+    }
+}
+
+impl CategoryIndex {
+    fn tree(&self, zim_path: &Path) -> Result<Arc<CategoryTree>> {
+        let zim = Archive::new(zim_path.to_str().unwrap())
+            .map_err(|e| anyhow!("Failed to open archive: {:?}", e))?;
+        let uuid = zim.get_uuid().to_string();
+        let slot = self.trees.lock().unwrap().slot(zim_path, &uuid);
+        // Built outside the index lock, so other archives aren't held up.
+        Ok(slot.tree.get_or_init(|| Arc::new(build_tree(&zim))).clone())
+    }
+
+    /// Drops the tree of an archive leaving the library.
+    pub fn forget(&self, zim_path: &Path) {
+        self.trees.lock().unwrap().slots.remove(zim_path);
+    }
+}
+
+#[derive(Serialize)]
+struct CategorySummary {
+    name: String,
+    path: String,
+    subcategory_count: usize,
+    member_count: usize,
+    /// Nested down to the requested depth.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subcategories: Option<Vec<CategorySummary>>,
+}
+
+#[derive(Serialize)]
+struct Member {
+    title: String,
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct TreeQuery {
+    depth: Option<u32>,
+}
+
+fn summary(
+    tree: &CategoryTree,
+    path: &str,
+    depth: u32,
+    ancestors: &mut Vec<String>,
+) -> CategorySummary {
+    let category = &tree.categories[path];
+    let subcategories = (depth > 0).then(|| {
+        ancestors.push(path.to_string());
+        let mut children = Vec::new();
+        for child in &category.subcategories {
+            // Category graphs have cycles; don't descend into one.
+            if !ancestors.contains(child) {
+                children.push(summary(tree, child, depth - 1, ancestors));
+            }
+        }
+        ancestors.pop();
+        children
+    });
+    CategorySummary {
+        name: category.name.clone(),
+        path: path.to_string(),
+        subcategory_count: category.subcategories.len(),
+        member_count: category.members.len(),
+        subcategories,
+    }
+}
+
+async fn load_tree(
+    state: &web::Data<AppState>,
+    id: &str,
+) -> Result<Arc<CategoryTree>, HttpResponse> {
+    let Some(zim_path) = state.archive_path(id) else {
+        return Err(HttpResponse::NotFound().json(json!({"error": "Archive not found"})));
+    };
+    let _lease = state.leases.acquire(&zim_path);
+    let index = state.categories.clone();
+    match web::block(move || index.tree(&zim_path)).await {
+        Ok(Ok(tree)) => Ok(tree),
+        Ok(Err(e)) => {
+            Err(HttpResponse::InternalServerError().json(json!({"error": e.to_string()})))
+        }
+        Err(e) => Err(HttpResponse::InternalServerError().json(json!({"error": e.to_string()}))),
+    }
+}
+
+/// Top-level categories (those without a parent in the archive), nested
+/// `depth` levels deep (default 1).
+#[get("/archives/{id}/categories", wrap = "crate::load_shed::Shed::heavy()")]
+async fn category_roots(
+    path: web::Path<String>,
+    query: web::Query<TreeQuery>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let tree = match load_tree(&state, &id).await {
+        Ok(tree) => tree,
+        Err(response) => return response,
+    };
+    let depth = query.depth.unwrap_or(1).min(MAX_DEPTH);
+    let mut roots: Vec<&String> = tree
+        .categories
+        .iter()
+        .filter(|(_, category)| category.parents.is_empty())
+        .map(|(path, _)| path)
+        .collect();
+    // Every category sits in a cycle; fall back to listing them all.
+    if roots.is_empty() {
+        roots = tree.categories.keys().collect();
+    }
+    let roots: Vec<CategorySummary> = roots
+        .into_iter()
+        .map(|root| summary(&tree, root, depth.saturating_sub(1), &mut Vec::new()))
+        .collect();
+    HttpResponse::Ok().json(json!({
+        "category_count": tree.categories.len(),
+        "categories": roots,
+    }))
+}
+
+/// One category with its parents, subcategories (nested `depth` levels,
+/// default 1) and member pages.
+#[get(
+    "/archives/{id}/categories/{path:.*}",
+    wrap = "crate::load_shed::Shed::heavy()"
+)]
+async fn category_detail(
+    path: web::Path<(String, String)>,
+    query: web::Query<TreeQuery>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let (id, category_path) = path.into_inner();
+    let tree = match load_tree(&state, &id).await {
+        Ok(tree) => tree,
+        Err(response) => return response,
+    };
+    let Some(category) = tree.categories.get(&category_path) else {
+        return HttpResponse::NotFound().json(json!({"error": "Category not found"}));
+    };
+    let depth = query.depth.unwrap_or(1).min(MAX_DEPTH);
+    let blocklist = state.blocklist_for(Some(&id));
+    let node = summary(&tree, &category_path, depth, &mut Vec::new());
+    let parents: Vec<CategorySummary> = category
+        .parents
+        .iter()
+        .map(|parent| summary(&tree, parent, 0, &mut Vec::new()))
+        .collect();
+    let members: Vec<Member> = category
+        .members
+        .iter()
+        .filter(|(path, title)| !blocklist.is_blocked(title, path))
+        .map(|(path, title)| Member {
+            title: title.clone(),
+            path: path.clone(),
+        })
+        .collect();
+    HttpResponse::Ok().json(json!({
+        "category": node,
+        "parents": parents,
+        "members": members,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_are_shared_until_the_archive_changes() {
+        let mut trees = CachedTrees::default();
+        let first = trees.slot(Path::new("/a.zim"), "uuid-1");
+        assert!(Arc::ptr_eq(
+            &first,
+            &trees.slot(Path::new("/a.zim"), "uuid-1")
+        ));
+        let replaced = trees.slot(Path::new("/a.zim"), "uuid-2");
+        assert!(!Arc::ptr_eq(&first, &replaced));
+    }
+
+    #[test]
+    fn least_recently_used_trees_are_dropped() {
+        let mut trees = CachedTrees::default();
+        for n in 0..MAX_TREES {
+            trees.slot(Path::new(&format!("/{}.zim", n)), "uuid");
+        }
+        // Touch the first so the second is the oldest.
+        trees.slot(Path::new("/0.zim"), "uuid");
+        trees.slot(Path::new("/new.zim"), "uuid");
+        assert_eq!(trees.slots.len(), MAX_TREES);
+        assert!(trees.slots.contains_key(Path::new("/0.zim")));
+        assert!(!trees.slots.contains_key(Path::new("/1.zim")));
+    }
+}
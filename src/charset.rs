@@ -0,0 +1,45 @@
+//! Decoding of text entries that are not UTF-8. Older ZIMs hold Latin-1 or
+//! Windows-1251 pages; the declared `charset` is used when the mimetype has
+//! one, otherwise the encoding is guessed from the bytes.
+
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, UTF_8};
+use std::borrow::Cow;
+
+/// Whether entries of this mimetype are text that should be served as UTF-8.
+pub fn is_text(mimetype: &str) -> bool {
+    let mimetype = mimetype.split(';').next().unwrap_or("").trim();
+    mimetype.starts_with("text/")
+        || mimetype.ends_with("+xml")
+        || matches!(mimetype, "application/javascript" | "application/xml")
+}
+
+fn declared_encoding(mimetype: &str) -> Option<&'static Encoding> {
+    mimetype
+        .split(';')
+        .skip(1)
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(name, _)| name.eq_ignore_ascii_case("charset"))
+        .and_then(|(_, label)| Encoding::for_label(label.trim_matches('"').as_bytes()))
+}
+
+/// `data` as UTF-8 text. Valid UTF-8 is borrowed as-is.
+pub fn decode<'a>(data: &'a [u8], mimetype: &str) -> Cow<'a, str> {
+    let encoding = declared_encoding(mimetype).unwrap_or_else(|| {
+        if std::str::from_utf8(data).is_ok() {
+            return UTF_8;
+        }
+        let mut detector = EncodingDetector::new();
+        detector.feed(data, true);
+        detector.guess(None, true)
+    });
+    encoding.decode(data).0
+}
+
+/// `mimetype` with its charset replaced by UTF-8, for transcoded bodies.
+pub fn utf8_mimetype(mimetype: &str) -> String {
+    format!(
+        "{}; charset=utf-8",
+        mimetype.split(';').next().unwrap_or("").trim()
+    )
+}
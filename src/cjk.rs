@@ -0,0 +1,146 @@
+//! Bigram matching for Chinese, Japanese and Korean archives. CJK text has no
+//! spaces between words, so whitespace tokenization turns a whole sentence
+//! into one "word" and queries rarely match. Splitting runs of CJK characters
+//! into overlapping bigrams gives usable recall without a dictionary.
+
+use std::collections::HashSet;
+use zim_rs::archive::Archive;
+use zim_rs::entry::Entry;
+
+/// Titles looked at by one title search at most, so a query on a huge archive
+/// stays bounded; matches past them are not found or counted.
+const MAX_SCANNED_TITLES: u32 = 500_000;
+
+/// ISO 639-1 and 639-3 codes treated as CJK.
+const CJK_LANGUAGES: &[&str] = &["zh", "zho", "chi", "ja", "jpn", "ko", "kor"];
+
+/// Whether the archive's `Language` metadata (a comma-separated list of
+/// codes) names a CJK language.
+pub fn is_cjk_archive(zim: &Archive) -> bool {
+    zim.get_metadata("Language").is_ok_and(|languages| {
+        languages
+            .split(',')
+            .map(|l| l.trim().to_lowercase())
+            .any(|l| CJK_LANGUAGES.contains(&l.as_str()))
+    })
+}
+
+pub fn is_cjk_char(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30ff}'   // Hiragana, Katakana
+        | '\u{3400}'..='\u{4dbf}' // CJK Extension A
+        | '\u{4e00}'..='\u{9fff}' // CJK Unified Ideographs
+        | '\u{ac00}'..='\u{d7af}' // Hangul syllables
+        | '\u{1100}'..='\u{11ff}' // Hangul Jamo
+        | '\u{f900}'..='\u{faff}' // CJK Compatibility Ideographs
+        | '\u{20000}'..='\u{2a6df}')
+}
+
+pub fn contains_cjk(text: &str) -> bool {
+    text.chars().any(is_cjk_char)
+}
+
+/// Splits `text` into lowercase tokens: overlapping bigrams for runs of CJK
+/// characters (a lone character stays a unigram) and whitespace/punctuation
+/// separated words for everything else.
+pub fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut run: Vec<char> = Vec::new();
+    let mut word = String::new();
+
+    fn flush_run(run: &mut Vec<char>, tokens: &mut Vec<String>) {
+        match run.len() {
+            0 => {}
+            1 => tokens.push(run[0].to_string()),
+            _ => tokens.extend(run.windows(2).map(|w| w.iter().collect())),
+        }
+        run.clear();
+    }
+
+    for c in text.chars() {
+        if is_cjk_char(c) {
+            if !word.is_empty() {
+                tokens.push(std::mem::take(&mut word));
+            }
+            run.push(c);
+        } else {
+            flush_run(&mut run, &mut tokens);
+            if c.is_alphanumeric() {
+                word.extend(c.to_lowercase());
+            } else if !word.is_empty() {
+                tokens.push(std::mem::take(&mut word));
+            }
+        }
+    }
+    flush_run(&mut run, &mut tokens);
+    if !word.is_empty() {
+        tokens.push(word);
+    }
+    tokens
+}
+
+/// Scans the title index for entries whose title contains every token of
+/// `query`. Used when the archive's full-text index finds nothing for a CJK
+/// query. Returns the number of matches and the entries `start..start + count`;
+/// only the first [`MAX_SCANNED_TITLES`] titles are looked at.
+pub fn title_search(zim: &Archive, query: &str, start: u32, count: u32) -> (u64, Vec<Entry>) {
+    let wanted: HashSet<String> = tokenize(query).into_iter().collect();
+    if wanted.is_empty() {
+        return (0, Vec::new());
+    }
+
+    let mut total = 0u64;
+    let mut entries = Vec::new();
+    for idx in 0..zim.get_entrycount().min(MAX_SCANNED_TITLES) {
+        let Ok(entry) = zim.get_entry_bytitle_index(idx) else {
+            continue;
+        };
+        let title = entry.get_title();
+        // Single-character queries stay unigrams, so index those too.
+        let title_tokens: HashSet<String> = tokenize(&title)
+            .into_iter()
+            .chain(title.chars().filter(|c| is_cjk_char(*c)).map(String::from))
+            .collect();
+        if !wanted.is_subset(&title_tokens) {
+            continue;
+        }
+        if total >= start as u64 && entries.len() < count as usize {
+            entries.push(entry);
+        }
+        total += 1;
+    }
+    (total, entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenize_splits_cjk_runs_into_bigrams() {
+        assert_eq!(tokenize("東京都"), vec!["東京", "京都"]);
+        assert_eq!(tokenize("猫"), vec!["猫"]);
+        assert_eq!(tokenize("한국어"), vec!["한국", "국어"]);
+    }
+
+    #[test]
+    fn tokenize_handles_mixed_scripts() {
+        assert_eq!(
+            tokenize("Rust言語の本 2024"),
+            vec!["rust", "言語", "語の", "の本", "2024"]
+        );
+    }
+
+    #[test]
+    fn tokenize_splits_latin_words_on_punctuation() {
+        assert_eq!(tokenize("Hello, World!"), vec!["hello", "world"]);
+        assert!(tokenize("  -- ").is_empty());
+    }
+
+    #[test]
+    fn contains_cjk_detects_any_cjk_character() {
+        assert!(contains_cjk("abc 中"));
+        assert!(contains_cjk("カタカナ"));
+        assert!(!contains_cjk("Ünïcödé"));
+    }
+}
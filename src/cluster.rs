@@ -0,0 +1,185 @@
+//! Several instances serving one library directory from shared (network)
+//! storage, enabled with `cluster` in the config. The archives being served
+//! are kept in `library_state.json` next to the manifest together with a
+//! generation number. An instance that changes the library (upload, delete,
+//! import, download, or the indexer seeing archives come and go on disk)
+//! applies its change to that file under the library lock and bumps the
+//! generation. Every instance polls the generation and, once it moved, takes
+//! over the archive list and drops what it kept for archives that went away
+//! or were replaced.
+//!
+//! Only the indexer watches the library roots, so a file still being copied
+//! is held back by one instance for everybody instead of each instance
+//! deciding on its own when it is stable.
+
+use crate::AppState;
+use crate::library::lock_library;
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+pub const STATE_FILE: &str = "library_state.json";
+
+/// How often a disabled poller checks whether cluster mode was enabled by a
+/// reload.
+const DISABLED_RECHECK: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Default)]
+struct SharedLibrary {
+    generation: u64,
+    archives: HashMap<String, PathBuf>,
+}
+
+pub enum Change {
+    Added(String, PathBuf),
+    Removed(String),
+    /// The whole library, as scanned by the indexer.
+    Replaced(HashMap<String, PathBuf>),
+}
+
+/// Generation of the shared library state this instance last applied.
+#[derive(Clone, Default)]
+pub struct ClusterSync {
+    applied: Arc<AtomicU64>,
+}
+
+fn load(library_dir: &Path) -> Result<SharedLibrary> {
+    let path = library_dir.join(STATE_FILE);
+    if !path.exists() {
+        return Ok(SharedLibrary::default());
+    }
+    let raw =
+        fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    serde_json::from_str(&raw).with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+/// Written through a temporary file, so readers polling without the lock
+/// never see a truncated state.
+fn save(library_dir: &Path, shared: &SharedLibrary) -> Result<()> {
+    let path = library_dir.join(STATE_FILE);
+    let tmp_path = library_dir.join(format!("{}.tmp", STATE_FILE));
+    fs::write(&tmp_path, serde_json::to_vec_pretty(shared)?)?;
+    fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
+
+fn is_enabled(state: &AppState) -> bool {
+    state.kiosk_archive.is_none() && state.config.read().unwrap().cluster.is_some()
+}
+
+/// Applies `changes` to the shared library state for the other instances to
+/// pick up. Does nothing outside cluster mode.
+pub fn publish(state: &AppState, changes: Vec<Change>) {
+    if changes.is_empty() || !is_enabled(state) {
+        return;
+    }
+    let library_dir = state.library_dir();
+    let result = lock_library(&library_dir).and_then(|_lock| {
+        let mut shared = load(&library_dir)?;
+        for change in changes {
+            match change {
+                Change::Added(id, path) => {
+                    shared.archives.insert(id, path);
+                }
+                Change::Removed(id) => {
+                    shared.archives.remove(&id);
+                }
+                Change::Replaced(archives) => shared.archives = archives,
+            }
+        }
+        shared.generation += 1;
+        save(&library_dir, &shared)
+    });
+    if let Err(e) = result {
+        warn!("Failed to publish library change: {}", e);
+    }
+}
+
+/// Takes over the shared archive list if another instance changed it since
+/// the last call.
+fn sync(state: &AppState) -> Result<()> {
+    let shared = load(&state.library_dir())?;
+    // Nothing published yet: keep what this instance scanned itself.
+    if shared.generation == 0 || shared.generation == state.cluster.applied.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    let mut file_cache = state.file_cache.lock().unwrap();
+    let stale: Vec<(String, PathBuf)> = file_cache
+        .iter()
+        .filter(|(id, path)| shared.archives.get(*id) != Some(*path))
+        .map(|(id, path)| (id.clone(), path.clone()))
+        .collect();
+    let added = shared
+        .archives
+        .keys()
+        .filter(|id| !file_cache.contains_key(*id))
+        .count();
+    if added > 0 || !stale.is_empty() {
+        info!(
+            "Library generation {}: {} archives added, {} removed or replaced",
+            shared.generation,
+            added,
+            stale.len()
+        );
+    }
+    *file_cache = shared.archives;
+    drop(file_cache);
+    for (id, path) in &stale {
+        state.forget_archive(id, path);
+    }
+    state
+        .cluster
+        .applied
+        .store(shared.generation, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Starts following the shared library state in the background. The indexer
+/// first publishes the library it loaded at startup.
+pub fn spawn(state: AppState) {
+    if is_enabled(&state) && state.config.read().unwrap().is_indexer() {
+        let archives = state.file_cache.lock().unwrap().clone();
+        publish(&state, vec![Change::Replaced(archives)]);
+    }
+    thread::spawn(move || {
+        loop {
+            let poll_secs = state
+                .config
+                .read()
+                .unwrap()
+                .cluster
+                .as_ref()
+                .map(|cluster| cluster.poll_secs.max(1));
+            let Some(poll_secs) = poll_secs.filter(|_| state.kiosk_archive.is_none()) else {
+                thread::sleep(DISABLED_RECHECK);
+                continue;
+            };
+            if let Err(e) = sync(&state) {
+                warn!("Failed to read shared library state: {}", e);
+            }
+            thread::sleep(Duration::from_secs(poll_secs));
+        }
+    });
+}
+
+/// Called after the library was rescanned because its roots changed: the
+/// indexer publishes the new scan, the others take the shared list again.
+pub fn library_reloaded(state: &AppState) {
+    if !is_enabled(state) {
+        return;
+    }
+    if state.config.read().unwrap().is_indexer() {
+        let archives = state.file_cache.lock().unwrap().clone();
+        publish(state, vec![Change::Replaced(archives)]);
+    } else {
+        state.cluster.applied.store(0, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,552 @@
+//! Collections: named, ordered reading lists of articles that can span several
+//! archives. They belong to the browser session that created them and are
+//! persisted as `collections.json` in the library directory, through a
+//! [`JsonStore`] so requests don't wait on the disk. A share link
+//! gives read-only access to anyone holding its token, and a collection can be
+//! exported as JSON or as an EPUB of its articles.
+
+use crate::AppState;
+use crate::article_html::{self, ArticleHtml};
+use crate::epub::{self, Chapter};
+use crate::json_store::JsonStore;
+use crate::session;
+use actix_web::{HttpRequest, HttpResponse, Responder, delete, get, post, put, web};
+use anyhow::Result;
+use chrono::Utc;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::path::Path;
+
+pub const COLLECTIONS_FILE: &str = "collections.json";
+const MAX_ITEMS: usize = 1000;
+const MAX_COLLECTIONS_PER_SESSION: usize = 200;
+/// Collections stored across every session.
+const MAX_COLLECTIONS: usize = 50_000;
+const MAX_NAME_CHARS: usize = 200;
+const MAX_DESCRIPTION_CHARS: usize = 4000;
+/// Limit on an item's archive id, path, title and note.
+const MAX_ITEM_FIELD_CHARS: usize = 2000;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CollectionItem {
+    pub archive_id: String,
+    pub path: String,
+    /// Display title; the article's own title is used when empty.
+    #[serde(default)]
+    pub title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Collection {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub items: Vec<CollectionItem>,
+    /// Token of the read-only share link, when shared.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub share_token: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct StoredCollection {
+    owner: String,
+    #[serde(flatten)]
+    collection: Collection,
+}
+
+/// Why a collection couldn't be created.
+#[derive(Debug, PartialEq)]
+pub enum LimitReached {
+    Session,
+    Library,
+}
+
+#[derive(Clone)]
+pub struct Collections {
+    store: JsonStore<StoredCollection>,
+}
+
+impl Collections {
+    pub fn load(library_dir: &Path) -> Result<Collections> {
+        Ok(Collections {
+            store: JsonStore::load(library_dir, COLLECTIONS_FILE)?,
+        })
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.store.flush()
+    }
+
+    /// The session's collections, most recently updated first.
+    pub fn list(&self, owner: &str) -> Vec<Collection> {
+        let mut owned: Vec<Collection> = self.store.read(|collections| {
+            collections
+                .values()
+                .filter(|stored| stored.owner == owner)
+                .map(|stored| stored.collection.clone())
+                .collect()
+        });
+        owned.sort_by_key(|collection| std::cmp::Reverse(collection.updated_at));
+        owned
+    }
+
+    pub fn get(&self, owner: &str, id: &str) -> Option<Collection> {
+        self.store.read(|collections| {
+            collections
+                .get(id)
+                .filter(|stored| stored.owner == owner)
+                .map(|stored| stored.collection.clone())
+        })
+    }
+
+    pub fn get_shared(&self, token: &str) -> Option<Collection> {
+        self.store.read(|collections| {
+            collections
+                .values()
+                .find(|stored| stored.collection.share_token.as_deref() == Some(token))
+                .map(|stored| stored.collection.clone())
+        })
+    }
+
+    pub fn create(
+        &self,
+        owner: &str,
+        name: String,
+        description: String,
+        items: Vec<CollectionItem>,
+    ) -> Result<Collection, LimitReached> {
+        let now = Utc::now().timestamp();
+        let collection = Collection {
+            id: uuid::Uuid::new_v4().to_string(),
+            name,
+            description,
+            items,
+            share_token: None,
+            created_at: now,
+            updated_at: now,
+        };
+        self.store.write(|collections| {
+            if collections.len() >= MAX_COLLECTIONS {
+                return Err(LimitReached::Library);
+            }
+            let owned = collections
+                .values()
+                .filter(|stored| stored.owner == owner)
+                .count();
+            if owned >= MAX_COLLECTIONS_PER_SESSION {
+                return Err(LimitReached::Session);
+            }
+            collections.insert(
+                collection.id.clone(),
+                StoredCollection {
+                    owner: owner.to_string(),
+                    collection: collection.clone(),
+                },
+            );
+            Ok(collection)
+        })
+    }
+
+    /// Applies `change` to one of the session's collections and saves it.
+    pub fn update<F>(&self, owner: &str, id: &str, change: F) -> Option<Collection>
+    where
+        F: FnOnce(&mut Collection),
+    {
+        self.store.write(|collections| {
+            let stored = collections
+                .get_mut(id)
+                .filter(|stored| stored.owner == owner)?;
+            change(&mut stored.collection);
+            stored.collection.updated_at = Utc::now().timestamp();
+            Some(stored.collection.clone())
+        })
+    }
+
+    pub fn delete(&self, owner: &str, id: &str) -> bool {
+        self.store.write(|collections| {
+            if collections
+                .get(id)
+                .is_none_or(|stored| stored.owner != owner)
+            {
+                return false;
+            }
+            collections.remove(id);
+            true
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateRequest {
+    name: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    items: Vec<CollectionItem>,
+}
+
+/// Fields to change; the item list is replaced as a whole, which is also how
+/// items are reordered.
+#[derive(Deserialize)]
+struct UpdateRequest {
+    name: Option<String>,
+    description: Option<String>,
+    items: Option<Vec<CollectionItem>>,
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    #[serde(default)]
+    format: Option<String>,
+}
+
+/// Checks the fields a request sets against the size limits.
+fn check_fields(
+    name: Option<&str>,
+    description: Option<&str>,
+    items: Option<&[CollectionItem]>,
+) -> Result<(), String> {
+    if let Some(name) = name {
+        if name.trim().is_empty() {
+            return Err("Name is required".to_string());
+        }
+        if name.chars().count() > MAX_NAME_CHARS {
+            return Err(format!("Names are at most {} characters", MAX_NAME_CHARS));
+        }
+    }
+    if description.is_some_and(|d| d.chars().count() > MAX_DESCRIPTION_CHARS) {
+        return Err(format!(
+            "Descriptions are at most {} characters",
+            MAX_DESCRIPTION_CHARS
+        ));
+    }
+    let items = items.unwrap_or_default();
+    if items.len() > MAX_ITEMS {
+        return Err(format!("At most {} items per collection", MAX_ITEMS));
+    }
+    let too_long = |field: &str| field.chars().count() > MAX_ITEM_FIELD_CHARS;
+    if items.iter().any(|item| {
+        too_long(&item.archive_id)
+            || too_long(&item.path)
+            || too_long(&item.title)
+            || item.note.as_deref().is_some_and(too_long)
+    }) {
+        return Err(format!(
+            "Item fields are at most {} characters",
+            MAX_ITEM_FIELD_CHARS
+        ));
+    }
+    Ok(())
+}
+
+#[get("/collections")]
+async fn list_collections(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    let (session, cookie) = session::session_id(&req);
+    session::with_session(cookie, HttpResponse::Ok()).json(state.collections.list(&session))
+}
+
+#[post("/collections")]
+async fn create_collection(
+    req: HttpRequest,
+    body: web::Json<CreateRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let body = body.into_inner();
+    if let Err(e) = check_fields(Some(&body.name), Some(&body.description), Some(&body.items)) {
+        return HttpResponse::BadRequest().json(json!({"error": e}));
+    }
+    let (session, cookie) = session::session_id(&req);
+    match state
+        .collections
+        .create(&session, body.name, body.description, body.items)
+    {
+        Ok(collection) => session::with_session(cookie, HttpResponse::Created()).json(collection),
+        Err(LimitReached::Session) => HttpResponse::BadRequest().json(json!({
+            "error": format!("At most {} collections per session", MAX_COLLECTIONS_PER_SESSION)
+        })),
+        Err(LimitReached::Library) => HttpResponse::InsufficientStorage()
+            .json(json!({"error": "No more collections can be stored"})),
+    }
+}
+
+#[get("/collections/{id}")]
+async fn get_collection(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let (session, _) = session::session_id(&req);
+    match state.collections.get(&session, &path) {
+        Some(collection) => HttpResponse::Ok().json(collection),
+        None => HttpResponse::NotFound().json(json!({"error": "Collection not found"})),
+    }
+}
+
+#[put("/collections/{id}")]
+async fn update_collection(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: web::Json<UpdateRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let body = body.into_inner();
+    if let Err(e) = check_fields(
+        body.name.as_deref(),
+        body.description.as_deref(),
+        body.items.as_deref(),
+    ) {
+        return HttpResponse::BadRequest().json(json!({"error": e}));
+    }
+    let (session, _) = session::session_id(&req);
+    let updated = state.collections.update(&session, &path, |collection| {
+        if let Some(name) = body.name {
+            collection.name = name;
+        }
+        if let Some(description) = body.description {
+            collection.description = description;
+        }
+        if let Some(items) = body.items {
+            collection.items = items;
+        }
+    });
+    match updated {
+        Some(collection) => HttpResponse::Ok().json(collection),
+        None => HttpResponse::NotFound().json(json!({"error": "Collection not found"})),
+    }
+}
+
+#[delete("/collections/{id}")]
+async fn delete_collection(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let (session, _) = session::session_id(&req);
+    if state.collections.delete(&session, &path) {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().json(json!({"error": "Collection not found"}))
+    }
+}
+
+/// Creates (or returns the existing) read-only share link.
+#[post("/collections/{id}/share")]
+async fn share_collection(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let (session, _) = session::session_id(&req);
+    let shared = state.collections.update(&session, &path, |collection| {
+        collection
+            .share_token
+            .get_or_insert_with(|| uuid::Uuid::new_v4().simple().to_string());
+    });
+    match shared.and_then(|collection| collection.share_token) {
+        Some(token) => HttpResponse::Ok().json(json!({
+            "token": token,
+            "url": format!("/shared/collections/{}", token),
+        })),
+        None => HttpResponse::NotFound().json(json!({"error": "Collection not found"})),
+    }
+}
+
+/// Revokes the share link; the old token stops working.
+#[delete("/collections/{id}/share")]
+async fn unshare_collection(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let (session, _) = session::session_id(&req);
+    match state
+        .collections
+        .update(&session, &path, |collection| collection.share_token = None)
+    {
+        Some(collection) => HttpResponse::Ok().json(collection),
+        None => HttpResponse::NotFound().json(json!({"error": "Collection not found"})),
+    }
+}
+
+#[get("/shared/collections/{token}")]
+async fn get_shared_collection(
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    match state.collections.get_shared(&path) {
+        Some(collection) => HttpResponse::Ok().json(collection),
+        None => HttpResponse::NotFound().json(json!({"error": "Collection not found"})),
+    }
+}
+
+/// Reads every article of the collection that is still in the library and
+/// not blocked, in order.
+fn collect_chapters(state: &AppState, collection: &Collection) -> Vec<Chapter> {
+    let mut chapters = Vec::new();
+    for item in &collection.items {
+        let Some(zim_path) = state.archive_path(&item.archive_id) else {
+            warn!(
+                "Collection {}: archive {} not found",
+                collection.id, item.archive_id
+            );
+            continue;
+        };
+        let _lease = state.leases.acquire(&zim_path);
+        let blocklist = state.blocklist_for(Some(&item.archive_id));
+        match article_html::read_article(&zim_path, &item.path, &blocklist) {
+            Ok(ArticleHtml::Found { title, html, .. }) => chapters.push(Chapter {
+                title: if item.title.is_empty() {
+                    title
+                } else {
+                    item.title.clone()
+                },
+                html,
+            }),
+            Ok(_) => warn!(
+                "Collection {}: {} is not available",
+                collection.id, item.path
+            ),
+            Err(e) => warn!(
+                "Collection {}: failed to read {}: {}",
+                collection.id, item.path, e
+            ),
+        }
+    }
+    chapters
+}
+
+async fn export(
+    collection: Collection,
+    format: Option<&str>,
+    state: web::Data<AppState>,
+) -> HttpResponse {
+    let file_stem: String = collection
+        .name
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect();
+    match format.unwrap_or("json") {
+        "json" => HttpResponse::Ok()
+            .insert_header((
+                "Content-Disposition",
+                format!("attachment; filename=\"{}.json\"", file_stem),
+            ))
+            .json(collection),
+        "epub" => {
+            let result = web::block(move || {
+                let chapters = collect_chapters(&state, &collection);
+                if chapters.is_empty() {
+                    return Ok(None);
+                }
+                epub::build(
+                    &collection.id,
+                    &collection.name,
+                    &collection.description,
+                    &chapters,
+                )
+                .map(Some)
+            })
+            .await;
+            match result {
+                Ok(Ok(Some(book))) => HttpResponse::Ok()
+                    .content_type("application/epub+zip")
+                    .insert_header((
+                        "Content-Disposition",
+                        format!("attachment; filename=\"{}.epub\"", file_stem),
+                    ))
+                    .body(book),
+                Ok(Ok(None)) => HttpResponse::NotFound()
+                    .json(json!({"error": "None of the collection's articles are available"})),
+                Ok(Err(e)) => {
+                    HttpResponse::InternalServerError().json(json!({"error": e.to_string()}))
+                }
+                Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+            }
+        }
+        other => HttpResponse::BadRequest()
+            .json(json!({"error": format!("Unsupported format '{}', use json or epub", other)})),
+    }
+}
+
+#[get("/collections/{id}/export", wrap = "crate::load_shed::Shed::heavy()")]
+async fn export_collection(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<ExportQuery>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let (session, _) = session::session_id(&req);
+    match state.collections.get(&session, &path) {
+        Some(collection) => export(collection, query.format.as_deref(), state).await,
+        None => HttpResponse::NotFound().json(json!({"error": "Collection not found"})),
+    }
+}
+
+#[get(
+    "/shared/collections/{token}/export",
+    wrap = "crate::load_shed::Shed::heavy()"
+)]
+async fn export_shared_collection(
+    path: web::Path<String>,
+    query: web::Query<ExportQuery>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    match state.collections.get_shared(&path) {
+        Some(collection) => export(collection, query.format.as_deref(), state).await,
+        None => HttpResponse::NotFound().json(json!({"error": "Collection not found"})),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(path: &str) -> CollectionItem {
+        CollectionItem {
+            archive_id: "wiki".to_string(),
+            path: path.to_string(),
+            title: String::new(),
+            note: None,
+        }
+    }
+
+    #[test]
+    fn oversized_fields_are_rejected() {
+        assert!(check_fields(Some("Reading"), Some(""), Some(&[item("A")])).is_ok());
+        assert!(check_fields(Some("  "), None, None).is_err());
+        let long = "x".repeat(MAX_NAME_CHARS + 1);
+        assert!(check_fields(Some(&long), None, None).is_err());
+        let items = vec![item("A"); MAX_ITEMS + 1];
+        assert!(check_fields(None, None, Some(&items)).is_err());
+        let long_path = item(&"p".repeat(MAX_ITEM_FIELD_CHARS + 1));
+        assert!(check_fields(None, None, Some(&[long_path])).is_err());
+    }
+
+    #[test]
+    fn sessions_are_limited_in_collections() {
+        let dir = std::env::temp_dir().join(format!("collections-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let collections = Collections::load(&dir).unwrap();
+        for _ in 0..MAX_COLLECTIONS_PER_SESSION {
+            assert!(
+                collections
+                    .create("a", "List".to_string(), String::new(), Vec::new())
+                    .is_ok()
+            );
+        }
+        let over = collections.create("a", "List".to_string(), String::new(), Vec::new());
+        assert_eq!(over.err(), Some(LimitReached::Session));
+        assert!(
+            collections
+                .create("b", "List".to_string(), String::new(), Vec::new())
+                .is_ok()
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
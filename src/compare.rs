@@ -0,0 +1,242 @@
+//! Comparison of two versions of a book. Entries are matched by path and
+//! compared by the SHA-256 of their content (or their target, for redirects);
+//! the report counts every entry added, removed or changed and lists the
+//! first [`MAX_REPORTED_ENTRIES`] of them, as JSON and CSV.
+
+use crate::AppState;
+use crate::jobs::{self, JobHandle};
+use actix_web::{HttpResponse, Responder, post, web};
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use zim_rs::archive::Archive;
+
+const JSON_REPORT: &str = "comparison.json";
+const CSV_REPORT: &str = "comparison.csv";
+/// Differing entries listed individually; past this only the counts grow.
+const MAX_REPORTED_ENTRIES: usize = 100_000;
+
+#[derive(Deserialize)]
+struct CompareRequest {
+    /// Archive id of the older version.
+    old: String,
+    /// Archive id of the newer version.
+    new: String,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum Change {
+    Added,
+    Removed,
+    Changed,
+}
+
+impl Change {
+    fn as_str(self) -> &'static str {
+        match self {
+            Change::Added => "added",
+            Change::Removed => "removed",
+            Change::Changed => "changed",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct EntryChange {
+    change: Change,
+    path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    old_sha256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    new_sha256: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ComparisonReport {
+    old: String,
+    new: String,
+    added: u64,
+    removed: u64,
+    changed: u64,
+    unchanged: u64,
+    entries: Vec<EntryChange>,
+}
+
+/// Walks an archive's entries in path order.
+struct EntryCursor {
+    zim: Archive,
+    idx: u32,
+    total: u32,
+}
+
+impl EntryCursor {
+    fn open(zim_path: &Path) -> Result<EntryCursor> {
+        let zim = Archive::new(zim_path.to_str().unwrap())
+            .map_err(|e| anyhow!("Failed to open archive {}: {:?}", zim_path.display(), e))?;
+        let total = zim.get_all_entrycount();
+        Ok(EntryCursor { zim, idx: 0, total })
+    }
+
+    fn peek_path(&self) -> Option<String> {
+        (self.idx < self.total).then(|| {
+            self.zim
+                .get_entry_bypath_index(self.idx)
+                .map(|e| e.get_path())
+                .unwrap_or_default()
+        })
+    }
+
+    /// Checksum of the current entry. Redirects hash their target path so a
+    /// redirect that now points elsewhere counts as changed.
+    fn checksum(&self) -> Result<String> {
+        let entry = self
+            .zim
+            .get_entry_bypath_index(self.idx)
+            .map_err(|e| anyhow!("Failed to read entry {}: {:?}", self.idx, e))?;
+        let mut hasher = Sha256::new();
+        if entry.is_redirect() {
+            let target = entry
+                .get_redirect_entry()
+                .map_err(|e| anyhow!("Broken redirect {}: {:?}", entry.get_path(), e))?;
+            hasher.update(b"redirect:");
+            hasher.update(target.get_path().as_bytes());
+        } else {
+            let blob = entry
+                .get_item(false)
+                .and_then(|item| item.get_data())
+                .map_err(|e| anyhow!("Failed to read {}: {:?}", entry.get_path(), e))?;
+            hasher.update(blob.data());
+        }
+        Ok(hex::encode(hasher.finalize()))
+    }
+}
+
+fn compare_archives(old_path: &Path, new_path: &Path, job: &JobHandle) -> Result<ComparisonReport> {
+    let mut old = EntryCursor::open(old_path)?;
+    let mut new = EntryCursor::open(new_path)?;
+    job.set_total(old.total as u64 + new.total as u64);
+    job.set_phase("comparing");
+
+    let mut report = ComparisonReport {
+        old: old.zim.get_uuid().to_string(),
+        new: new.zim.get_uuid().to_string(),
+        added: 0,
+        removed: 0,
+        changed: 0,
+        unchanged: 0,
+        entries: Vec::new(),
+    };
+    loop {
+        job.set_processed(old.idx as u64 + new.idx as u64);
+        let (change, path, old_sha256, new_sha256) = match (old.peek_path(), new.peek_path()) {
+            (None, None) => break,
+            (Some(path), None) => (Change::Removed, path, Some(old.checksum()?), None),
+            (None, Some(path)) => (Change::Added, path, None, Some(new.checksum()?)),
+            (Some(old_entry), Some(new_entry)) => match old_entry.cmp(&new_entry) {
+                Ordering::Less => (Change::Removed, old_entry, Some(old.checksum()?), None),
+                Ordering::Greater => (Change::Added, new_entry, None, Some(new.checksum()?)),
+                Ordering::Equal => {
+                    let (old_sum, new_sum) = (old.checksum()?, new.checksum()?);
+                    old.idx += 1;
+                    new.idx += 1;
+                    if old_sum == new_sum {
+                        report.unchanged += 1;
+                        continue;
+                    }
+                    report.changed += 1;
+                    if report.entries.len() < MAX_REPORTED_ENTRIES {
+                        report.entries.push(EntryChange {
+                            change: Change::Changed,
+                            path: old_entry,
+                            old_sha256: Some(old_sum),
+                            new_sha256: Some(new_sum),
+                        });
+                    }
+                    continue;
+                }
+            },
+        };
+        match change {
+            Change::Removed => {
+                old.idx += 1;
+                report.removed += 1;
+            }
+            _ => {
+                new.idx += 1;
+                report.added += 1;
+            }
+        }
+        if report.entries.len() < MAX_REPORTED_ENTRIES {
+            report.entries.push(EntryChange {
+                change,
+                path,
+                old_sha256,
+                new_sha256,
+            });
+        }
+    }
+    Ok(report)
+}
+
+pub(crate) fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn write_reports(job: &JobHandle, report: &ComparisonReport) -> Result<()> {
+    job.set_phase("writing reports");
+    fs::write(
+        job.output_dir().join(JSON_REPORT),
+        serde_json::to_vec_pretty(report)?,
+    )?;
+    job.add_output_file(JSON_REPORT);
+
+    let mut csv = BufWriter::new(File::create(job.output_dir().join(CSV_REPORT))?);
+    writeln!(csv, "change,path,old_sha256,new_sha256")?;
+    for entry in &report.entries {
+        writeln!(
+            csv,
+            "{},{},{},{}",
+            entry.change.as_str(),
+            csv_field(&entry.path),
+            entry.old_sha256.as_deref().unwrap_or(""),
+            entry.new_sha256.as_deref().unwrap_or("")
+        )?;
+    }
+    csv.flush()?;
+    job.add_output_file(CSV_REPORT);
+    Ok(())
+}
+
+#[post("/archives/compare", wrap = "crate::load_shed::Shed::job()")]
+async fn compare(req: web::Json<CompareRequest>, state: web::Data<AppState>) -> impl Responder {
+    let (Some(old_path), Some(new_path)): (Option<PathBuf>, Option<PathBuf>) =
+        (state.archive_path(&req.old), state.archive_path(&req.new))
+    else {
+        return HttpResponse::NotFound().json(json!({"error": "Archive not found"}));
+    };
+    let leases = [
+        state.leases.acquire(&old_path),
+        state.leases.acquire(&new_path),
+    ];
+
+    match state
+        .jobs
+        .spawn("archive_compare", &jobs::jobs_dir(&state), move |job| {
+            let _leases = leases;
+            let report = compare_archives(&old_path, &new_path, job)?;
+            write_reports(job, &report)
+        }) {
+        Ok(job_id) => HttpResponse::Accepted().json(json!({ "job_id": job_id })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+    }
+}
@@ -0,0 +1,413 @@
+use crate::blocklist::Blocklist;
+use actix_web::http::Uri;
+use anyhow::{Context, Result, bail};
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_CONFIG_PATH: &str = "./config.json";
+/// Peers a federated search may fan out to.
+pub const MAX_PEERS: usize = 16;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub fn to_filter(self) -> LevelFilter {
+        match self {
+            LogLevel::Off => LevelFilter::Off,
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+/// Rotating log file settings. Files are written as `access.log` and `app.log`
+/// inside `dir`.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(default)]
+pub struct LogFileConfig {
+    pub dir: PathBuf,
+    /// Rotate once a file would grow past this size. 0 disables size rotation.
+    pub max_bytes: u64,
+    /// Rotate files older than this. 0 disables time rotation.
+    pub max_age_secs: u64,
+    /// Number of rotated files kept per log.
+    pub retention: usize,
+}
+
+impl Default for LogFileConfig {
+    fn default() -> Self {
+        LogFileConfig {
+            dir: PathBuf::from("./logs"),
+            max_bytes: 10 * 1024 * 1024,
+            max_age_secs: 24 * 60 * 60,
+            retention: 5,
+        }
+    }
+}
+
+/// On-disk cache of compressed article bodies served under `/content`.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(default)]
+pub struct BodyCacheConfig {
+    pub dir: PathBuf,
+    /// New bodies are no longer stored once the cache holds this much.
+    pub max_bytes: u64,
+    /// Bodies smaller than this are sent as-is; compressing them gains little.
+    pub min_bytes: u64,
+}
+
+impl Default for BodyCacheConfig {
+    fn default() -> Self {
+        BodyCacheConfig {
+            dir: PathBuf::from("./cache/bodies"),
+            max_bytes: 1024 * 1024 * 1024,
+            min_bytes: 1024,
+        }
+    }
+}
+
+/// PEM files used to serve HTTPS (and HTTP/2) instead of plain HTTP.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct TlsConfig {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum TranslationBackend {
+    LibreTranslate,
+}
+
+/// External service used to translate articles.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct TranslationConfig {
+    pub backend: TranslationBackend,
+    /// Base URL of the service, e.g. `http://localhost:5000`.
+    pub url: String,
+    #[serde(default)]
+    pub api_key: Option<String>,
+    #[serde(default = "default_translation_timeout")]
+    pub timeout_secs: u64,
+}
+
+fn default_translation_timeout() -> u64 {
+    60
+}
+
+/// Another kiwix-serve or Zim-viewer instance searched by
+/// `/federated/search`.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct PeerConfig {
+    /// Label shown next to the peer's results.
+    pub name: String,
+    /// Base URL, e.g. `http://192.168.1.20:8080`.
+    pub url: String,
+    #[serde(default = "default_peer_timeout")]
+    pub timeout_secs: u64,
+}
+
+fn default_peer_timeout() -> u64 {
+    10
+}
+
+/// Several instances serving one library directory on shared storage.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(default)]
+pub struct ClusterConfig {
+    /// Watch the library roots and publish what changes on disk. Exactly one
+    /// instance should be the indexer; the others follow it.
+    pub indexer: bool,
+    /// How often the shared library state is checked for changes made by
+    /// other instances.
+    pub poll_secs: u64,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        ClusterConfig {
+            indexer: false,
+            poll_secs: 2,
+        }
+    }
+}
+
+/// Server configuration, read from a JSON file. Every field is optional in the
+/// file; anything missing falls back to the defaults below.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(default)]
+pub struct Config {
+    /// Address, port and TLS are only read at startup, changing them needs a
+    /// restart.
+    pub bind_address: String,
+    pub port: u16,
+    pub tls: Option<TlsConfig>,
+    pub library_dir: PathBuf,
+    /// More directories scanned for `.zim` files, e.g. an external drive.
+    /// Uploads, jobs and the manifest always live in `library_dir`.
+    pub extra_library_dirs: Vec<PathBuf>,
+    /// How often the library roots are checked for added, removed or replaced
+    /// archives. 0 disables the check.
+    pub library_poll_secs: u64,
+    pub max_upload_bytes: u64,
+    pub max_search_page_size: u32,
+    /// Searches allowed to run at once; further ones wait in a fair queue.
+    pub max_concurrent_searches: usize,
+    pub max_queued_searches: usize,
+    pub search_queue_timeout_secs: u64,
+    /// Reverse proxies whose `Forwarded`/`X-Forwarded-For` headers are
+    /// believed when telling clients apart. Otherwise the connecting address
+    /// is used, as anyone can send those headers.
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Searches, article tools and browsing served at once before further
+    /// ones get `503`. 0 disables the limit.
+    pub max_heavy_requests: usize,
+    /// Jobs running at once before new ones are refused. 0 disables the limit.
+    pub max_running_jobs: usize,
+    /// Collect query statistics in memory for `/admin/analytics`.
+    pub query_analytics: bool,
+    /// Bearer tokens accepted by the `/admin` endpoints. Empty means no auth.
+    pub auth_tokens: Vec<String>,
+    pub log_level: LogLevel,
+    /// Log to rotating files instead of the console when set.
+    pub log_files: Option<LogFileConfig>,
+    /// Entries hidden per archive id; the `*` key applies to every archive.
+    pub blocklists: BTreeMap<String, Blocklist>,
+    /// Titles suggested per archive id before anything has been typed, ahead
+    /// of the most viewed ones.
+    pub featured_titles: BTreeMap<String, Vec<String>>,
+    pub translation: Option<TranslationConfig>,
+    /// Keep gzip/brotli copies of served text content on disk when set.
+    pub body_cache: Option<BodyCacheConfig>,
+    /// Share `library_dir` with other instances when set.
+    pub cluster: Option<ClusterConfig>,
+    /// Remote instances whose results `/federated/search` merges in.
+    pub peers: Vec<PeerConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind_address: "127.0.0.1".to_string(),
+            port: 8080,
+            tls: None,
+            library_dir: PathBuf::from("./uploads"),
+            extra_library_dirs: Vec::new(),
+            library_poll_secs: 30,
+            max_upload_bytes: 64 * 1024 * 1024 * 1024,
+            max_search_page_size: 200,
+            max_concurrent_searches: 4,
+            max_queued_searches: 64,
+            search_queue_timeout_secs: 30,
+            trusted_proxies: Vec::new(),
+            max_heavy_requests: 32,
+            max_running_jobs: 4,
+            query_analytics: false,
+            auth_tokens: Vec::new(),
+            log_level: LogLevel::Info,
+            log_files: None,
+            blocklists: BTreeMap::new(),
+            featured_titles: BTreeMap::new(),
+            translation: None,
+            body_cache: None,
+            cluster: None,
+            peers: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config file, returning the defaults if it does not exist.
+    pub fn load(path: &Path) -> Result<Config> {
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        let raw = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let config: Config = serde_json::from_str(&raw)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+        config
+            .check_peers()
+            .with_context(|| format!("Invalid config file {}", path.display()))?;
+        Ok(config)
+    }
+
+    /// Peers must be reachable over HTTP(S), which is all the federated search
+    /// speaks, and few enough for one search to ask them all.
+    fn check_peers(&self) -> Result<()> {
+        if self.peers.len() > MAX_PEERS {
+            bail!("At most {} peers can be configured", MAX_PEERS);
+        }
+        for peer in &self.peers {
+            let uri: Uri = peer
+                .url
+                .parse()
+                .with_context(|| format!("Peer {} has an invalid url {}", peer.name, peer.url))?;
+            let http = matches!(uri.scheme_str(), Some("http" | "https"));
+            if !http || uri.host().is_none() {
+                bail!(
+                    "Peer {} must have an http:// or https:// url, not {}",
+                    peer.name,
+                    peer.url
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Names of the settings that differ between `self` and `new` but cannot be
+    /// applied without rebinding the listener.
+    pub fn restart_required(&self, new: &Config) -> Vec<&'static str> {
+        let mut fields = Vec::new();
+        if self.bind_address != new.bind_address {
+            fields.push("bind_address");
+        }
+        if self.port != new.port {
+            fields.push("port");
+        }
+        if self.tls != new.tls {
+            fields.push("tls");
+        }
+        fields
+    }
+
+    /// Names of the hot-reloadable settings that differ between `self` and `new`.
+    pub fn changed_fields(&self, new: &Config) -> Vec<&'static str> {
+        let mut fields = Vec::new();
+        if self.library_dir != new.library_dir {
+            fields.push("library_dir");
+        }
+        if self.extra_library_dirs != new.extra_library_dirs {
+            fields.push("extra_library_dirs");
+        }
+        if self.library_poll_secs != new.library_poll_secs {
+            fields.push("library_poll_secs");
+        }
+        if self.max_upload_bytes != new.max_upload_bytes {
+            fields.push("max_upload_bytes");
+        }
+        if self.max_search_page_size != new.max_search_page_size {
+            fields.push("max_search_page_size");
+        }
+        if self.max_concurrent_searches != new.max_concurrent_searches {
+            fields.push("max_concurrent_searches");
+        }
+        if self.max_queued_searches != new.max_queued_searches {
+            fields.push("max_queued_searches");
+        }
+        if self.search_queue_timeout_secs != new.search_queue_timeout_secs {
+            fields.push("search_queue_timeout_secs");
+        }
+        if self.trusted_proxies != new.trusted_proxies {
+            fields.push("trusted_proxies");
+        }
+        if self.max_heavy_requests != new.max_heavy_requests {
+            fields.push("max_heavy_requests");
+        }
+        if self.max_running_jobs != new.max_running_jobs {
+            fields.push("max_running_jobs");
+        }
+        if self.query_analytics != new.query_analytics {
+            fields.push("query_analytics");
+        }
+        if self.auth_tokens != new.auth_tokens {
+            fields.push("auth_tokens");
+        }
+        if self.log_level != new.log_level {
+            fields.push("log_level");
+        }
+        if self.log_files != new.log_files {
+            fields.push("log_files");
+        }
+        if self.blocklists != new.blocklists {
+            fields.push("blocklists");
+        }
+        if self.featured_titles != new.featured_titles {
+            fields.push("featured_titles");
+        }
+        if self.translation != new.translation {
+            fields.push("translation");
+        }
+        if self.body_cache != new.body_cache {
+            fields.push("body_cache");
+        }
+        if self.cluster != new.cluster {
+            fields.push("cluster");
+        }
+        if self.peers != new.peers {
+            fields.push("peers");
+        }
+        fields
+    }
+
+    /// Every directory scanned for archives, `library_dir` first.
+    pub fn library_roots(&self) -> Vec<PathBuf> {
+        let mut roots = vec![self.library_dir.clone()];
+        roots.extend(self.extra_library_dirs.iter().cloned());
+        roots
+    }
+
+    /// Whether this instance scans the library roots itself: always, unless
+    /// it follows another instance's index.
+    pub fn is_indexer(&self) -> bool {
+        self.cluster.as_ref().is_none_or(|cluster| cluster.indexer)
+    }
+
+    pub fn is_authorized(&self, authorization: Option<&str>) -> bool {
+        if self.auth_tokens.is_empty() {
+            return true;
+        }
+        match authorization.and_then(|h| h.strip_prefix("Bearer ")) {
+            Some(token) => self.auth_tokens.iter().any(|t| t == token),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_peer(url: &str) -> Config {
+        Config {
+            peers: vec![PeerConfig {
+                name: "peer".to_string(),
+                url: url.to_string(),
+                timeout_secs: 10,
+            }],
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn peers_need_http_urls() {
+        assert!(with_peer("http://192.168.1.20:8080").check_peers().is_ok());
+        assert!(with_peer("https://library.example/").check_peers().is_ok());
+        assert!(with_peer("file:///etc/passwd").check_peers().is_err());
+        assert!(with_peer("ftp://library.example").check_peers().is_err());
+        assert!(with_peer("library.example").check_peers().is_err());
+    }
+
+    #[test]
+    fn peers_are_limited() {
+        let mut config = with_peer("http://library.example");
+        config.peers = vec![config.peers[0].clone(); MAX_PEERS + 1];
+        assert!(config.check_peers().is_err());
+    }
+}
@@ -0,0 +1,222 @@
+//! Derived copies of an archive. `POST /archives/{id}/derive` rewrites a book
+//! with edited metadata (title, description, illustration, ...) so curators
+//! can repackage content under their own name; the entries themselves are
+//! copied unchanged, except those the archive's blocklist hides.
+
+use crate::AppState;
+use crate::blocklist::Blocklist;
+use crate::jobs::{self, JobHandle};
+use crate::zim_writer::ZimWriter;
+use actix_web::{HttpResponse, Responder, post, web};
+use anyhow::{Result, anyhow, bail};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use zim_rs::archive::Archive;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+#[derive(Deserialize)]
+struct DeriveRequest {
+    /// Metadata to set, e.g. `{"Title": "...", "Description": "..."}`. An
+    /// empty value removes the key.
+    #[serde(default)]
+    metadata: BTreeMap<String, String>,
+    /// Base64 square PNG replacing the archive's illustrations.
+    illustration: Option<String>,
+}
+
+/// Width of a square PNG, read from its `IHDR` chunk.
+fn png_square_size(png: &[u8]) -> Result<u32> {
+    if png.len() < 24 || !png.starts_with(PNG_SIGNATURE) || &png[12..16] != b"IHDR" {
+        bail!("Illustration must be a PNG image");
+    }
+    let width = u32::from_be_bytes(png[16..20].try_into().unwrap());
+    let height = u32::from_be_bytes(png[20..24].try_into().unwrap());
+    if width != height || width == 0 {
+        bail!("Illustration must be square, got {}x{}", width, height);
+    }
+    Ok(width)
+}
+
+/// Copies the archive's text metadata into `writer`, applying `overrides`.
+/// Illustrations are copied as well unless `replace_illustrations` is set.
+pub(crate) fn copy_metadata(
+    zim: &Archive,
+    writer: &mut ZimWriter,
+    overrides: &BTreeMap<String, String>,
+    replace_illustrations: bool,
+) -> Result<()> {
+    let mut metadata: BTreeMap<String, String> = zim
+        .get_metadata_keys()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|key| !key.starts_with("Illustration_") && key != "Counter")
+        .filter_map(|key| zim.get_metadata(&key).ok().map(|value| (key, value)))
+        .collect();
+    for (key, value) in overrides {
+        if value.is_empty() {
+            metadata.remove(key);
+        } else {
+            metadata.insert(key.clone(), value.clone());
+        }
+    }
+    for (key, value) in &metadata {
+        writer.add_metadata(key, "text/plain", value.as_bytes())?;
+    }
+    if !replace_illustrations {
+        for size in zim.get_illustration_sizes().unwrap_or_default() {
+            let Ok(item) = zim.get_illustration_item(size) else {
+                continue;
+            };
+            let blob = item
+                .get_data()
+                .map_err(|e| anyhow!("Failed to read illustration: {:?}", e))?;
+            writer.add_metadata(
+                &format!("Illustration_{}x{}@1", size, size),
+                "image/png",
+                blob.data(),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Path of the entry the main page redirects to, if the archive has one.
+pub(crate) fn main_path(zim: &Archive) -> Option<String> {
+    let entry = zim.get_main_entry().ok()?;
+    entry.get_item(true).ok().map(|item| item.get_path())
+}
+
+/// Whether the entry at `path` is hidden by `blocklist`.
+pub(crate) fn is_blocked_path(zim: &Archive, blocklist: &Blocklist, path: &str) -> bool {
+    zim.get_entry_bypath_str(path)
+        .is_ok_and(|entry| blocklist.is_blocked(&entry.get_title(), path))
+}
+
+fn derive_archive(
+    zim_path: &Path,
+    request: &DeriveRequest,
+    illustration: Option<(u32, Vec<u8>)>,
+    blocklist: &Blocklist,
+    job: &JobHandle,
+) -> Result<()> {
+    let zim = Archive::new(zim_path.to_str().unwrap())
+        .map_err(|e| anyhow!("Failed to open archive: {:?}", e))?;
+    let total = zim.get_entrycount();
+    job.set_total(total as u64);
+    job.set_phase("copying entries");
+    let mut writer = ZimWriter::new(job.output_dir())?;
+
+    for idx in 0..total {
+        if job.is_cancelled() {
+            bail!("Cancelled");
+        }
+        job.set_processed(idx as u64);
+        let entry = zim
+            .get_entry_bypath_index(idx)
+            .map_err(|e| anyhow!("Failed to read entry {}: {:?}", idx, e))?;
+        if blocklist.is_blocked(&entry.get_title(), &entry.get_path()) {
+            continue;
+        }
+        if entry.is_redirect() {
+            let target = entry
+                .get_redirect_entry()
+                .map_err(|e| anyhow!("Failed to resolve redirect {}: {:?}", entry.get_path(), e))?;
+            // Redirects are dropped along with what they lead to, through chains too.
+            let resolved = entry.get_item(true).ok().map(|item| item.get_path());
+            if blocklist.is_blocked(&target.get_title(), &target.get_path())
+                || resolved.is_some_and(|path| is_blocked_path(&zim, blocklist, &path))
+            {
+                continue;
+            }
+            writer.add_redirect(&entry.get_path(), &entry.get_title(), &target.get_path());
+            continue;
+        }
+        let item = entry
+            .get_item(false)
+            .map_err(|e| anyhow!("Failed to read {}: {:?}", entry.get_path(), e))?;
+        let blob = item
+            .get_data()
+            .map_err(|e| anyhow!("Failed to read {}: {:?}", item.get_path(), e))?;
+        writer.add_item(
+            &item.get_path(),
+            &item.get_title(),
+            &item.get_mimetype().unwrap_or_default(),
+            blob.data(),
+        )?;
+    }
+    job.set_processed(total as u64);
+
+    job.set_phase("writing archive");
+    copy_metadata(&zim, &mut writer, &request.metadata, illustration.is_some())?;
+    if let Some((size, png)) = illustration {
+        writer.add_metadata(
+            &format!("Illustration_{}x{}@1", size, size),
+            "image/png",
+            &png,
+        )?;
+    }
+    if let Some(path) = main_path(&zim).filter(|path| !is_blocked_path(&zim, blocklist, path)) {
+        writer.set_main_path(&path);
+    }
+    let file_name = zim_path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("derived.zim")
+        .to_string();
+    writer.finish(&job.output_dir().join(&file_name))?;
+    job.add_output_file(&file_name);
+    Ok(())
+}
+
+#[post("/archives/{id}/derive", wrap = "crate::load_shed::Shed::job()")]
+async fn derive(
+    path: web::Path<String>,
+    req: web::Json<DeriveRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let Some(zim_path): Option<PathBuf> = state.archive_path(&id) else {
+        return HttpResponse::NotFound().json(json!({"error": "Archive not found"}));
+    };
+    let request = req.into_inner();
+    if let Some(key) = request
+        .metadata
+        .keys()
+        .find(|key| key.is_empty() || key.contains('/') || key.starts_with("Illustration_"))
+    {
+        return HttpResponse::BadRequest()
+            .json(json!({"error": format!("Invalid metadata key: {:?}", key)}));
+    }
+    let illustration = match &request.illustration {
+        Some(encoded) => {
+            let decoded = BASE64
+                .decode(encoded)
+                .map_err(|e| anyhow!("Illustration is not valid base64: {}", e))
+                .and_then(|png| Ok((png_square_size(&png)?, png)));
+            match decoded {
+                Ok(illustration) => Some(illustration),
+                Err(e) => {
+                    return HttpResponse::BadRequest().json(json!({"error": e.to_string()}));
+                }
+            }
+        }
+        None => None,
+    };
+    let blocklist = state.blocklist_for(Some(&id));
+    let lease = state.leases.acquire(&zim_path);
+
+    match state
+        .jobs
+        .spawn("zim_derive", &jobs::jobs_dir(&state), move |job| {
+            let _lease = lease;
+            derive_archive(&zim_path, &request, illustration, &blocklist, job)
+        }) {
+        Ok(job_id) => HttpResponse::Accepted().json(json!({ "job_id": job_id })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+    }
+}
@@ -0,0 +1,58 @@
+//! `--desktop` mode: run as a local reader rather than a server. The first
+//! instance binds a free localhost port and opens the system browser on it;
+//! later instances find it through a lock file in the library directory and
+//! just open the browser again.
+
+use log::{info, warn};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+const LOCK_FILE: &str = "desktop.lock";
+/// Address of the running instance, written next to the lock.
+const URL_FILE: &str = "desktop.url";
+
+/// Takes the single-instance lock, held for as long as the returned file is.
+/// Returns `None` after pointing the browser at the instance that already
+/// holds it.
+pub fn acquire_instance_lock(dir: &Path) -> io::Result<Option<File>> {
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join(LOCK_FILE))?;
+    match lock.try_lock() {
+        Ok(()) => Ok(Some(lock)),
+        Err(TryLockError::WouldBlock) => {
+            let url = fs::read_to_string(dir.join(URL_FILE))?;
+            info!("Zim-viewer is already running at {}", url.trim());
+            open_browser(url.trim())?;
+            Ok(None)
+        }
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+/// Records where this instance listens and opens the browser there.
+pub fn announce(dir: &Path, url: &str) -> io::Result<()> {
+    fs::write(dir.join(URL_FILE), url)?;
+    if let Err(e) = open_browser(url) {
+        warn!("Failed to open a browser, visit {} instead: {}", url, e);
+    }
+    Ok(())
+}
+
+fn open_browser(url: &str) -> io::Result<()> {
+    #[cfg(target_os = "windows")]
+    let mut command = {
+        let mut command = Command::new("cmd");
+        command.args(["/C", "start", ""]);
+        command
+    };
+    #[cfg(target_os = "macos")]
+    let mut command = Command::new("open");
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    let mut command = Command::new("xdg-open");
+    command.arg(url).spawn().map(|_| ())
+}
@@ -0,0 +1,239 @@
+//! Server-side fetching of archives as background jobs. A finished download
+//! is moved into the library directory and becomes available right away.
+//! Downloads can be listed with their speed and ETA, paused, resumed and
+//! cancelled.
+
+use crate::AppState;
+use crate::cluster::{self, Change};
+use crate::jobs::JobInfo;
+use crate::jobs::{self, JobHandle};
+use actix_web::{HttpRequest, HttpResponse, Responder, get, post, web};
+use anyhow::{Result, anyhow, bail};
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::hash_map::Entry;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+const DOWNLOAD_JOB: &str = "download";
+
+#[derive(Serialize)]
+struct DownloadInfo {
+    #[serde(flatten)]
+    job: JobInfo,
+    bytes_per_sec: u64,
+    /// Estimated seconds left, unknown until the size and a speed are known.
+    eta_secs: Option<u64>,
+}
+
+impl From<JobInfo> for DownloadInfo {
+    fn from(job: JobInfo) -> Self {
+        let remaining = job.total.saturating_sub(job.processed);
+        let eta_secs =
+            (job.total > 0 && job.rate >= 1.0).then(|| (remaining as f64 / job.rate) as u64);
+        DownloadInfo {
+            bytes_per_sec: job.rate as u64,
+            eta_secs,
+            job,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DownloadRequest {
+    /// Magnet link or `.torrent` URL.
+    url: String,
+}
+
+/// Moves `file` to `destination`, refusing to replace anything already there.
+/// `on_copy` is called when the file has to be copied across filesystems.
+fn move_new(file: &Path, destination: &Path, on_copy: impl FnOnce()) -> Result<()> {
+    let exists = || anyhow!("{} is already in the library", destination.display());
+    match fs::hard_link(file, destination) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Err(exists()),
+        Err(_) => {
+            // Different filesystem: fall back to copying.
+            on_copy();
+            let mut target = match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(destination)
+            {
+                Ok(target) => target,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Err(exists()),
+                Err(e) => return Err(e.into()),
+            };
+            if let Err(e) = io::copy(&mut File::open(file)?, &mut target) {
+                let _ = fs::remove_file(destination);
+                return Err(e.into());
+            }
+        }
+    }
+    fs::remove_file(file)?;
+    Ok(())
+}
+
+/// Moves downloaded archives into the library and registers them under their
+/// file stem, the same id a library scan would give them. A stem already
+/// registered fails the job rather than replacing that archive.
+fn add_to_library(
+    job: &JobHandle,
+    files: Vec<PathBuf>,
+    library_dir: &Path,
+    state: &AppState,
+) -> Result<()> {
+    if files.is_empty() {
+        bail!("The download did not contain any .zim file");
+    }
+    for file in files {
+        let Some(name) = file.file_name() else {
+            continue;
+        };
+        let destination = library_dir.join(name);
+        let id = destination
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or_default()
+            .to_string();
+        let taken = || anyhow!("Archive id {} is already in use", id);
+        if state.file_cache.lock().unwrap().contains_key(&id) {
+            return Err(taken());
+        }
+        move_new(&file, &destination, || job.set_phase("copying"))?;
+        match state.file_cache.lock().unwrap().entry(id.clone()) {
+            Entry::Vacant(slot) => {
+                slot.insert(destination.clone());
+            }
+            Entry::Occupied(_) => {
+                // Registered elsewhere while the file was being moved.
+                let _ = fs::remove_file(&destination);
+                return Err(taken());
+            }
+        }
+        info!("Downloaded archive {} to {}", id, destination.display());
+        cluster::publish(state, vec![Change::Added(id, destination)]);
+    }
+    Ok(())
+}
+
+#[cfg(feature = "torrent")]
+fn fetch(job: &JobHandle, url: &str, library_dir: &Path) -> Result<Vec<PathBuf>> {
+    use crate::torrent;
+
+    if !torrent::is_torrent_source(url) {
+        bail!("Only magnet links and .torrent URLs are supported");
+    }
+    torrent::download(job, url, &library_dir.join(torrent::TORRENT_DIR))
+}
+
+#[cfg(not(feature = "torrent"))]
+fn fetch(_job: &JobHandle, _url: &str, _library_dir: &Path) -> Result<Vec<PathBuf>> {
+    bail!("Torrent downloads need a build with the `torrent` feature")
+}
+
+#[post("/downloads", wrap = "crate::load_shed::Shed::job()")]
+async fn start_download(
+    req: HttpRequest,
+    body: web::Json<DownloadRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    if !state.is_admin(&req) {
+        return HttpResponse::Unauthorized().json(json!({"error": "Invalid or missing token"}));
+    }
+    if !cfg!(feature = "torrent") {
+        return HttpResponse::NotImplemented().json(json!({
+            "error": "Torrent downloads need a build with the `torrent` feature"
+        }));
+    }
+    let url = body.url.clone();
+    let library_dir = state.library_dir();
+    let app_state = state.get_ref().clone();
+
+    match state
+        .jobs
+        .spawn(DOWNLOAD_JOB, &jobs::jobs_dir(&state), move |job| {
+            let files = fetch(job, &url, &library_dir)?;
+            add_to_library(job, files, &library_dir, &app_state)
+        }) {
+        Ok(job_id) => HttpResponse::Accepted().json(json!({ "job_id": job_id })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+    }
+}
+
+#[get("/downloads")]
+async fn list_downloads(state: web::Data<AppState>) -> impl Responder {
+    let downloads: Vec<DownloadInfo> = state
+        .jobs
+        .list()
+        .into_iter()
+        .filter(|job| job.kind == DOWNLOAD_JOB)
+        .map(DownloadInfo::from)
+        .collect();
+    HttpResponse::Ok().json(downloads)
+}
+
+#[post("/downloads/{id}/{action}")]
+async fn control_download(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    if !state.is_admin(&req) {
+        return HttpResponse::Unauthorized().json(json!({"error": "Invalid or missing token"}));
+    }
+    let (id, action) = path.into_inner();
+    if state
+        .jobs
+        .get(&id)
+        .is_none_or(|job| job.kind != DOWNLOAD_JOB)
+    {
+        return HttpResponse::NotFound().json(json!({"error": "Download not found"}));
+    }
+    let job = match action.as_str() {
+        "pause" => state.jobs.pause(&id),
+        "resume" => state.jobs.resume(&id),
+        "cancel" => state.jobs.cancel(&id),
+        _ => return HttpResponse::NotFound().json(json!({"error": "Unknown action"})),
+    };
+    match job {
+        Some(job) => HttpResponse::Ok().json(DownloadInfo::from(job)),
+        None => HttpResponse::NotFound().json(json!({"error": "Download not found"})),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("downloads-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn move_new_moves_the_file() {
+        let dir = temp_dir();
+        let (file, destination) = (dir.join("new.zim"), dir.join("library.zim"));
+        fs::write(&file, b"archive").unwrap();
+        move_new(&file, &destination, || {}).unwrap();
+        assert!(!file.exists());
+        assert_eq!(fs::read(&destination).unwrap(), b"archive");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn move_new_keeps_an_existing_archive() {
+        let dir = temp_dir();
+        let (file, destination) = (dir.join("new.zim"), dir.join("library.zim"));
+        fs::write(&file, b"new").unwrap();
+        fs::write(&destination, b"old").unwrap();
+        assert!(move_new(&file, &destination, || {}).is_err());
+        assert_eq!(fs::read(&destination).unwrap(), b"old");
+        assert!(file.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,212 @@
+//! Minimal EPUB 3 writer for exporting collections. Articles are reduced to
+//! well-formed XHTML: text structure (headings, paragraphs, lists, tables,
+//! emphasis) is kept, while scripts, media and links into the archive are
+//! dropped since the book cannot resolve them.
+
+use anyhow::Result;
+use chrono::Utc;
+use html_escape::{encode_double_quoted_attribute, encode_text};
+use scraper::{ElementRef, Html, Selector};
+use std::io::{Cursor, Write};
+use zip::CompressionMethod;
+use zip::write::{SimpleFileOptions, ZipWriter};
+
+/// Elements copied into chapters as they are.
+const KEPT_ELEMENTS: &[&str] = &[
+    "p",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "ul",
+    "ol",
+    "li",
+    "dl",
+    "dt",
+    "dd",
+    "blockquote",
+    "pre",
+    "code",
+    "em",
+    "strong",
+    "i",
+    "b",
+    "u",
+    "s",
+    "sub",
+    "sup",
+    "small",
+    "table",
+    "caption",
+    "thead",
+    "tbody",
+    "tfoot",
+    "tr",
+    "th",
+    "td",
+    "figure",
+    "figcaption",
+    "section",
+    "div",
+    "span",
+];
+/// Elements dropped together with their content.
+const DROPPED_ELEMENTS: &[&str] = &[
+    "script", "style", "noscript", "template", "iframe", "object", "embed", "svg", "math", "video",
+    "audio", "img", "picture", "button", "input", "select", "textarea", "form",
+];
+
+pub struct Chapter {
+    pub title: String,
+    pub html: String,
+}
+
+/// Text that is valid inside XML: markup escaped, control characters removed.
+fn xml_text(text: &str) -> String {
+    let text: String = text
+        .chars()
+        .filter(|c| !c.is_control() || matches!(c, '\t' | '\n' | '\r'))
+        .collect();
+    encode_text(&text).into_owned()
+}
+
+fn write_children(element: ElementRef, out: &mut String) {
+    for child in element.children() {
+        if let Some(text) = child.value().as_text() {
+            out.push_str(&xml_text(text));
+        } else if let Some(child) = ElementRef::wrap(child) {
+            write_element(child, out);
+        }
+    }
+}
+
+fn write_element(element: ElementRef, out: &mut String) {
+    let name = element.value().name();
+    if DROPPED_ELEMENTS.contains(&name) {
+        return;
+    }
+    if matches!(name, "br" | "hr") {
+        out.push_str(&format!("<{}/>", name));
+        return;
+    }
+    let kept = KEPT_ELEMENTS.contains(&name);
+    if kept {
+        out.push('<');
+        out.push_str(name);
+        for attr in ["colspan", "rowspan"] {
+            if let Some(value) = element.value().attr(attr) {
+                out.push_str(&format!(
+                    " {}=\"{}\"",
+                    attr,
+                    encode_double_quoted_attribute(value)
+                ));
+            }
+        }
+        out.push('>');
+    }
+    write_children(element, out);
+    if kept {
+        out.push_str(&format!("</{}>", name));
+    }
+}
+
+/// The article body as an XHTML chapter document.
+fn chapter_xhtml(chapter: &Chapter) -> String {
+    let document = Html::parse_document(&chapter.html);
+    let mut body = String::new();
+    if let Some(root) = document.select(&Selector::parse("body").unwrap()).next() {
+        write_children(root, &mut body);
+    }
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <!DOCTYPE html>\n\
+         <html xmlns=\"http://www.w3.org/1999/xhtml\">\n\
+         <head><title>{title}</title></head>\n\
+         <body><h1>{title}</h1>\n{body}\n</body>\n</html>\n",
+        title = xml_text(&chapter.title),
+        body = body
+    )
+}
+
+/// Builds an EPUB with one chapter per article, in order.
+pub fn build(id: &str, title: &str, description: &str, chapters: &[Chapter]) -> Result<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    let deflated = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    // The mimetype must come first, uncompressed.
+    zip.start_file("mimetype", stored)?;
+    zip.write_all(b"application/epub+zip")?;
+    zip.start_file("META-INF/container.xml", deflated)?;
+    zip.write_all(
+        b"<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+          <container version=\"1.0\" xmlns=\"urn:oasis:names:tc:opendocument:xmlns:container\">\n\
+          <rootfiles><rootfile full-path=\"OEBPS/content.opf\" media-type=\"application/oebps-package+xml\"/></rootfiles>\n\
+          </container>\n",
+    )?;
+
+    let mut manifest = String::new();
+    let mut spine = String::new();
+    let mut nav = String::new();
+    for (idx, chapter) in chapters.iter().enumerate() {
+        let file = format!("chapter-{}.xhtml", idx + 1);
+        zip.start_file(format!("OEBPS/{}", file), deflated)?;
+        zip.write_all(chapter_xhtml(chapter).as_bytes())?;
+        manifest.push_str(&format!(
+            "<item id=\"c{}\" href=\"{}\" media-type=\"application/xhtml+xml\"/>\n",
+            idx + 1,
+            file
+        ));
+        spine.push_str(&format!("<itemref idref=\"c{}\"/>\n", idx + 1));
+        nav.push_str(&format!(
+            "<li><a href=\"{}\">{}</a></li>\n",
+            file,
+            xml_text(&chapter.title)
+        ));
+    }
+
+    zip.start_file("OEBPS/nav.xhtml", deflated)?;
+    zip.write_all(
+        format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+             <!DOCTYPE html>\n\
+             <html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\">\n\
+             <head><title>{title}</title></head>\n\
+             <body><nav epub:type=\"toc\"><h1>{title}</h1><ol>\n{nav}</ol></nav></body>\n</html>\n",
+            title = xml_text(title),
+            nav = nav
+        )
+        .as_bytes(),
+    )?;
+
+    zip.start_file("OEBPS/content.opf", deflated)?;
+    zip.write_all(
+        format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+             <package xmlns=\"http://www.idpf.org/2007/opf\" version=\"3.0\" unique-identifier=\"uid\">\n\
+             <metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n\
+             <dc:identifier id=\"uid\">urn:uuid:{id}</dc:identifier>\n\
+             <dc:title>{title}</dc:title>\n\
+             <dc:description>{description}</dc:description>\n\
+             <dc:language>und</dc:language>\n\
+             <meta property=\"dcterms:modified\">{modified}</meta>\n\
+             </metadata>\n\
+             <manifest>\n\
+             <item id=\"nav\" href=\"nav.xhtml\" media-type=\"application/xhtml+xml\" properties=\"nav\"/>\n\
+             {manifest}</manifest>\n\
+             <spine>\n{spine}</spine>\n\
+             </package>\n",
+            id = xml_text(id),
+            title = xml_text(title),
+            description = xml_text(description),
+            modified = Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+            manifest = manifest,
+            spine = spine
+        )
+        .as_bytes(),
+    )?;
+
+    Ok(zip.finish()?.into_inner())
+}
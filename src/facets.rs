@@ -0,0 +1,67 @@
+//! Facet counts returned alongside search hits, so the frontend can offer
+//! refine-by-type controls. Counting every match would mean loading every
+//! result, so mimetype and namespace counts come from the top
+//! `SAMPLE_SIZE` hits; per-archive counts are the searches' estimates.
+
+use crate::blocklist::Blocklist;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use zim_rs::entry::Entry;
+
+/// Hits inspected for the mimetype and namespace counts.
+pub const SAMPLE_SIZE: u32 = 1000;
+
+#[derive(Serialize, Default, Clone)]
+pub struct Facets {
+    pub mimetype: BTreeMap<String, u64>,
+    pub namespace: BTreeMap<String, u64>,
+    /// Estimated matches per archive, in multi-archive searches.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub archive: BTreeMap<String, u64>,
+    /// Number of hits the mimetype and namespace counts are based on.
+    pub sampled: u64,
+}
+
+/// Namespace of an entry path. Archives using the old namespace scheme
+/// report paths as `A/Foo`; in newer ones every article is in `C`.
+fn namespace(path: &str) -> &str {
+    match path.split_once('/') {
+        Some((ns, _)) if ns.len() == 1 && ns.chars().all(|c| c.is_ascii_alphabetic()) => ns,
+        _ => "C",
+    }
+}
+
+impl Facets {
+    pub fn record(&mut self, entry: &Entry, blocklist: &Blocklist) {
+        let Ok(item) = entry.get_item(true) else {
+            return;
+        };
+        let path = item.get_path();
+        if blocklist.is_blocked(&item.get_title(), &path) {
+            return;
+        }
+        let mimetype = item.get_mimetype().unwrap_or_default();
+        // Drop parameters so `text/html` and `text/html; raw=true` count together.
+        let mimetype = mimetype.split(';').next().unwrap_or("").trim().to_string();
+        *self.mimetype.entry(mimetype).or_default() += 1;
+        *self
+            .namespace
+            .entry(namespace(&path).to_string())
+            .or_default() += 1;
+        self.sampled += 1;
+    }
+
+    /// Adds another archive's counts.
+    pub fn merge(&mut self, other: Facets) {
+        for (mimetype, count) in other.mimetype {
+            *self.mimetype.entry(mimetype).or_default() += count;
+        }
+        for (namespace, count) in other.namespace {
+            *self.namespace.entry(namespace).or_default() += count;
+        }
+        for (archive, count) in other.archive {
+            *self.archive.entry(archive).or_default() += count;
+        }
+        self.sampled += other.sampled;
+    }
+}
@@ -0,0 +1,97 @@
+//! Browser tab icons taken from the archive's illustration (the 48x48 PNG
+//! every ZIM file is expected to carry). `/favicon.ico` follows the currently
+//! opened archive; `/archives/{id}/favicon.png` serves a specific one.
+
+use crate::AppState;
+use actix_web::{HttpResponse, Responder, get, web};
+use anyhow::{Result, anyhow};
+use std::path::{Path, PathBuf};
+use zim_rs::archive::Archive;
+
+const PREFERRED_SIZE: u32 = 48;
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// The archive's illustration as `(data, mimetype)`, preferring 48x48 and
+/// falling back to the smallest size it has.
+fn illustration(zim_path: &Path) -> Result<Option<(Vec<u8>, String)>> {
+    let zim = Archive::new(zim_path.to_str().unwrap())
+        .map_err(|e| anyhow!("Failed to open archive: {:?}", e))?;
+    let mut sizes = zim.get_illustration_sizes().unwrap_or_default();
+    sizes.sort();
+    sizes.retain(|&s| s != PREFERRED_SIZE);
+    sizes.insert(0, PREFERRED_SIZE);
+
+    for size in sizes {
+        let Ok(item) = zim.get_illustration_item(size) else {
+            continue;
+        };
+        let blob = item
+            .get_data()
+            .map_err(|e| anyhow!("Failed to read illustration: {:?}", e))?;
+        let mimetype = item
+            .get_mimetype()
+            .unwrap_or_else(|_| "image/png".to_string());
+        return Ok(Some((blob.data().to_vec(), mimetype)));
+    }
+    Ok(None)
+}
+
+/// Wraps a PNG in a single-image ICO container, which may embed PNG data
+/// as-is. Returns `None` if `png` is not a PNG.
+fn png_to_ico(png: &[u8]) -> Option<Vec<u8>> {
+    if png.len() < 24 || !png.starts_with(PNG_SIGNATURE) {
+        return None;
+    }
+    // Width and height are the first fields of the IHDR chunk; ICO stores
+    // them in one byte each, with 0 meaning 256.
+    let width = u32::from_be_bytes(png[16..20].try_into().ok()?);
+    let height = u32::from_be_bytes(png[20..24].try_into().ok()?);
+    let dimension = |d: u32| if d >= 256 { 0 } else { d as u8 };
+
+    let mut ico = Vec::with_capacity(22 + png.len());
+    ico.extend_from_slice(&[0, 0, 1, 0, 1, 0]); // reserved, type 1 (icon), 1 image
+    ico.push(dimension(width));
+    ico.push(dimension(height));
+    ico.extend_from_slice(&[0, 0]); // no palette, reserved
+    ico.extend_from_slice(&1u16.to_le_bytes()); // color planes
+    ico.extend_from_slice(&32u16.to_le_bytes()); // bits per pixel
+    ico.extend_from_slice(&(png.len() as u32).to_le_bytes());
+    ico.extend_from_slice(&22u32.to_le_bytes()); // offset of the image data
+    ico.extend_from_slice(png);
+    Some(ico)
+}
+
+async fn illustration_response(zim_path: PathBuf, as_ico: bool) -> HttpResponse {
+    match web::block(move || illustration(&zim_path)).await {
+        Ok(Ok(Some((data, mimetype)))) => {
+            let (data, mimetype) = match as_ico.then(|| png_to_ico(&data)).flatten() {
+                Some(ico) => (ico, "image/x-icon".to_string()),
+                None => (data, mimetype),
+            };
+            HttpResponse::Ok()
+                .content_type(mimetype)
+                .insert_header(("Cache-Control", "public, max-age=3600"))
+                .body(data)
+        }
+        Ok(Ok(None)) => HttpResponse::NotFound().body("Archive has no illustration"),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[get("/favicon.ico")]
+async fn favicon(state: web::Data<AppState>) -> impl Responder {
+    let zim_path = state.current_zim_path.lock().unwrap().clone();
+    match zim_path {
+        Some(zim_path) => illustration_response(zim_path, true).await,
+        None => HttpResponse::NotFound().body("No ZIM file loaded"),
+    }
+}
+
+#[get("/archives/{id}/favicon.png")]
+async fn archive_favicon(path: web::Path<String>, state: web::Data<AppState>) -> impl Responder {
+    match state.archive_path(&path.into_inner()) {
+        Some(zim_path) => illustration_response(zim_path, false).await,
+        None => HttpResponse::NotFound().body("Archive not found"),
+    }
+}
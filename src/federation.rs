@@ -0,0 +1,290 @@
+//! Search fanned out to other instances (`peers` in the config), for
+//! households running separate servers for different collections. Peers are
+//! asked through the kiwix-serve search contract (`/search?format=xml`),
+//! which both kiwix-serve and this server answer, while the local library is
+//! searched directly. Results are interleaved by rank so no single source
+//! crowds out the others, each labelled with where it came from.
+
+use crate::AppState;
+use crate::config::PeerConfig;
+use crate::kiwix;
+use crate::search_queue;
+use actix_web::{HttpRequest, HttpResponse, Responder, get, web};
+use anyhow::{Result, anyhow};
+use futures_util::future::join_all;
+use quick_xml::events::Event;
+use quick_xml::reader::Reader;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::time::{Duration, Instant};
+
+/// Label of this server's own results.
+const LOCAL_SOURCE: &str = "local";
+const DEFAULT_PAGE_LENGTH: u32 = 10;
+const MAX_PAGE_LENGTH: u32 = 50;
+/// Upper bound on the RSS document read from a peer.
+const MAX_RESPONSE_BYTES: usize = 8 * 1024 * 1024;
+
+#[derive(Deserialize)]
+struct FederatedQuery {
+    pattern: String,
+    /// Results asked of every source.
+    #[serde(rename = "pageLength")]
+    page_length: Option<u32>,
+    /// Comma-separated source names to query; every source by default.
+    sources: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Hit {
+    source: String,
+    title: String,
+    /// Relative for local results, absolute for a peer's.
+    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    book: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    snippet: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SourceStatus {
+    name: String,
+    total: u64,
+    took_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+struct SourceResults {
+    total: u64,
+    hits: Vec<Hit>,
+}
+
+/// Reads the hits and total out of a kiwix-serve search RSS feed.
+fn parse_rss(xml: &str, peer: &PeerConfig) -> Result<SourceResults> {
+    let mut reader = Reader::from_str(xml);
+    let base = peer.url.trim_end_matches('/');
+    let mut path: Vec<Vec<u8>> = Vec::new();
+    let mut total = 0;
+    let mut hits = Vec::new();
+    let mut current: Option<Hit> = None;
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => {
+                if e.name().as_ref() == b"item" {
+                    current = Some(Hit {
+                        source: peer.name.clone(),
+                        title: String::new(),
+                        url: String::new(),
+                        book: None,
+                        snippet: None,
+                    });
+                }
+                path.push(e.name().as_ref().to_vec());
+            }
+            Event::End(e) => {
+                if e.name().as_ref() == b"item" {
+                    hits.extend(current.take().filter(|hit| !hit.url.is_empty()));
+                }
+                path.pop();
+            }
+            Event::Text(e) => {
+                let text = e.unescape()?.trim().to_string();
+                if text.is_empty() {
+                    continue;
+                }
+                let names: Vec<&[u8]> = path.iter().map(Vec::as_slice).collect();
+                match (names.as_slice(), current.as_mut()) {
+                    ([.., b"opensearch:totalResults"], _) => total = text.parse().unwrap_or(0),
+                    ([.., b"item", b"title"], Some(hit)) => hit.title = text,
+                    ([.., b"item", b"link"], Some(hit)) => {
+                        hit.url = if text.starts_with('/') {
+                            format!("{}{}", base, text)
+                        } else {
+                            text
+                        };
+                    }
+                    ([.., b"item", b"description"], Some(hit)) => hit.snippet = Some(text),
+                    ([.., b"book", b"title"], Some(hit)) => hit.book = Some(text),
+                    _ => {}
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(SourceResults { total, hits })
+}
+
+async fn search_peer(peer: &PeerConfig, pattern: &str, page_length: u32) -> Result<SourceResults> {
+    let url = format!(
+        "{}/search?pattern={}&pageLength={}&format=xml",
+        peer.url.trim_end_matches('/'),
+        urlencoding::encode(pattern),
+        page_length
+    );
+    let client = awc::Client::builder()
+        .timeout(Duration::from_secs(peer.timeout_secs))
+        .finish();
+    let mut response = client
+        .get(&url)
+        .send()
+        .await
+        .map_err(|e| anyhow!("Request to {} failed: {}", peer.name, e))?;
+    if !response.status().is_success() {
+        return Err(anyhow!("{} answered {}", peer.name, response.status()));
+    }
+    let body = response
+        .body()
+        .limit(MAX_RESPONSE_BYTES)
+        .await
+        .map_err(|e| anyhow!("Failed to read the answer of {}: {}", peer.name, e))?;
+    let mut results = parse_rss(&String::from_utf8_lossy(&body), peer)?;
+    results.hits.truncate(page_length as usize);
+    Ok(results)
+}
+
+async fn search_local(
+    req: &HttpRequest,
+    state: &web::Data<AppState>,
+    pattern: &str,
+    page_length: u32,
+) -> Result<SourceResults> {
+    let archives = kiwix::library_archives(state);
+    let blocklists = kiwix::library_blocklists(state, &archives);
+    let client = search_queue::client_key(req, state);
+    let permit = state
+        .search_queue
+        .acquire(&client, state.search_limits())
+        .await
+        .map_err(|e| anyhow!("{}", e))?;
+    let (leases, query) = (state.leases.clone(), pattern.to_string());
+    let book_cache = state.kiwix_books.clone();
+    let result = web::block(move || {
+        let books = kiwix::resolve_books(&book_cache, archives, &[]);
+        let _leases: Vec<_> = books.iter().map(|b| leases.acquire(&b.path)).collect();
+        kiwix::search_books(&books, &query, 0, page_length, &blocklists, false)
+    })
+    .await;
+    drop(permit);
+    let results = result.map_err(|e| anyhow!("{}", e))??;
+    let hits = results
+        .hits
+        .into_iter()
+        .map(|(book_name, book_title, hit)| Hit {
+            source: LOCAL_SOURCE.to_string(),
+            title: hit.title,
+            url: kiwix::content_link(&book_name, &hit.path),
+            book: Some(book_title),
+            snippet: None,
+        })
+        .collect();
+    Ok(SourceResults {
+        total: results.total,
+        hits,
+    })
+}
+
+/// Takes the first hit of every source, then every second one, and so on.
+fn interleave(sources: Vec<Vec<Hit>>) -> Vec<Hit> {
+    let mut iters: Vec<_> = sources.into_iter().map(Vec::into_iter).collect();
+    let mut merged = Vec::new();
+    loop {
+        let before = merged.len();
+        merged.extend(iters.iter_mut().filter_map(Iterator::next));
+        if merged.len() == before {
+            return merged;
+        }
+    }
+}
+
+async fn timed<F: Future<Output = Result<SourceResults>>>(
+    future: F,
+) -> (u64, Result<SourceResults>) {
+    let started = Instant::now();
+    let result = future.await;
+    (started.elapsed().as_millis() as u64, result)
+}
+
+#[get("/federated/search", wrap = "crate::load_shed::Shed::heavy()")]
+async fn federated_search(
+    req: HttpRequest,
+    query: web::Query<FederatedQuery>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let pattern = query.pattern.trim().to_string();
+    if pattern.is_empty() {
+        return HttpResponse::BadRequest().json(json!({"error": "No query provided"}));
+    }
+    let page_length = query
+        .page_length
+        .unwrap_or(DEFAULT_PAGE_LENGTH)
+        .clamp(1, MAX_PAGE_LENGTH);
+    let wanted: Option<Vec<&str>> = query
+        .sources
+        .as_deref()
+        .map(|sources| sources.split(',').map(str::trim).collect());
+    let is_wanted = |name: &str| wanted.as_ref().is_none_or(|w| w.contains(&name));
+
+    let peers: Vec<PeerConfig> = state
+        .config
+        .read()
+        .unwrap()
+        .peers
+        .iter()
+        .filter(|peer| is_wanted(&peer.name))
+        .cloned()
+        .collect();
+    let with_local = is_wanted(LOCAL_SOURCE);
+    if peers.is_empty() && !with_local {
+        return HttpResponse::BadRequest().json(json!({"error": "No such source"}));
+    }
+
+    let local = async {
+        if with_local {
+            Some(timed(search_local(&req, &state, &pattern, page_length)).await)
+        } else {
+            None
+        }
+    };
+    let remote = join_all(
+        peers
+            .iter()
+            .map(|peer| timed(search_peer(peer, &pattern, page_length))),
+    );
+    let (local, remote) = futures_util::join!(local, remote);
+
+    let names = with_local
+        .then(|| LOCAL_SOURCE.to_string())
+        .into_iter()
+        .chain(peers.iter().map(|peer| peer.name.clone()));
+    let mut statuses = Vec::new();
+    let mut hits = Vec::new();
+    for (name, (took_ms, result)) in names.zip(local.into_iter().chain(remote)) {
+        match result {
+            Ok(results) => {
+                statuses.push(SourceStatus {
+                    name,
+                    total: results.total,
+                    took_ms,
+                    error: None,
+                });
+                hits.push(results.hits);
+            }
+            Err(e) => statuses.push(SourceStatus {
+                name,
+                total: 0,
+                took_ms,
+                error: Some(e.to_string()),
+            }),
+        }
+    }
+
+    HttpResponse::Ok().json(json!({
+        "pattern": pattern,
+        "total": statuses.iter().map(|s| s.total).sum::<u64>(),
+        "sources": statuses,
+        "results": interleave(hits),
+    }))
+}
@@ -0,0 +1,261 @@
+//! `mount <file> <dir>` subcommand: exposes one archive as a read-only FUSE
+//! filesystem using the same path layout as the WebDAV share.
+
+use crate::archive_tree::{self, Node};
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyOpen, Request,
+};
+use log::{error, info};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use zim_rs::archive::Archive;
+
+const TTL: Duration = Duration::from_secs(60);
+const ROOT_INO: u64 = 1;
+const BLOCK_SIZE: u32 = 4096;
+
+struct ZimFs {
+    zim: Archive,
+    mtime: SystemTime,
+    uid: u32,
+    gid: u32,
+    /// Archive path of every inode handed out so far, indexed by `ino - 1`.
+    paths: Vec<String>,
+    inodes: HashMap<String, u64>,
+    /// Content of the file read last, so sequential reads don't re-fetch the blob.
+    last_read: Option<(u64, Vec<u8>)>,
+    /// Children of every open directory handle, listed once by `opendir` and
+    /// handed out page by page by `readdir`.
+    listings: HashMap<u64, Vec<(String, FileType)>>,
+    next_fh: u64,
+}
+
+impl ZimFs {
+    fn new(zim: Archive, mtime: SystemTime) -> ZimFs {
+        let mut inodes = HashMap::new();
+        inodes.insert(String::new(), ROOT_INO);
+        ZimFs {
+            zim,
+            mtime,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            paths: vec![String::new()],
+            inodes,
+            last_read: None,
+            listings: HashMap::new(),
+            next_fh: 1,
+        }
+    }
+
+    fn path_of(&self, ino: u64) -> Option<&str> {
+        self.paths.get((ino - 1) as usize).map(|s| s.as_str())
+    }
+
+    fn inode_for(&mut self, path: String) -> u64 {
+        if let Some(ino) = self.inodes.get(&path) {
+            return *ino;
+        }
+        self.paths.push(path.clone());
+        let ino = self.paths.len() as u64;
+        self.inodes.insert(path, ino);
+        ino
+    }
+
+    fn child_path(&self, parent: u64, name: &str) -> Option<String> {
+        let parent_path = self.path_of(parent)?;
+        Some(if parent_path.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}/{}", parent_path, name)
+        })
+    }
+
+    fn attr(&self, ino: u64, node: &Node) -> FileAttr {
+        let (kind, size, perm, nlink) = match node {
+            Node::Dir => (FileType::Directory, 0, 0o555, 2),
+            Node::File { size, .. } => (FileType::RegularFile, *size, 0o444, 1),
+        };
+        FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(BLOCK_SIZE as u64),
+            atime: self.mtime,
+            mtime: self.mtime,
+            ctime: self.mtime,
+            crtime: self.mtime,
+            kind,
+            perm,
+            nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: BLOCK_SIZE,
+            flags: 0,
+        }
+    }
+}
+
+impl Filesystem for ZimFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let Some(path) = name.to_str().and_then(|n| self.child_path(parent, n)) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        match archive_tree::stat(&self.zim, &path) {
+            Some(node) => {
+                let ino = self.inode_for(path);
+                reply.entry(&TTL, &self.attr(ino, &node), 0);
+            }
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        let node = self
+            .path_of(ino)
+            .and_then(|path| archive_tree::stat(&self.zim, path));
+        match node {
+            Some(node) => reply.attr(&TTL, &self.attr(ino, &node)),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        if self
+            .last_read
+            .as_ref()
+            .is_none_or(|(cached, _)| *cached != ino)
+        {
+            let Some(path) = self.path_of(ino) else {
+                reply.error(libc::ENOENT);
+                return;
+            };
+            match archive_tree::read_file(&self.zim, path) {
+                Ok(data) => self.last_read = Some((ino, data)),
+                Err(e) => {
+                    error!("Failed to read {}: {:?}", path, e);
+                    reply.error(libc::EIO);
+                    return;
+                }
+            }
+        }
+
+        let data = &self.last_read.as_ref().unwrap().1;
+        let start = (offset.max(0) as usize).min(data.len());
+        let end = (start + size as usize).min(data.len());
+        reply.data(&data[start..end]);
+    }
+
+    fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        let Some(path) = self.path_of(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        let children = match archive_tree::list_dir(&self.zim, path) {
+            Ok(children) => children,
+            Err(_) => {
+                reply.error(libc::ENOTDIR);
+                return;
+            }
+        };
+        let listing = children
+            .into_iter()
+            .map(|child| {
+                let kind = match child.node {
+                    Node::Dir => FileType::Directory,
+                    Node::File { .. } => FileType::RegularFile,
+                };
+                (child.name, kind)
+            })
+            .collect();
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.listings.insert(fh, listing);
+        reply.opened(fh, 0);
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let Some(listing) = self.listings.remove(&fh) else {
+            reply.error(libc::EBADF);
+            return;
+        };
+        // Offsets 1 and 2 are `.` and `..`, then one per child.
+        let offset = offset.max(0) as usize;
+        for i in offset..2 + listing.len() {
+            let (child_ino, kind, name) = match i {
+                0 => (ino, FileType::Directory, "."),
+                1 => (ino, FileType::Directory, ".."),
+                _ => {
+                    let (name, kind) = &listing[i - 2];
+                    let child_ino = self.inode_for(self.child_path(ino, name).unwrap());
+                    (child_ino, *kind, name.as_str())
+                }
+            };
+            if reply.add(child_ino, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        self.listings.insert(fh, listing);
+        reply.ok();
+    }
+
+    fn releasedir(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        reply: ReplyEmpty,
+    ) {
+        self.listings.remove(&fh);
+        reply.ok();
+    }
+}
+
+/// Mounts `zim_file` read-only at `mountpoint` and blocks until it is unmounted.
+pub fn mount(zim_file: &Path, mountpoint: &Path) -> io::Result<()> {
+    let path_str = zim_file
+        .to_str()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid ZIM file path"))?;
+    let zim = Archive::new(path_str).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Failed to open archive: {:?}", e),
+        )
+    })?;
+    let mtime = fs::metadata(zim_file)?.modified()?;
+
+    info!(
+        "Mounting {} at {} (read-only)",
+        zim_file.display(),
+        mountpoint.display()
+    );
+    let options = [
+        MountOption::RO,
+        MountOption::FSName("zim-viewer".to_string()),
+        MountOption::Subtype("zim".to_string()),
+    ];
+    fuser::mount2(ZimFs::new(zim, mtime), mountpoint, &options)
+}
@@ -0,0 +1,203 @@
+//! "Grep mode": scans article text for a regular expression, for archives
+//! (API docs, code references) where token-based full-text search misses
+//! exact identifiers or punctuation. Every scan is bounded in the number of
+//! entries, matches and time; a truncated scan reports where to continue.
+
+use crate::AppState;
+use crate::article_html;
+use crate::blocklist::Blocklist;
+use actix_web::{HttpResponse, Responder, post, web};
+use anyhow::{Result, anyhow};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use zim_rs::archive::Archive;
+
+const MAX_ENTRIES_LIMIT: u32 = 100_000;
+const MAX_MATCHES_LIMIT: usize = 1_000;
+const TIME_LIMIT_MS_LIMIT: u64 = 30_000;
+/// Characters kept on each side of a match in the returned line.
+const CONTEXT_CHARS: usize = 120;
+/// Compiled-regex size cap, to refuse pathological patterns.
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+fn default_max_entries() -> u32 {
+    10_000
+}
+
+fn default_max_matches() -> usize {
+    100
+}
+
+fn default_time_limit_ms() -> u64 {
+    5_000
+}
+
+#[derive(Deserialize)]
+struct GrepRequest {
+    pattern: String,
+    #[serde(default)]
+    case_insensitive: bool,
+    /// Entry index (in path order) to start from, as returned by `next_entry`.
+    #[serde(default)]
+    start: u32,
+    #[serde(default = "default_max_entries")]
+    max_entries: u32,
+    #[serde(default = "default_max_matches")]
+    max_matches: usize,
+    #[serde(default = "default_time_limit_ms")]
+    time_limit_ms: u64,
+}
+
+#[derive(Serialize)]
+struct GrepMatch {
+    path: String,
+    title: String,
+    line_number: usize,
+    line: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum StopReason {
+    Finished,
+    MaxEntries,
+    MaxMatches,
+    TimeLimit,
+}
+
+#[derive(Serialize)]
+struct GrepResponse {
+    matches: Vec<GrepMatch>,
+    scanned_entries: u32,
+    stopped: StopReason,
+    /// Where to continue a truncated scan, `None` once the archive is done.
+    next_entry: Option<u32>,
+}
+
+/// Cuts `line` down to the match and some context on each side.
+fn excerpt(line: &str, start: usize, end: usize) -> String {
+    let from = line[..start]
+        .char_indices()
+        .rev()
+        .nth(CONTEXT_CHARS)
+        .map_or(0, |(i, _)| i);
+    let to = line[end..]
+        .char_indices()
+        .nth(CONTEXT_CHARS)
+        .map_or(line.len(), |(i, _)| end + i);
+    let mut excerpt = String::new();
+    if from > 0 {
+        excerpt.push('…');
+    }
+    excerpt.push_str(&line[from..to]);
+    if to < line.len() {
+        excerpt.push('…');
+    }
+    excerpt
+}
+
+fn grep_archive(
+    zim_path: &Path,
+    regex: &Regex,
+    req: &GrepRequest,
+    blocklist: &Blocklist,
+) -> Result<GrepResponse> {
+    let zim = Archive::new(zim_path.to_str().unwrap())
+        .map_err(|e| anyhow!("Failed to open archive: {:?}", e))?;
+    let total = zim.get_all_entrycount();
+    let max_entries = req.max_entries.clamp(1, MAX_ENTRIES_LIMIT);
+    let max_matches = req.max_matches.clamp(1, MAX_MATCHES_LIMIT);
+    let deadline =
+        Instant::now() + Duration::from_millis(req.time_limit_ms.min(TIME_LIMIT_MS_LIMIT));
+
+    let mut matches = Vec::new();
+    let mut idx = req.start;
+    let stopped = loop {
+        if idx >= total {
+            break StopReason::Finished;
+        }
+        if idx - req.start >= max_entries {
+            break StopReason::MaxEntries;
+        }
+        if matches.len() >= max_matches {
+            break StopReason::MaxMatches;
+        }
+        if Instant::now() >= deadline {
+            break StopReason::TimeLimit;
+        }
+
+        let entry_idx = idx;
+        idx += 1;
+        let Ok(entry) = zim.get_entry_bypath_index(entry_idx) else {
+            continue;
+        };
+        if entry.is_redirect() || blocklist.is_blocked(&entry.get_title(), &entry.get_path()) {
+            continue;
+        }
+        let Ok(item) = entry.get_item(false) else {
+            continue;
+        };
+        if !item
+            .get_mimetype()
+            .is_ok_and(|m| m.starts_with("text/html"))
+        {
+            continue;
+        }
+        let Ok(blob) = item.get_data() else {
+            continue;
+        };
+        let html = String::from_utf8_lossy(blob.data());
+        for (line_idx, line) in article_html::text_lines(&html).iter().enumerate() {
+            if let Some(m) = regex.find(line) {
+                matches.push(GrepMatch {
+                    path: entry.get_path(),
+                    title: entry.get_title(),
+                    line_number: line_idx + 1,
+                    line: excerpt(line, m.start(), m.end()),
+                });
+                if matches.len() >= max_matches {
+                    break;
+                }
+            }
+        }
+    };
+
+    Ok(GrepResponse {
+        matches,
+        scanned_entries: idx - req.start,
+        next_entry: (idx < total).then_some(idx),
+        stopped,
+    })
+}
+
+#[post("/archives/{id}/grep", wrap = "crate::load_shed::Shed::heavy()")]
+async fn grep(
+    path: web::Path<String>,
+    req: web::Json<GrepRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let Some(zim_path) = state.archive_path(&id) else {
+        return HttpResponse::NotFound().json(json!({"error": "Archive not found"}));
+    };
+    let _lease = state.leases.acquire(&zim_path);
+    let regex = match RegexBuilder::new(&req.pattern)
+        .case_insensitive(req.case_insensitive)
+        .size_limit(REGEX_SIZE_LIMIT)
+        .build()
+    {
+        Ok(regex) => regex,
+        Err(e) => return HttpResponse::BadRequest().json(json!({"error": e.to_string()})),
+    };
+    let blocklist = state.blocklist_for(Some(&id));
+
+    let req = req.into_inner();
+    match web::block(move || grep_archive(&zim_path, &regex, &req, &blocklist)).await {
+        Ok(Ok(response)) => HttpResponse::Ok().json(response),
+        Ok(Err(e)) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+    }
+}
@@ -0,0 +1,129 @@
+//! Per-session search history, persisted as `search_history.json` in the
+//! library directory so users can re-run past queries after a restart.
+
+use crate::AppState;
+use crate::json_store::{self, JsonStore};
+use crate::session;
+use actix_web::{HttpRequest, HttpResponse, Responder, delete, get, web};
+use anyhow::Result;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+
+pub const HISTORY_FILE: &str = "search_history.json";
+/// Oldest queries are dropped once a session has this many.
+const MAX_ENTRIES_PER_SESSION: usize = 200;
+/// Sessions kept; the ones that searched least recently are dropped first.
+const MAX_SESSIONS: usize = 5000;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HistoryEntry {
+    pub query: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archive_id: Option<String>,
+    pub file_path: PathBuf,
+    pub total_estimate: u64,
+    pub searched_at: i64,
+}
+
+#[derive(Clone)]
+pub struct SearchHistory {
+    store: JsonStore<Vec<HistoryEntry>>,
+}
+
+impl SearchHistory {
+    pub fn load(library_dir: &Path) -> Result<SearchHistory> {
+        Ok(SearchHistory {
+            store: JsonStore::load(library_dir, HISTORY_FILE)?,
+        })
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.store.flush()
+    }
+
+    pub fn record(&self, session: &str, entry: HistoryEntry) {
+        self.store.write(|sessions| {
+            let entries = sessions.entry(session.to_string()).or_default();
+            entries.push(entry);
+            if entries.len() > MAX_ENTRIES_PER_SESSION {
+                let excess = entries.len() - MAX_ENTRIES_PER_SESSION;
+                entries.drain(..excess);
+            }
+            json_store::evict_oldest(sessions, MAX_SESSIONS, |entries| {
+                entries.last().map_or(0, |entry| entry.searched_at)
+            });
+        });
+    }
+
+    /// The session's queries, newest first.
+    pub fn list(&self, session: &str) -> Vec<HistoryEntry> {
+        let mut entries = self
+            .store
+            .read(|sessions| sessions.get(session).cloned().unwrap_or_default());
+        entries.reverse();
+        entries
+    }
+
+    /// Removes one session's history, or every session's when `session` is
+    /// `None`. Returns the number of entries removed.
+    pub fn clear(&self, session: Option<&str>) -> usize {
+        self.store.write(|sessions| match session {
+            Some(session) => sessions.remove(session).map_or(0, |e| e.len()),
+            None => {
+                let removed = sessions.values().map(Vec::len).sum();
+                sessions.clear();
+                removed
+            }
+        })
+    }
+}
+
+pub fn new_entry(
+    query: &str,
+    archive_id: Option<String>,
+    file_path: &Path,
+    total: u64,
+) -> HistoryEntry {
+    HistoryEntry {
+        query: query.to_string(),
+        archive_id,
+        file_path: file_path.to_path_buf(),
+        total_estimate: total,
+        searched_at: Utc::now().timestamp(),
+    }
+}
+
+#[get("/history")]
+async fn list_history(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    let (session, cookie) = session::session_id(&req);
+    session::with_session(cookie, HttpResponse::Ok()).json(state.search_history.list(&session))
+}
+
+#[delete("/history")]
+async fn clear_history(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    let (session, _) = session::session_id(&req);
+    let removed = state.search_history.clear(Some(&session));
+    HttpResponse::Ok().json(json!({ "removed": removed }))
+}
+
+#[derive(Deserialize)]
+struct PurgeQuery {
+    session: Option<String>,
+}
+
+/// Admin purge of every session's history, or of one with `?session=<id>`.
+#[delete("/admin/history")]
+async fn purge_history(
+    req: HttpRequest,
+    query: web::Query<PurgeQuery>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    if !state.is_admin(&req) {
+        return HttpResponse::Unauthorized().json(json!({"error": "Invalid or missing token"}));
+    }
+
+    let removed = state.search_history.clear(query.session.as_deref());
+    HttpResponse::Ok().json(json!({ "removed": removed }))
+}
@@ -0,0 +1,411 @@
+//! Background jobs for long-running archive work (exports, reports, ...).
+//! Each job runs on its own thread, reports progress through a shared
+//! `JobInfo` and writes its results into a per-job output directory. Jobs that
+//! support it check `JobHandle::is_paused`/`is_cancelled` to honour pause and
+//! cancel requests.
+
+use crate::AppState;
+use actix_files::NamedFile;
+use actix_web::{HttpResponse, Responder, get, web};
+use anyhow::Result;
+use async_stream::stream;
+use chrono::Utc;
+use log::{error, info};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+/// How long a finished job and its output stay available.
+const FINISHED_JOB_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Finished jobs kept at most; the oldest are pruned first.
+const MAX_FINISHED_JOBS: usize = 100;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Running,
+    Paused,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Serialize, Clone)]
+pub struct JobInfo {
+    pub id: String,
+    pub kind: String,
+    pub status: JobStatus,
+    /// What the job is doing right now, e.g. `verifying` or `downloading`.
+    pub phase: Option<String>,
+    pub processed: u64,
+    pub total: u64,
+    /// Smoothed progress rate, in `processed` units per second.
+    pub rate: f64,
+    pub error: Option<String>,
+    pub output_files: Vec<String>,
+    pub started_at: i64,
+    pub finished_at: Option<i64>,
+}
+
+#[derive(Default)]
+struct JobControl {
+    paused: AtomicBool,
+    cancelled: AtomicBool,
+}
+
+/// Given to the job's work function to report progress and register outputs.
+pub struct JobHandle {
+    info: Arc<Mutex<JobInfo>>,
+    control: Arc<JobControl>,
+    output_dir: PathBuf,
+    last_sample: Mutex<Option<(Instant, u64)>>,
+}
+
+impl JobHandle {
+    pub fn output_dir(&self) -> &Path {
+        &self.output_dir
+    }
+
+    pub fn set_phase(&self, phase: &str) {
+        self.info.lock().unwrap().phase = Some(phase.to_string());
+    }
+
+    pub fn set_total(&self, total: u64) {
+        self.info.lock().unwrap().total = total;
+    }
+
+    pub fn set_processed(&self, processed: u64) {
+        let mut info = self.info.lock().unwrap();
+        info.processed = processed;
+
+        let now = Instant::now();
+        let mut last_sample = self.last_sample.lock().unwrap();
+        match *last_sample {
+            Some((at, value)) => {
+                let elapsed = now.duration_since(at).as_secs_f64();
+                if elapsed >= 1.0 {
+                    let current = processed.saturating_sub(value) as f64 / elapsed;
+                    info.rate = 0.7 * info.rate + 0.3 * current;
+                    *last_sample = Some((now, processed));
+                }
+            }
+            None => *last_sample = Some((now, processed)),
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.control.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.control.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Registers a file written into `output_dir` as downloadable.
+    pub fn add_output_file(&self, name: &str) {
+        self.info
+            .lock()
+            .unwrap()
+            .output_files
+            .push(name.to_string());
+    }
+}
+
+struct Job {
+    info: Arc<Mutex<JobInfo>>,
+    control: Arc<JobControl>,
+    output_dir: PathBuf,
+}
+
+/// Jobs of `jobs` to forget: those finished longer than `ttl` ago, then the
+/// oldest finished ones beyond `max_finished`.
+fn expired_jobs(
+    jobs: &[(String, Option<i64>)],
+    now: i64,
+    ttl: Duration,
+    max_finished: usize,
+) -> Vec<String> {
+    let mut finished: Vec<(&String, i64)> = jobs
+        .iter()
+        .filter_map(|(id, finished_at)| Some((id, (*finished_at)?)))
+        .collect();
+    finished.sort_by_key(|&(_, finished_at)| std::cmp::Reverse(finished_at));
+    finished
+        .iter()
+        .enumerate()
+        .filter(|&(i, &(_, finished_at))| {
+            i >= max_finished || now - finished_at > ttl.as_secs() as i64
+        })
+        .map(|(_, (id, _))| (*id).clone())
+        .collect()
+}
+
+#[derive(Clone, Default)]
+pub struct JobRegistry {
+    jobs: Arc<Mutex<HashMap<String, Job>>>,
+}
+
+impl JobRegistry {
+    /// Starts `work` on a new thread and returns the job id. Outputs go to
+    /// `output_root/<job id>/`.
+    pub fn spawn<F>(&self, kind: &str, output_root: &Path, work: F) -> io::Result<String>
+    where
+        F: FnOnce(&JobHandle) -> Result<()> + Send + 'static,
+    {
+        self.prune();
+        let id = uuid::Uuid::new_v4().to_string();
+        let output_dir = output_root.join(&id);
+        fs::create_dir_all(&output_dir)?;
+
+        let info = Arc::new(Mutex::new(JobInfo {
+            id: id.clone(),
+            kind: kind.to_string(),
+            status: JobStatus::Running,
+            phase: None,
+            processed: 0,
+            total: 0,
+            rate: 0.0,
+            error: None,
+            output_files: Vec::new(),
+            started_at: Utc::now().timestamp(),
+            finished_at: None,
+        }));
+        let control = Arc::new(JobControl::default());
+        self.jobs.lock().unwrap().insert(
+            id.clone(),
+            Job {
+                info: info.clone(),
+                control: control.clone(),
+                output_dir: output_dir.clone(),
+            },
+        );
+
+        let handle = JobHandle {
+            info,
+            control,
+            output_dir,
+            last_sample: Mutex::new(None),
+        };
+        let job_id = id.clone();
+        let kind = kind.to_string();
+        thread::Builder::new()
+            .name(format!("job-{}", kind))
+            .spawn(move || {
+                info!("Job {} ({}) started", job_id, kind);
+                let result = panic::catch_unwind(AssertUnwindSafe(|| work(&handle)))
+                    .unwrap_or_else(|panic| {
+                        let message = panic
+                            .downcast_ref::<&str>()
+                            .map(|s| s.to_string())
+                            .or_else(|| panic.downcast_ref::<String>().cloned())
+                            .unwrap_or_else(|| "unknown panic".to_string());
+                        Err(anyhow::anyhow!("Job panicked: {}", message))
+                    });
+                // A panic may have poisoned the lock while the job held it.
+                let mut info = handle
+                    .info
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+                info.finished_at = Some(Utc::now().timestamp());
+                info.rate = 0.0;
+                match result {
+                    Err(_) if handle.is_cancelled() => {
+                        info.status = JobStatus::Cancelled;
+                        info!("Job {} ({}) cancelled", job_id, kind);
+                    }
+                    Ok(()) => {
+                        info.status = JobStatus::Completed;
+                        info!("Job {} ({}) completed", job_id, kind);
+                    }
+                    Err(e) => {
+                        info.status = JobStatus::Failed;
+                        info.error = Some(e.to_string());
+                        error!("Job {} ({}) failed: {:?}", job_id, kind, e);
+                    }
+                }
+            })?;
+        Ok(id)
+    }
+
+    /// Forgets expired finished jobs and deletes their output.
+    fn prune(&self) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let finished: Vec<(String, Option<i64>)> = jobs
+            .iter()
+            .map(|(id, job)| (id.clone(), job.info.lock().unwrap().finished_at))
+            .collect();
+        let now = Utc::now().timestamp();
+        for id in expired_jobs(&finished, now, FINISHED_JOB_TTL, MAX_FINISHED_JOBS) {
+            if let Some(job) = jobs.remove(&id) {
+                if let Err(e) = fs::remove_dir_all(&job.output_dir) {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        error!("Failed to remove output of job {}: {:?}", id, e);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<JobInfo> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.get(id).map(|job| job.info.lock().unwrap().clone())
+    }
+
+    pub fn list(&self) -> Vec<JobInfo> {
+        let jobs = self.jobs.lock().unwrap();
+        let mut list: Vec<JobInfo> = jobs
+            .values()
+            .map(|job| job.info.lock().unwrap().clone())
+            .collect();
+        list.sort_by_key(|j| j.started_at);
+        list
+    }
+
+    /// Applies a pause, resume or cancel request to an unfinished job and
+    /// returns its updated info, or `None` if there is no such job.
+    fn control(&self, id: &str, apply: impl FnOnce(&JobControl, &mut JobInfo)) -> Option<JobInfo> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.get(id)?;
+        let mut info = job.info.lock().unwrap();
+        if matches!(info.status, JobStatus::Running | JobStatus::Paused) {
+            apply(&job.control, &mut info);
+        }
+        Some(info.clone())
+    }
+
+    pub fn pause(&self, id: &str) -> Option<JobInfo> {
+        self.control(id, |control, info| {
+            control.paused.store(true, Ordering::Relaxed);
+            info.status = JobStatus::Paused;
+        })
+    }
+
+    pub fn resume(&self, id: &str) -> Option<JobInfo> {
+        self.control(id, |control, info| {
+            control.paused.store(false, Ordering::Relaxed);
+            info.status = JobStatus::Running;
+        })
+    }
+
+    /// Asks the job to stop; its status becomes `cancelled` once it has.
+    pub fn cancel(&self, id: &str) -> Option<JobInfo> {
+        self.control(id, |control, _| {
+            control.cancelled.store(true, Ordering::Relaxed);
+            control.paused.store(false, Ordering::Relaxed);
+        })
+    }
+}
+
+/// Directory holding the output of every job.
+pub fn jobs_dir(state: &AppState) -> PathBuf {
+    state.library_dir().join("jobs")
+}
+
+#[get("/jobs")]
+async fn list_jobs(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(state.jobs.list())
+}
+
+#[get("/jobs/{id}")]
+async fn get_job(path: web::Path<String>, state: web::Data<AppState>) -> impl Responder {
+    match state.jobs.get(&path.into_inner()) {
+        Some(job) => HttpResponse::Ok().json(job),
+        None => HttpResponse::NotFound().json(json!({"error": "Job not found"})),
+    }
+}
+
+/// Server-sent events with the job's info whenever it changes, ending once
+/// the job has finished.
+#[get("/jobs/{id}/events")]
+async fn job_events(path: web::Path<String>, state: web::Data<AppState>) -> impl Responder {
+    let id = path.into_inner();
+    if state.jobs.get(&id).is_none() {
+        return HttpResponse::NotFound().json(json!({"error": "Job not found"}));
+    }
+    let jobs = state.jobs.clone();
+    let s = stream! {
+        let mut last = String::new();
+        while let Some(job) = jobs.get(&id) {
+            let data = serde_json::to_string(&job).unwrap_or_default();
+            if data != last {
+                yield Ok::<_, actix_web::Error>(web::Bytes::from(format!("data: {}\n\n", data)));
+                last = data;
+            }
+            if job.finished_at.is_some() {
+                break;
+            }
+            sleep(Duration::from_millis(500)).await;
+        }
+    };
+
+    HttpResponse::Ok()
+        .insert_header(("Content-Type", "text/event-stream"))
+        .streaming(s)
+}
+
+#[get("/jobs/{id}/files/{name}")]
+async fn download_job_file(
+    path: web::Path<(String, String)>,
+    state: web::Data<AppState>,
+) -> actix_web::Result<NamedFile> {
+    let (id, name) = path.into_inner();
+    let job = state
+        .jobs
+        .get(&id)
+        .ok_or_else(|| actix_web::error::ErrorNotFound("Job not found"))?;
+    if !job.output_files.contains(&name) {
+        return Err(actix_web::error::ErrorNotFound("File not found"));
+    }
+    Ok(NamedFile::open(jobs_dir(&state).join(&id).join(&name))?.use_last_modified(true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expired_jobs_keeps_running_and_recent_jobs() {
+        let jobs = vec![
+            ("running".to_string(), None),
+            ("recent".to_string(), Some(1_000)),
+            ("old".to_string(), Some(0)),
+        ];
+        let expired = expired_jobs(&jobs, 1_100, Duration::from_secs(500), 10);
+        assert_eq!(expired, vec!["old".to_string()]);
+    }
+
+    #[test]
+    fn expired_jobs_caps_finished_jobs() {
+        let jobs: Vec<_> = (0..5).map(|i| (i.to_string(), Some(i))).collect();
+        let mut expired = expired_jobs(&jobs, 10, Duration::from_secs(3600), 3);
+        expired.sort();
+        assert_eq!(expired, vec!["0".to_string(), "1".to_string()]);
+    }
+
+    #[test]
+    fn panicking_job_is_marked_failed() {
+        let registry = JobRegistry::default();
+        let root = std::env::temp_dir().join(format!("jobs-test-{}", uuid::Uuid::new_v4()));
+        let id = registry.spawn("test", &root, |_| panic!("boom")).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let job = loop {
+            let job = registry.get(&id).unwrap();
+            if job.finished_at.is_some() || Instant::now() > deadline {
+                break job;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(job.status, JobStatus::Failed);
+        assert!(job.error.unwrap().contains("boom"));
+        let _ = fs::remove_dir_all(root);
+    }
+}
@@ -0,0 +1,242 @@
+//! Maps kept in memory and persisted as one JSON file in the library directory
+//! (search history, collections, preferences). Changes are written by a
+//! background thread a moment later, so a burst of them costs one write and no
+//! request waits on the disk; the file is replaced atomically.
+//!
+//! Instances sharing the library directory (cluster mode) share the file too:
+//! a save re-reads it under the library lock, writes back only the entries
+//! changed here since the last save, and takes over what the others changed.
+
+use crate::library::lock_library;
+use anyhow::{Context, Result};
+use log::error;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::NamedTempFile;
+
+/// How long changes are collected before they are written.
+const SAVE_DELAY: Duration = Duration::from_secs(1);
+
+pub struct JsonStore<V> {
+    path: PathBuf,
+    entries: Arc<Mutex<BTreeMap<String, V>>>,
+    /// The entries as last read from or written to the file, to tell this
+    /// instance's changes from the others'. Held for a whole save, so a
+    /// `flush` and the background thread never interleave.
+    saved: Arc<Mutex<BTreeMap<String, Value>>>,
+    changed: Sender<()>,
+}
+
+impl<V> Clone for JsonStore<V> {
+    fn clone(&self) -> Self {
+        JsonStore {
+            path: self.path.clone(),
+            entries: self.entries.clone(),
+            saved: self.saved.clone(),
+            changed: self.changed.clone(),
+        }
+    }
+}
+
+fn read_file(path: &Path) -> Result<BTreeMap<String, Value>> {
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let raw =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    serde_json::from_str(&raw).with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+fn to_values<V: Serialize>(entries: &BTreeMap<String, V>) -> Result<BTreeMap<String, Value>> {
+    entries
+        .iter()
+        .map(|(key, value)| Ok((key.clone(), serde_json::to_value(value)?)))
+        .collect()
+}
+
+fn save<V: Serialize + DeserializeOwned>(
+    path: &Path,
+    entries: &Mutex<BTreeMap<String, V>>,
+    saved: &Mutex<BTreeMap<String, Value>>,
+) -> Result<()> {
+    let mut saved = saved.lock().unwrap();
+    let ours = to_values(&entries.lock().unwrap())?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let _lock = lock_library(dir)?;
+
+    let mut merged = read_file(path)?;
+    let keys: BTreeSet<&String> = saved.keys().chain(ours.keys()).collect();
+    for key in keys {
+        if saved.get(key) == ours.get(key) {
+            continue;
+        }
+        match ours.get(key) {
+            Some(value) => merged.insert(key.clone(), value.clone()),
+            None => merged.remove(key),
+        };
+    }
+    let mut tmp = NamedTempFile::new_in(dir)?;
+    tmp.write_all(&serde_json::to_vec(&merged)?)?;
+    tmp.persist(path)?;
+
+    // Take over the others' changes, unless the entry changed here meanwhile.
+    let mut entries = entries.lock().unwrap();
+    let keys: BTreeSet<&String> = merged.keys().chain(ours.keys()).collect();
+    for key in keys {
+        if merged.get(key) == ours.get(key) {
+            continue;
+        }
+        let current = entries.get(key).map(serde_json::to_value).transpose()?;
+        if current.as_ref() != ours.get(key) {
+            continue;
+        }
+        match merged.get(key) {
+            Some(value) => {
+                entries.insert(key.clone(), serde_json::from_value(value.clone())?);
+            }
+            None => {
+                entries.remove(key);
+            }
+        }
+    }
+    *saved = merged;
+    Ok(())
+}
+
+impl<V: Serialize + DeserializeOwned + Send + 'static> JsonStore<V> {
+    /// Loads `library_dir/file_name`, empty if it doesn't exist yet, and
+    /// starts the thread saving it.
+    pub fn load(library_dir: &Path, file_name: &str) -> Result<JsonStore<V>> {
+        let path = library_dir.join(file_name);
+        let saved = read_file(&path)?;
+        let entries = saved
+            .iter()
+            .map(|(key, value)| Ok((key.clone(), serde_json::from_value(value.clone())?)))
+            .collect::<Result<BTreeMap<String, V>>>()
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+        let entries = Arc::new(Mutex::new(entries));
+        let saved = Arc::new(Mutex::new(saved));
+
+        let (changed, pending) = mpsc::channel::<()>();
+        let (save_path, save_entries, save_saved) = (path.clone(), entries.clone(), saved.clone());
+        thread::Builder::new()
+            .name(format!("save-{}", file_name))
+            .spawn(move || {
+                // Ends once every handle to the store is gone.
+                while pending.recv().is_ok() {
+                    thread::sleep(SAVE_DELAY);
+                    while pending.try_recv().is_ok() {}
+                    if let Err(e) = save(&save_path, &save_entries, &save_saved) {
+                        error!("Failed to save {}: {:?}", save_path.display(), e);
+                    }
+                }
+            })?;
+        Ok(JsonStore {
+            path,
+            entries,
+            saved,
+            changed,
+        })
+    }
+
+    pub fn read<R>(&self, read: impl FnOnce(&BTreeMap<String, V>) -> R) -> R {
+        read(&self.entries.lock().unwrap())
+    }
+
+    /// Applies `change` and schedules a save.
+    pub fn write<R>(&self, change: impl FnOnce(&mut BTreeMap<String, V>) -> R) -> R {
+        let result = change(&mut self.entries.lock().unwrap());
+        let _ = self.changed.send(());
+        result
+    }
+
+    /// Writes the file now, e.g. before shutting down.
+    pub fn flush(&self) -> Result<()> {
+        save(&self.path, &self.entries, &self.saved)
+    }
+}
+
+/// Drops the entries used least recently until at most `max` are left.
+pub fn evict_oldest<V>(
+    entries: &mut BTreeMap<String, V>,
+    max: usize,
+    last_used: impl Fn(&V) -> i64,
+) {
+    if entries.len() <= max {
+        return;
+    }
+    let mut by_age: Vec<(i64, String)> = entries
+        .iter()
+        .map(|(key, value)| (last_used(value), key.clone()))
+        .collect();
+    by_age.sort();
+    let excess = entries.len() - max;
+    for (_, key) in by_age.into_iter().take(excess) {
+        entries.remove(&key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evict_oldest_keeps_the_most_recent_entries() {
+        let mut entries: BTreeMap<String, i64> = [("a", 3), ("b", 1), ("c", 2)]
+            .map(|(k, v)| (k.to_string(), v))
+            .into();
+        evict_oldest(&mut entries, 2, |used| *used);
+        assert_eq!(entries.keys().collect::<Vec<_>>(), ["a", "c"]);
+        evict_oldest(&mut entries, 5, |used| *used);
+        assert_eq!(entries.len(), 2);
+    }
+
+    #[test]
+    fn changes_are_saved_and_loaded_back() {
+        let dir = std::env::temp_dir().join(format!("json-store-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let store: JsonStore<Vec<u32>> = JsonStore::load(&dir, "store.json").unwrap();
+        store.write(|entries| entries.insert("session".to_string(), vec![1, 2]));
+        store.flush().unwrap();
+
+        let loaded: JsonStore<Vec<u32>> = JsonStore::load(&dir, "store.json").unwrap();
+        assert_eq!(
+            loaded.read(|entries| entries.get("session").cloned()),
+            Some(vec![1, 2])
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stores_sharing_a_file_merge_their_changes() {
+        let dir = std::env::temp_dir().join(format!("json-store-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let first: JsonStore<u32> = JsonStore::load(&dir, "store.json").unwrap();
+        let second: JsonStore<u32> = JsonStore::load(&dir, "store.json").unwrap();
+        first.write(|entries| entries.insert("a".to_string(), 1));
+        first.flush().unwrap();
+        second.write(|entries| entries.insert("b".to_string(), 2));
+        second.flush().unwrap();
+        // The second store didn't drop the first one's entry and took it over.
+        assert_eq!(second.read(|entries| entries.get("a").copied()), Some(1));
+
+        first.write(|entries| entries.remove("a"));
+        first.flush().unwrap();
+        assert_eq!(first.read(|entries| entries.get("b").copied()), Some(2));
+        let loaded: JsonStore<u32> = JsonStore::load(&dir, "store.json").unwrap();
+        assert_eq!(
+            loaded.read(|entries| entries.keys().cloned().collect::<Vec<_>>()),
+            ["b"]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,891 @@
+//! Routes following kiwix-serve's query contracts, so Kiwix clients can use
+//! this server unchanged:
+//!
+//! - `GET /search?books.name=..&pattern=..&start=..&pageLength=..&format=xml`
+//! - `GET /suggest?books.name=..&term=..&count=..`
+//! - `GET /content/{book}/{path}`
+//!
+//! Books are selected by `books.name` (the archive's `Name` metadata),
+//! `books.id` (library id or archive UUID) or the legacy `content` parameter,
+//! and searches can be narrowed to tagged books with `books.filter.tag`.
+
+use crate::blocklist::{self, Blocklist};
+use crate::body_cache::{self, BodyCache, Encoding};
+use crate::charset;
+use crate::config::BodyCacheConfig;
+use crate::facets::Facets;
+use crate::library::Manifest;
+use crate::load_shed;
+use crate::search_queue;
+use crate::tags;
+use crate::{AppState, ArticleSummary, run_fulltext_search};
+use actix_web::{HttpRequest, HttpResponse, Responder, get, web};
+use anyhow::{Result, anyhow};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use zim_rs::archive::Archive;
+
+const DEFAULT_PAGE_LENGTH: u32 = 25;
+const MAX_PAGE_LENGTH: u32 = 140;
+const DEFAULT_SUGGESTION_COUNT: u32 = 10;
+/// Terms this short get featured and most viewed titles instead of only
+/// prefix matches.
+const MAX_DEFAULT_TERM_CHARS: usize = 1;
+/// Most viewed paths considered when building default suggestions.
+const POPULAR_CANDIDATES: usize = 200;
+
+/// A library archive as Kiwix addresses it.
+pub(crate) struct KiwixBook {
+    id: String,
+    uuid: String,
+    name: String,
+    title: String,
+    pub path: PathBuf,
+}
+
+fn param<'a>(params: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    params
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.as_str())
+}
+
+fn params_named(params: &[(String, String)], key: &str) -> Vec<String> {
+    params
+        .iter()
+        .filter(|(k, _)| k == key)
+        .map(|(_, v)| v.clone())
+        .collect()
+}
+
+/// `Name` metadata of the archive, falling back to the library id.
+fn book_name(zim: &Archive, id: &str) -> String {
+    zim.get_metadata("Name")
+        .ok()
+        .filter(|n| !n.trim().is_empty())
+        .unwrap_or_else(|| id.to_string())
+}
+
+/// Size and modification time of an archive file, which change whenever the
+/// file is replaced.
+type Fingerprint = (SystemTime, u64);
+
+fn fingerprint(path: &PathBuf) -> Option<Fingerprint> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+struct CachedBook {
+    fingerprint: Fingerprint,
+    uuid: String,
+    name: String,
+    title: String,
+}
+
+/// Name, UUID and title of every library archive, so resolving a book doesn't
+/// open every archive on each request. Entries are keyed by library id and
+/// path, dropped once the archive leaves the library and re-read once the
+/// file changes.
+#[derive(Clone, Default)]
+pub struct BookCache {
+    books: Arc<Mutex<HashMap<(String, PathBuf), CachedBook>>>,
+}
+
+impl BookCache {
+    /// Drops the archives no longer in `archives`, the whole library.
+    fn retain(&self, archives: &[(String, PathBuf)]) {
+        let current: HashSet<&(String, PathBuf)> = archives.iter().collect();
+        self.books
+            .lock()
+            .unwrap()
+            .retain(|key, _| current.contains(key));
+    }
+
+    fn book(&self, id: String, path: PathBuf) -> Option<KiwixBook> {
+        let fingerprint = fingerprint(&path)?;
+        let key = (id, path);
+        if let Some(cached) = self.books.lock().unwrap().get(&key) {
+            if cached.fingerprint == fingerprint {
+                return Some(KiwixBook {
+                    uuid: cached.uuid.clone(),
+                    name: cached.name.clone(),
+                    title: cached.title.clone(),
+                    id: key.0,
+                    path: key.1,
+                });
+            }
+        }
+        let zim = Archive::new(key.1.to_str().unwrap()).ok()?;
+        let name = book_name(&zim, &key.0);
+        let cached = CachedBook {
+            fingerprint,
+            uuid: zim.get_uuid().to_string(),
+            title: zim.get_metadata("Title").unwrap_or_else(|_| name.clone()),
+            name,
+        };
+        let book = KiwixBook {
+            uuid: cached.uuid.clone(),
+            name: cached.name.clone(),
+            title: cached.title.clone(),
+            id: key.0.clone(),
+            path: key.1.clone(),
+        };
+        self.books.lock().unwrap().insert(key, cached);
+        Some(book)
+    }
+}
+
+/// Finds the books matching any of `selectors` (names, library ids or UUIDs).
+/// Without selectors every book in the library is returned.
+pub(crate) fn resolve_books(
+    cache: &BookCache,
+    archives: Vec<(String, PathBuf)>,
+    selectors: &[String],
+) -> Vec<KiwixBook> {
+    archives
+        .into_iter()
+        .filter_map(|(id, path)| cache.book(id, path))
+        .filter(|book| {
+            selectors.is_empty()
+                || selectors
+                    .iter()
+                    .any(|s| *s == book.name || *s == book.id || *s == book.uuid)
+        })
+        .collect()
+}
+
+pub(crate) fn library_archives(state: &AppState) -> Vec<(String, PathBuf)> {
+    let mut archives: Vec<(String, PathBuf)> = state
+        .file_cache
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(id, path)| (id.clone(), path.clone()))
+        .collect();
+    archives.sort();
+    state.kiwix_books.retain(&archives);
+    archives
+}
+
+/// Effective blocklist of every library archive, keyed by id.
+pub(crate) fn library_blocklists(
+    state: &AppState,
+    archives: &[(String, PathBuf)],
+) -> HashMap<String, Blocklist> {
+    archives
+        .iter()
+        .map(|(id, _)| (id.clone(), state.blocklist_for(Some(id))))
+        .collect()
+}
+
+fn book_selectors(params: &[(String, String)]) -> Vec<String> {
+    let mut selectors = params_named(params, "books.name");
+    selectors.extend(params_named(params, "books.id"));
+    selectors.extend(params_named(params, "content"));
+    selectors
+}
+
+pub(crate) struct KiwixSearchResults {
+    pub total: u64,
+    /// `(book name, book title, hit)`.
+    pub hits: Vec<(String, String, ArticleSummary)>,
+    pub facets: Option<Facets>,
+}
+
+/// The 0-based offset of kiwix-serve's 1-based `start`, `None` when the page
+/// would end past what libzim can address (`i32` offsets).
+fn search_start(start: Option<&str>, page_length: u32) -> Option<u32> {
+    let start = match start.map(str::parse::<u64>) {
+        Some(Ok(start)) => start.saturating_sub(1),
+        Some(Err(_)) | None => 0,
+    };
+    (start + page_length as u64 <= i32::MAX as u64).then_some(start as u32)
+}
+
+/// Searches the selected books in order, skipping the first `start` hits over
+/// all of them. Each hit carries its book's name and title.
+pub(crate) fn search_books(
+    books: &[KiwixBook],
+    pattern: &str,
+    start: u32,
+    page_length: u32,
+    blocklists: &HashMap<String, Blocklist>,
+    with_facets: bool,
+) -> Result<KiwixSearchResults> {
+    let mut total = 0;
+    let mut hits = Vec::new();
+    let mut facets = with_facets.then(Facets::default);
+    let mut skip = start as u64;
+    for book in books {
+        let zim = Archive::new(book.path.to_str().unwrap())
+            .map_err(|e| anyhow!("Failed to open archive {}: {:?}", book.name, e))?;
+        let remaining = page_length.saturating_sub(hits.len() as u32);
+        let blocklist = blocklists.get(&book.id).cloned().unwrap_or_default();
+        let mut book_facets = facets.as_ref().map(|_| Facets::default());
+        let (estimate, results) = run_fulltext_search(
+            &zim,
+            pattern,
+            skip.min(u32::MAX as u64) as u32,
+            remaining,
+            &blocklist,
+            book_facets.as_mut(),
+        )?;
+        if let (Some(facets), Some(mut book_facets)) = (facets.as_mut(), book_facets) {
+            book_facets.archive.insert(book.name.clone(), estimate);
+            facets.merge(book_facets);
+        }
+        total += estimate;
+        skip = skip.saturating_sub(estimate);
+        for result in results.into_iter().take(remaining as usize) {
+            hits.push((book.name.clone(), book.title.clone(), result));
+        }
+    }
+    Ok(KiwixSearchResults {
+        total,
+        hits,
+        facets,
+    })
+}
+
+fn escape(text: &str) -> String {
+    html_escape::encode_text(text).into_owned()
+}
+
+pub(crate) fn content_link(book_name: &str, path: &str) -> String {
+    let encoded: Vec<String> = path
+        .split('/')
+        .map(|s| urlencoding::encode(s).into_owned())
+        .collect();
+    format!(
+        "/content/{}/{}",
+        urlencoding::encode(book_name),
+        encoded.join("/")
+    )
+}
+
+fn search_rss(pattern: &str, start: u32, page_length: u32, results: &KiwixSearchResults) -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <rss version=\"2.0\" xmlns:opensearch=\"http://a9.com/-/spec/opensearch/1.1/\" \
+         xmlns:atom=\"http://www.w3.org/2005/Atom\">\n  <channel>\n",
+    );
+    xml.push_str(&format!(
+        "    <title>Search: {0}</title>\n    <link>/search?pattern={1}&amp;format=xml</link>\n    \
+         <description>Search result for {0}</description>\n    \
+         <opensearch:totalResults>{2}</opensearch:totalResults>\n    \
+         <opensearch:startIndex>{3}</opensearch:startIndex>\n    \
+         <opensearch:itemsPerPage>{4}</opensearch:itemsPerPage>\n    \
+         <opensearch:Query role=\"request\" searchTerms=\"{0}\" startIndex=\"{3}\" count=\"{4}\"/>\n",
+        escape(pattern),
+        urlencoding::encode(pattern),
+        results.total,
+        start + 1,
+        page_length
+    ));
+    for (book_name, book_title, hit) in &results.hits {
+        xml.push_str(&format!(
+            "    <item>\n      <title>{}</title>\n      <link>{}</link>\n      \
+             <description></description>\n      <book>\n        <title>{}</title>\n      </book>\n    </item>\n",
+            escape(&hit.title),
+            escape(&content_link(book_name, &hit.path)),
+            escape(book_title)
+        ));
+    }
+    xml.push_str("  </channel>\n</rss>\n");
+    xml
+}
+
+/// Per-book counts linking to a search of that book alone, and the sampled
+/// content types.
+fn facets_html(pattern: &str, facets: &Facets) -> String {
+    let mut html = String::from("<nav class=\"facets\">\n");
+    if facets.archive.len() > 1 {
+        html.push_str("<ul class=\"books\">\n");
+        for (book_name, count) in &facets.archive {
+            html.push_str(&format!(
+                "<li><a href=\"/search?books.name={}&amp;pattern={}\">{}</a> ({})</li>\n",
+                urlencoding::encode(book_name),
+                urlencoding::encode(pattern),
+                escape(book_name),
+                count
+            ));
+        }
+        html.push_str("</ul>\n");
+    }
+    html.push_str("<ul class=\"mimetypes\">\n");
+    for (mimetype, count) in &facets.mimetype {
+        html.push_str(&format!("<li>{} ({})</li>\n", escape(mimetype), count));
+    }
+    html.push_str("</ul>\n</nav>\n");
+    html
+}
+
+fn search_html(pattern: &str, start: u32, results: &KiwixSearchResults) -> String {
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Search: {0}</title></head><body>\n\
+         <h1>Results {1}-{2} of {3} for \"{0}\"</h1>\n",
+        escape(pattern),
+        start + 1,
+        start as usize + results.hits.len(),
+        results.total
+    );
+    if let Some(facets) = &results.facets {
+        html.push_str(&facets_html(pattern, facets));
+    }
+    html.push_str("<ul>\n");
+    for (book_name, book_title, hit) in &results.hits {
+        html.push_str(&format!(
+            "<li><a href=\"{}\">{}</a> <cite>{}</cite></li>\n",
+            escape(&content_link(book_name, &hit.path)),
+            escape(&hit.title),
+            escape(book_title)
+        ));
+    }
+    html.push_str("</ul>\n</body></html>\n");
+    html
+}
+
+#[get("/search", wrap = "crate::load_shed::Shed::heavy()")]
+async fn kiwix_search(
+    req: HttpRequest,
+    params: web::Query<Vec<(String, String)>>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let Some(pattern) = param(&params, "pattern").map(|p| p.to_string()) else {
+        return HttpResponse::BadRequest().body("No query provided.");
+    };
+    let page_length = param(&params, "pageLength")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_PAGE_LENGTH)
+        .clamp(1, MAX_PAGE_LENGTH);
+    let Some(start) = search_start(param(&params, "start"), page_length) else {
+        return HttpResponse::BadRequest().body("start is out of range.");
+    };
+    let as_xml = param(&params, "format") == Some("xml");
+    let selectors = book_selectors(&params);
+    let wanted_tags = tags::wanted(
+        params_named(&params, "books.filter.tag")
+            .iter()
+            .map(String::as_str),
+    );
+    let archives = library_archives(&state);
+    let blocklists = library_blocklists(&state, &archives);
+    let library_dir = state.library_dir();
+
+    let client = search_queue::client_key(&req, &state);
+    let permit = match state
+        .search_queue
+        .acquire(&client, state.search_limits())
+        .await
+    {
+        Ok(permit) => permit,
+        Err(e) => {
+            return HttpResponse::ServiceUnavailable()
+                .insert_header(("Retry-After", load_shed::RETRY_AFTER_SECS.to_string()))
+                .body(e.to_string());
+        }
+    };
+    let query = pattern.clone();
+    let leases = state.leases.clone();
+    let book_cache = state.kiwix_books.clone();
+    let result = web::block(move || {
+        let archives = if wanted_tags.is_empty() {
+            archives
+        } else {
+            tags::filter(archives, &Manifest::load(&library_dir)?, &wanted_tags)
+        };
+        let books = resolve_books(&book_cache, archives, &selectors);
+        if books.is_empty() {
+            return Err(anyhow!("No such book"));
+        }
+        let _leases: Vec<_> = books.iter().map(|b| leases.acquire(&b.path)).collect();
+        search_books(&books, &query, start, page_length, &blocklists, !as_xml)
+    })
+    .await;
+    drop(permit);
+
+    match result {
+        Ok(Ok(results)) if as_xml => HttpResponse::Ok()
+            .content_type("application/rss+xml; charset=utf-8")
+            .body(search_rss(&pattern, start, page_length, &results)),
+        Ok(Ok(results)) => HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .body(search_html(&pattern, start, &results)),
+        Ok(Err(e)) => HttpResponse::NotFound().body(e.to_string()),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Index of the first title-ordered entry whose title is not lower than `prefix`.
+fn title_lower_bound(zim: &Archive, prefix: &str) -> u32 {
+    let (mut lo, mut hi) = (0, zim.get_entrycount());
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        match zim.get_entry_bytitle_index(mid) {
+            Ok(entry) if entry.get_title().as_str() < prefix => lo = mid + 1,
+            _ => hi = mid,
+        }
+    }
+    lo
+}
+
+/// Titles starting with `term`, as typed and with its first letter
+/// capitalized, in title order.
+pub(crate) fn suggest_titles(zim: &Archive, term: &str, count: u32) -> Vec<ArticleSummary> {
+    let mut capitalized = term.chars();
+    let capitalized = match capitalized.next() {
+        Some(first) => first.to_uppercase().collect::<String>() + capitalized.as_str(),
+        None => String::new(),
+    };
+    let mut prefixes = vec![term.to_string()];
+    if capitalized != term {
+        prefixes.push(capitalized);
+    }
+
+    let total = zim.get_entrycount();
+    let mut suggestions: Vec<ArticleSummary> = Vec::new();
+    for prefix in prefixes {
+        let mut idx = title_lower_bound(zim, &prefix);
+        while idx < total && suggestions.len() < count as usize {
+            let Ok(entry) = zim.get_entry_bytitle_index(idx) else {
+                break;
+            };
+            let title = entry.get_title();
+            if !title.starts_with(&prefix) {
+                break;
+            }
+            if !suggestions.iter().any(|s| s.title == title) {
+                suggestions.push(ArticleSummary {
+                    title,
+                    path: entry.get_path(),
+                    alternate_titles: Vec::new(),
+                });
+            }
+            idx += 1;
+        }
+    }
+    suggestions
+}
+
+/// Featured titles, then the most viewed ones (given as paths), keeping
+/// those starting with `term`.
+fn default_suggestions(
+    zim: &Archive,
+    term: &str,
+    featured: &[String],
+    popular: &[String],
+    count: u32,
+) -> Vec<ArticleSummary> {
+    let prefix = term.to_lowercase();
+    let featured = featured
+        .iter()
+        .filter_map(|title| zim.get_entry_bytitle_str(title).ok());
+    let popular = popular
+        .iter()
+        .filter_map(|path| zim.get_entry_bypath_str(path).ok());
+
+    let mut suggestions: Vec<ArticleSummary> = Vec::new();
+    for entry in featured.chain(popular) {
+        if suggestions.len() >= count as usize {
+            break;
+        }
+        let title = entry.get_title();
+        if title.to_lowercase().starts_with(&prefix)
+            && !suggestions.iter().any(|s| s.title == title)
+        {
+            suggestions.push(ArticleSummary {
+                title,
+                path: entry.get_path(),
+                alternate_titles: Vec::new(),
+            });
+        }
+    }
+    suggestions
+}
+
+#[derive(Serialize)]
+struct KiwixSuggestion {
+    value: String,
+    label: String,
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+}
+
+#[get("/suggest")]
+async fn kiwix_suggest(
+    params: web::Query<Vec<(String, String)>>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let term = param(&params, "term").unwrap_or("").to_string();
+    let count = param(&params, "count")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_SUGGESTION_COUNT)
+        .clamp(1, MAX_PAGE_LENGTH);
+    let selectors = book_selectors(&params);
+    let archives = library_archives(&state);
+    let blocklists = library_blocklists(&state, &archives);
+    let featured_titles = state.config.read().unwrap().featured_titles.clone();
+    let popular_titles = state.popular_titles.clone();
+
+    let query = term.clone();
+    let leases = state.leases.clone();
+    let book_cache = state.kiwix_books.clone();
+    let result = web::block(move || {
+        let book = resolve_books(&book_cache, archives, &selectors)
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("No such book"))?;
+        let _lease = leases.acquire(&book.path);
+        let zim = Archive::new(book.path.to_str().unwrap())
+            .map_err(|e| anyhow!("Failed to open archive: {:?}", e))?;
+        let blocklist = blocklists.get(&book.id).cloned().unwrap_or_default();
+
+        let mut titles = Vec::new();
+        if query.chars().count() <= MAX_DEFAULT_TERM_CHARS {
+            let featured = featured_titles.get(&book.id).cloned().unwrap_or_default();
+            let popular = popular_titles.top(&book.id, POPULAR_CANDIDATES);
+            titles = default_suggestions(&zim, &query, &featured, &popular, count);
+        }
+        if !query.is_empty() && titles.len() < count as usize {
+            for suggestion in suggest_titles(&zim, &query, count) {
+                if titles.len() < count as usize
+                    && !titles.iter().any(|s| s.title == suggestion.title)
+                {
+                    titles.push(suggestion);
+                }
+            }
+        }
+        Ok::<_, anyhow::Error>(
+            titles
+                .into_iter()
+                .filter(|s| !blocklist.is_blocked(&s.title, &s.path))
+                .collect::<Vec<_>>(),
+        )
+    })
+    .await;
+
+    match result {
+        Ok(Ok(titles)) => {
+            let mut suggestions: Vec<KiwixSuggestion> = titles
+                .into_iter()
+                .map(|s| KiwixSuggestion {
+                    label: escape(&s.title),
+                    value: s.title,
+                    kind: "path",
+                    path: Some(s.path),
+                })
+                .collect();
+            if !term.is_empty() {
+                suggestions.push(KiwixSuggestion {
+                    value: format!("{} ", term),
+                    label: format!("containing '{}'...", escape(&term)),
+                    kind: "pattern",
+                    path: None,
+                });
+            }
+            HttpResponse::Ok().json(suggestions)
+        }
+        Ok(Err(e)) => HttpResponse::NotFound().body(e.to_string()),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+enum ContentResponse {
+    Data {
+        mimetype: String,
+        data: Vec<u8>,
+        /// Set when `data` is compressed for the client.
+        encoding: Option<Encoding>,
+    },
+    Redirect(String),
+    Blocked,
+}
+
+/// Compression settings for one `/content` request: the negotiated encoding
+/// and where compressed bodies are kept.
+struct Compression {
+    encoding: Encoding,
+    config: BodyCacheConfig,
+    cache: BodyCache,
+}
+
+/// `link_book` is how redirects name the book, so UUID-addressed content
+/// keeps pointing at UUID URLs.
+fn read_content(
+    book: &KiwixBook,
+    link_book: &str,
+    path: &str,
+    blocklist: &Blocklist,
+    compression: Option<&Compression>,
+) -> Result<ContentResponse> {
+    let zim = Archive::new(book.path.to_str().unwrap())
+        .map_err(|e| anyhow!("Failed to open archive: {:?}", e))?;
+    if path.is_empty() {
+        let main = zim
+            .get_main_entry()
+            .map_err(|e| anyhow!("No main page: {:?}", e))?;
+        let target = main
+            .get_item(true)
+            .map_err(|e| anyhow!("Failed to resolve main page: {:?}", e))?;
+        return Ok(ContentResponse::Redirect(content_link(
+            link_book,
+            &target.get_path(),
+        )));
+    }
+
+    let entry = zim
+        .get_entry_bypath_str(path)
+        .map_err(|e| anyhow!("Entry not found: {:?}", e))?;
+    if blocklist.is_blocked(&entry.get_title(), &entry.get_path()) {
+        return Ok(ContentResponse::Blocked);
+    }
+    if entry.is_redirect() {
+        let target = entry
+            .get_item(true)
+            .map_err(|e| anyhow!("Failed to resolve redirect: {:?}", e))?;
+        return Ok(ContentResponse::Redirect(content_link(
+            link_book,
+            &target.get_path(),
+        )));
+    }
+    let item = entry
+        .get_item(false)
+        .map_err(|e| anyhow!("Failed to read entry: {:?}", e))?;
+    let mut mimetype = item
+        .get_mimetype()
+        .unwrap_or_else(|_| "application/octet-stream".to_string());
+    // Text is always sent as UTF-8, transcoded below if stored otherwise.
+    let is_text = charset::is_text(&mimetype);
+    let declared_mimetype = mimetype.clone();
+    if is_text {
+        mimetype = charset::utf8_mimetype(&mimetype);
+    }
+    let compression = compression.filter(|c| {
+        body_cache::is_compressible(&mimetype) && item.get_size() >= c.config.min_bytes
+    });
+    let uuid = zim.get_uuid().to_string();
+    let entry_path = item.get_path();
+
+    // A cached body skips reading (and decompressing) the cluster entirely.
+    if let Some(c) = compression {
+        if let Some(data) = c.cache.get(&c.config, &uuid, &entry_path, c.encoding) {
+            return Ok(ContentResponse::Data {
+                mimetype,
+                data,
+                encoding: Some(c.encoding),
+            });
+        }
+    }
+
+    let blob = item
+        .get_data()
+        .map_err(|e| anyhow!("Failed to read entry data: {:?}", e))?;
+    let data = match is_text.then(|| charset::decode(blob.data(), &declared_mimetype)) {
+        Some(Cow::Owned(text)) => text.into_bytes(),
+        _ => blob.data().to_vec(),
+    };
+    match compression {
+        Some(c) => Ok(ContentResponse::Data {
+            data: c
+                .cache
+                .insert(&c.config, &uuid, &entry_path, c.encoding, &data)?,
+            mimetype,
+            encoding: Some(c.encoding),
+        }),
+        None => Ok(ContentResponse::Data {
+            mimetype,
+            data,
+            encoding: None,
+        }),
+    }
+}
+
+/// `Cache-Control` of content addressed by archive UUID. The archive's bytes
+/// never change, but a blocklist can be edited at any time and text is
+/// transcoded by this server, so only untouched bytes are cached for good.
+fn uuid_cache_control(blocklist: &Blocklist, mimetype: &str) -> &'static str {
+    if !blocklist.is_empty() {
+        "private, max-age=300"
+    } else if charset::is_text(mimetype) {
+        "public, max-age=86400"
+    } else {
+        "public, max-age=31536000, immutable"
+    }
+}
+
+#[get("/content/{book}/{path:.*}")]
+async fn kiwix_content(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let (selector, entry_path) = path.into_inner();
+    let archives = library_archives(&state);
+    let blocklists = library_blocklists(&state, &archives);
+    let accept_encoding = req
+        .headers()
+        .get("Accept-Encoding")
+        .and_then(|v| v.to_str().ok());
+    let compression = state
+        .config
+        .read()
+        .unwrap()
+        .body_cache
+        .clone()
+        .zip(body_cache::negotiate(accept_encoding))
+        .map(|(config, encoding)| Compression {
+            encoding,
+            config,
+            cache: state.body_cache.clone(),
+        });
+    let popular_titles = state.popular_titles.clone();
+    let leases = state.leases.clone();
+    let book_cache = state.kiwix_books.clone();
+
+    let result = web::block(move || {
+        let book = resolve_books(&book_cache, archives, std::slice::from_ref(&selector))
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("No such book"))?;
+        let _lease = leases.acquire(&book.path);
+        let blocklist = blocklists.get(&book.id).cloned().unwrap_or_default();
+        let by_uuid = selector == book.uuid;
+        let archive_id = book.id.clone();
+        let link_book = if by_uuid { &book.uuid } else { &book.name };
+        read_content(
+            &book,
+            link_book,
+            &entry_path,
+            &blocklist,
+            compression.as_ref(),
+        )
+        .inspect(|content| {
+            if let ContentResponse::Data { mimetype, .. } = content {
+                if mimetype.starts_with("text/html") {
+                    popular_titles.record(&archive_id, &entry_path);
+                }
+            }
+        })
+        .map(|content| {
+            let cache_control = match &content {
+                ContentResponse::Data { mimetype, .. } if by_uuid => {
+                    Some(uuid_cache_control(&blocklist, mimetype))
+                }
+                _ => None,
+            };
+            (content, cache_control)
+        })
+    })
+    .await;
+
+    match result {
+        Ok(Ok((
+            ContentResponse::Data {
+                mimetype,
+                data,
+                encoding,
+            },
+            cache_control,
+        ))) => {
+            let mut response = HttpResponse::Ok();
+            response.content_type(mimetype);
+            if let Some(cache_control) = cache_control {
+                response.insert_header(("Cache-Control", cache_control));
+            }
+            if let Some(encoding) = encoding {
+                response
+                    .insert_header(("Content-Encoding", encoding.header_value()))
+                    .insert_header(("Vary", "Accept-Encoding"));
+            }
+            response.body(data)
+        }
+        Ok(Ok((ContentResponse::Redirect(location), _))) => HttpResponse::Found()
+            .insert_header(("Location", location))
+            .finish(),
+        Ok(Ok((ContentResponse::Blocked, _))) => {
+            HttpResponse::Forbidden().body(blocklist::BLOCKED_MESSAGE)
+        }
+        Ok(Err(e)) => HttpResponse::NotFound().body(e.to_string()),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_start_stays_addressable() {
+        assert_eq!(search_start(None, 25), Some(0));
+        assert_eq!(search_start(Some("1"), 25), Some(0));
+        assert_eq!(search_start(Some("26"), 25), Some(25));
+        assert_eq!(search_start(Some("abc"), 25), Some(0));
+        assert_eq!(search_start(Some("3000000000"), 25), None);
+        assert_eq!(search_start(Some("99999999999"), 25), None);
+        let last = (i32::MAX as u64 - 25 + 1).to_string();
+        assert!(search_start(Some(&last), 25).is_some());
+    }
+
+    fn cached(fingerprint: Fingerprint) -> CachedBook {
+        CachedBook {
+            fingerprint,
+            uuid: String::new(),
+            name: String::new(),
+            title: String::new(),
+        }
+    }
+
+    #[test]
+    fn book_cache_forgets_archives_leaving_the_library() {
+        let cache = BookCache::default();
+        let kept = ("a".to_string(), PathBuf::from("/library/a.zim"));
+        let gone = ("b".to_string(), PathBuf::from("/library/b.zim"));
+        {
+            let mut books = cache.books.lock().unwrap();
+            books.insert(kept.clone(), cached((SystemTime::UNIX_EPOCH, 1)));
+            books.insert(gone.clone(), cached((SystemTime::UNIX_EPOCH, 1)));
+        }
+        cache.retain(std::slice::from_ref(&kept));
+        let books = cache.books.lock().unwrap();
+        assert!(books.contains_key(&kept));
+        assert!(!books.contains_key(&gone));
+    }
+
+    #[test]
+    fn uuid_content_is_immutable_only_when_served_untouched() {
+        let none = Blocklist::default();
+        assert_eq!(
+            uuid_cache_control(&none, "image/png"),
+            "public, max-age=31536000, immutable"
+        );
+        assert_eq!(
+            uuid_cache_control(&none, "text/html; charset=utf-8"),
+            "public, max-age=86400"
+        );
+        let blocklist = Blocklist {
+            title_patterns: vec!["*gambling*".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            uuid_cache_control(&blocklist, "image/png"),
+            "private, max-age=300"
+        );
+    }
+
+    #[test]
+    fn fingerprint_changes_when_the_file_is_replaced() {
+        let path = std::env::temp_dir().join(format!("kiwix-test-{}.zim", uuid::Uuid::new_v4()));
+        fs::write(&path, b"first").unwrap();
+        let before = fingerprint(&path).unwrap();
+        fs::write(&path, b"replaced archive").unwrap();
+        assert_ne!(fingerprint(&path), Some(before));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(fingerprint(&path), None);
+    }
+}
@@ -0,0 +1,64 @@
+//! Per-article language detection, for multilingual archives whose
+//! `Language` metadata only says `mul`.
+
+use crate::AppState;
+use crate::article_html;
+use actix_web::{HttpResponse, Responder, get, web};
+use serde::Serialize;
+
+/// Detection only looks at the start of long articles; more text does not
+/// change the answer but costs time.
+const MAX_SAMPLE_CHARS: usize = 10_000;
+
+#[derive(Serialize)]
+struct DetectedLanguage {
+    /// ISO 639-3 code, as used by ZIM `Language` metadata.
+    code: &'static str,
+    name: &'static str,
+    script: String,
+    confidence: f64,
+    reliable: bool,
+}
+
+#[derive(Serialize)]
+struct LanguageResponse {
+    title: String,
+    path: String,
+    /// `None` when the article has too little text to tell.
+    language: Option<DetectedLanguage>,
+}
+
+fn detect(text: &str) -> Option<DetectedLanguage> {
+    let sample: String = text.chars().take(MAX_SAMPLE_CHARS).collect();
+    let info = whatlang::detect(&sample)?;
+    Some(DetectedLanguage {
+        code: info.lang().code(),
+        name: info.lang().eng_name(),
+        script: info.script().name().to_string(),
+        confidence: info.confidence(),
+        reliable: info.is_reliable(),
+    })
+}
+
+#[get(
+    "/archives/{id}/language/{path:.*}",
+    wrap = "crate::load_shed::Shed::heavy()"
+)]
+async fn article_language(
+    path: web::Path<(String, String)>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let (id, entry_path) = path.into_inner();
+    let result = article_html::with_article(&state, &id, entry_path, |title, path, html| {
+        LanguageResponse {
+            title: title.to_string(),
+            path: path.to_string(),
+            language: detect(&article_html::visible_text(html)),
+        }
+    })
+    .await;
+    match result {
+        Ok(language) => HttpResponse::Ok().json(language),
+        Err(response) => response,
+    }
+}
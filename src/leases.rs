@@ -0,0 +1,64 @@
+//! Reference counts of the archives requests and jobs are reading, so an
+//! archive is only deleted once nobody has it open.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Default)]
+pub struct ArchiveLeases {
+    counts: Arc<Mutex<HashMap<PathBuf, usize>>>,
+}
+
+/// Held while an archive is being read; released on drop.
+pub struct ArchiveLease {
+    leases: ArchiveLeases,
+    path: PathBuf,
+}
+
+impl Drop for ArchiveLease {
+    fn drop(&mut self) {
+        let mut counts = self.leases.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.path) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.path);
+            }
+        }
+    }
+}
+
+impl ArchiveLeases {
+    pub fn acquire(&self, path: &Path) -> ArchiveLease {
+        *self
+            .counts
+            .lock()
+            .unwrap()
+            .entry(path.to_path_buf())
+            .or_default() += 1;
+        ArchiveLease {
+            leases: self.clone(),
+            path: path.to_path_buf(),
+        }
+    }
+
+    pub fn in_use(&self, path: &Path) -> usize {
+        self.counts.lock().unwrap().get(path).copied().unwrap_or(0)
+    }
+
+    /// Blocks until nobody holds `path` or `timeout` passes; returns whether
+    /// it was released.
+    pub fn wait_released(&self, path: &Path, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.in_use(path) > 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        true
+    }
+}
@@ -0,0 +1,168 @@
+//! Persistent list of archives registered from outside the library directory
+//! (e.g. imported from a Kiwix `library.xml`), stored as `manifest.json` in the
+//! library directory. Uploaded files are found by scanning and are not listed.
+//! The manifest also remembers the last opened archive, reopened at startup,
+//! and the tags given to archives.
+//! Changes go through `Manifest::update`, which holds a file lock so instances
+//! sharing the library directory never interleave their writes.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+/// Locked while shared library files are read and rewritten.
+const LOCK_FILE: &str = "library.lock";
+
+/// Takes the exclusive library lock, held until the returned file is dropped.
+pub fn lock_library(library_dir: &Path) -> Result<File> {
+    fs::create_dir_all(library_dir)?;
+    let path = library_dir.join(LOCK_FILE);
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    lock.lock()
+        .with_context(|| format!("Failed to lock {}", path.display()))?;
+    Ok(lock)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Book {
+    pub id: String,
+    pub path: PathBuf,
+    /// Where the archive can be downloaded from, as given by `library.xml`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Manifest {
+    #[serde(default)]
+    pub books: Vec<Book>,
+    /// The archive that was active when the server last ran.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active: Option<PathBuf>,
+    /// Tags assigned to archives, by archive id.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl Manifest {
+    pub fn load(library_dir: &Path) -> Result<Manifest> {
+        let path = library_dir.join(MANIFEST_FILE);
+        if !path.exists() {
+            return Ok(Manifest::default());
+        }
+        let raw = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        serde_json::from_str(&raw).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    /// Writes the manifest through a temporary file so a crash never leaves a
+    /// truncated manifest behind.
+    pub fn save(&self, library_dir: &Path) -> Result<()> {
+        fs::create_dir_all(library_dir)?;
+        let path = library_dir.join(MANIFEST_FILE);
+        let tmp_path = library_dir.join(format!("{}.tmp", MANIFEST_FILE));
+        fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    /// Loads the manifest, applies `change` and saves it if `change` returns
+    /// true, all under the library lock.
+    pub fn update<F>(library_dir: &Path, change: F) -> Result<()>
+    where
+        F: FnOnce(&mut Manifest) -> Result<bool>,
+    {
+        let _lock = lock_library(library_dir)?;
+        let mut manifest = Manifest::load(library_dir)?;
+        if change(&mut manifest)? {
+            manifest.save(library_dir)?;
+        }
+        Ok(())
+    }
+
+    /// Drops the registration and tags of `id`, returning whether there
+    /// were any.
+    pub fn remove(&mut self, id: &str) -> bool {
+        let before = self.books.len();
+        self.books.retain(|b| b.id != id);
+        let had_tags = self.tags.remove(id).is_some();
+        self.books.len() != before || had_tags
+    }
+
+    /// Adds `book`, replacing any earlier registration with the same id.
+    pub fn upsert(&mut self, book: Book) {
+        match self.books.iter_mut().find(|b| b.id == book.id) {
+            Some(existing) => *existing = book,
+            None => self.books.push(book),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("library-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn remove_drops_the_book_and_its_tags() {
+        let mut manifest = Manifest::default();
+        manifest.upsert(Book {
+            id: "wiki".to_string(),
+            path: PathBuf::from("/books/wiki.zim"),
+            url: None,
+        });
+        manifest
+            .tags
+            .insert("wiki".to_string(), BTreeSet::from(["kids".to_string()]));
+        assert!(manifest.remove("wiki"));
+        assert!(manifest.books.is_empty() && manifest.tags.is_empty());
+        assert!(!manifest.remove("wiki"));
+    }
+
+    #[test]
+    fn concurrent_updates_are_all_kept() {
+        let dir = temp_dir();
+        let threads: Vec<_> = (0..8)
+            .map(|n| {
+                let dir = dir.clone();
+                std::thread::spawn(move || {
+                    Manifest::update(&dir, |manifest| {
+                        manifest.upsert(Book {
+                            id: n.to_string(),
+                            path: PathBuf::from(format!("/books/{}.zim", n)),
+                            url: None,
+                        });
+                        Ok(true)
+                    })
+                    .unwrap();
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(Manifest::load(&dir).unwrap().books.len(), 8);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unchanged_manifests_are_not_written() {
+        let dir = temp_dir();
+        Manifest::update(&dir, |_| Ok(false)).unwrap();
+        assert!(!dir.join(MANIFEST_FILE).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
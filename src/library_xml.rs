@@ -0,0 +1,312 @@
+//! Interoperability with Kiwix's `library.xml`: bulk registration of books
+//! from an existing file and an export describing this server's library, in
+//! the format written by `kiwix-manage`.
+
+use crate::AppState;
+use crate::cluster::{self, Change};
+use crate::library::{Book, Manifest};
+use crate::tags;
+use actix_web::{HttpRequest, HttpResponse, Responder, get, post, web};
+use anyhow::{Context, Result, anyhow};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use log::{info, warn};
+use quick_xml::events::Event;
+use quick_xml::reader::Reader;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use zim_rs::archive::Archive;
+
+/// Metadata keys copied into `<book>` attributes, as `(metadata, attribute)`.
+const METADATA_ATTRIBUTES: &[(&str, &str)] = &[
+    ("Title", "title"),
+    ("Description", "description"),
+    ("Language", "language"),
+    ("Creator", "creator"),
+    ("Publisher", "publisher"),
+    ("Date", "date"),
+    ("Name", "name"),
+    ("Flavour", "flavour"),
+    ("Tags", "tags"),
+];
+
+#[derive(Deserialize)]
+struct ImportRequest {
+    /// Path of the `library.xml` on the server.
+    library_xml: PathBuf,
+}
+
+/// Reads every `<book>` element's attributes from a `library.xml`.
+fn parse_books(xml: &str) -> Result<Vec<HashMap<String, String>>> {
+    let mut reader = Reader::from_str(xml);
+    let mut books = Vec::new();
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) | Event::Empty(e) if e.name().as_ref() == b"book" => {
+                let mut attributes = HashMap::new();
+                for attr in e.attributes() {
+                    let attr = attr?;
+                    let key = String::from_utf8_lossy(attr.key.as_ref()).into_owned();
+                    attributes.insert(key, attr.unescape_value()?.into_owned());
+                }
+                books.push(attributes);
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(books)
+}
+
+/// Registers the books of `library_xml` whose archive exists on disk and
+/// whose id isn't taken yet. Paths in the file are relative to its own
+/// directory. Returns `(registered, skipped)`.
+fn import_library_xml(
+    library_xml: &Path,
+    library_dir: &Path,
+    known_ids: &HashSet<String>,
+    known_paths: &[PathBuf],
+) -> Result<(Vec<Book>, Vec<String>)> {
+    let xml = fs::read_to_string(library_xml)
+        .with_context(|| format!("Failed to read {}", library_xml.display()))?;
+    let base_dir = library_xml.parent().unwrap_or(Path::new("."));
+
+    let books = parse_books(&xml)?;
+    let mut registered = Vec::new();
+    let mut skipped = Vec::new();
+    Manifest::update(library_dir, |manifest| {
+        for attributes in books {
+            let (Some(id), Some(path)) = (attributes.get("id"), attributes.get("path")) else {
+                skipped.push(format!("{:?}: missing id or path", attributes.get("id")));
+                continue;
+            };
+            let taken = known_ids.contains(id)
+                || manifest.books.iter().any(|book| book.id == *id)
+                || registered.iter().any(|book: &Book| book.id == *id);
+            if taken {
+                skipped.push(format!("{}: id already in use", id));
+                continue;
+            }
+            let path = base_dir.join(path);
+            if !path.is_file() {
+                warn!("Skipping book {}: {} does not exist", id, path.display());
+                skipped.push(format!("{}: {} not found", id, path.display()));
+                continue;
+            }
+            let path = path.canonicalize().unwrap_or(path);
+            if known_paths.contains(&path) {
+                skipped.push(format!("{}: already in the library", id));
+                continue;
+            }
+
+            let book = Book {
+                id: id.clone(),
+                path,
+                url: attributes.get("url").cloned(),
+            };
+            manifest.upsert(book.clone());
+            registered.push(book);
+        }
+        Ok(!registered.is_empty())
+    })?;
+    Ok((registered, skipped))
+}
+
+fn book_element(id: &str, zim_path: &Path, assigned: Option<&BTreeSet<String>>) -> Result<String> {
+    let zim = Archive::new(zim_path.to_str().unwrap())
+        .map_err(|e| anyhow!("Failed to open archive {}: {:?}", id, e))?;
+    let path = zim_path
+        .canonicalize()
+        .unwrap_or_else(|_| zim_path.to_path_buf());
+    let size_kb = fs::metadata(zim_path).map(|m| m.len() / 1024).unwrap_or(0);
+
+    let mut attributes = vec![
+        ("id", zim.get_uuid().to_string()),
+        ("path", path.display().to_string()),
+    ];
+    for (key, attribute) in METADATA_ATTRIBUTES {
+        if let Ok(value) = zim.get_metadata(key) {
+            attributes.push((attribute, value));
+        }
+    }
+    // Tags assigned in the manifest join the archive's own.
+    if let Some(assigned) = assigned {
+        let existing = attributes.iter().position(|(key, _)| *key == "tags");
+        let mut tags: Vec<String> = existing
+            .map(|i| attributes.remove(i).1)
+            .unwrap_or_default()
+            .split(';')
+            .filter(|tag| !tag.is_empty())
+            .map(str::to_string)
+            .collect();
+        for tag in assigned {
+            if !tags
+                .iter()
+                .any(|t| tags::normalize(t).as_ref() == Some(tag))
+            {
+                tags.push(tag.clone());
+            }
+        }
+        attributes.push(("tags", tags.join(";")));
+    }
+    attributes.push(("articleCount", zim.get_articlecount().to_string()));
+    attributes.push(("mediaCount", zim.get_mediacount().to_string()));
+    attributes.push(("size", size_kb.to_string()));
+    if let Ok(item) = zim.get_illustration_item(48) {
+        if let (Ok(blob), Ok(mimetype)) = (item.get_data(), item.get_mimetype()) {
+            attributes.push(("favicon", BASE64.encode(blob.data())));
+            attributes.push(("faviconMimeType", mimetype));
+        }
+    }
+
+    let attributes: Vec<String> = attributes
+        .into_iter()
+        .map(|(key, value)| {
+            format!(
+                "{}=\"{}\"",
+                key,
+                html_escape::encode_double_quoted_attribute(&value)
+            )
+        })
+        .collect();
+    Ok(format!("  <book {} />\n", attributes.join(" ")))
+}
+
+fn export_library_xml(archives: Vec<(String, PathBuf)>, manifest: &Manifest) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<library version=\"20110515\">\n");
+    for (id, path) in archives {
+        match book_element(&id, &path, manifest.tags.get(&id)) {
+            Ok(element) => xml.push_str(&element),
+            Err(e) => warn!("Leaving {} out of library.xml: {:?}", id, e),
+        }
+    }
+    xml.push_str("</library>\n");
+    xml
+}
+
+#[post("/library/import")]
+async fn import_library(
+    req: HttpRequest,
+    body: web::Json<ImportRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    if !state.is_admin(&req) {
+        return HttpResponse::Unauthorized().json(json!({"error": "Invalid or missing token"}));
+    }
+    let library_xml = body.library_xml.clone();
+    let library_dir = state.library_dir();
+    let (known_ids, known_paths): (HashSet<String>, Vec<PathBuf>) = state
+        .file_cache
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(id, p)| (id.clone(), p.canonicalize().unwrap_or_else(|_| p.clone())))
+        .unzip();
+
+    let result = web::block(move || {
+        import_library_xml(&library_xml, &library_dir, &known_ids, &known_paths)
+    })
+    .await;
+    match result {
+        Ok(Ok((registered, skipped))) => {
+            let mut cache_guard = state.file_cache.lock().unwrap();
+            for book in &registered {
+                // An archive added meanwhile under the same id keeps it.
+                cache_guard
+                    .entry(book.id.clone())
+                    .or_insert_with(|| book.path.clone());
+            }
+            drop(cache_guard);
+            let changes = registered
+                .iter()
+                .map(|book| Change::Added(book.id.clone(), book.path.clone()))
+                .collect();
+            cluster::publish(&state, changes);
+            info!(
+                "Imported {} books from library.xml, skipped {}",
+                registered.len(),
+                skipped.len()
+            );
+            HttpResponse::Ok().json(json!({
+                "registered": registered.iter().map(|b| &b.id).collect::<Vec<_>>(),
+                "skipped": skipped,
+            }))
+        }
+        Ok(Err(e)) => HttpResponse::BadRequest().json(json!({"error": e.to_string()})),
+        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+    }
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    /// Only books carrying all these `;`-separated tags.
+    tag: Option<String>,
+}
+
+#[get("/library.xml")]
+async fn export_library(
+    query: web::Query<ExportQuery>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let wanted = tags::wanted(query.tag.as_deref());
+    let library_dir = state.library_dir();
+    let mut archives: Vec<(String, PathBuf)> = state
+        .file_cache
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(id, path)| (id.clone(), path.clone()))
+        .collect();
+    archives.sort();
+
+    let result = web::block(move || {
+        let manifest = Manifest::load(&library_dir)?;
+        let archives = tags::filter(archives, &manifest, &wanted);
+        Ok::<_, anyhow::Error>(export_library_xml(archives, &manifest))
+    })
+    .await;
+    match result {
+        Ok(Ok(xml)) => HttpResponse::Ok()
+            .content_type("application/xml; charset=utf-8")
+            .body(xml),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_in_use_are_not_replaced() {
+        let dir = std::env::temp_dir().join(format!("library-xml-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["a.zim", "b.zim", "c.zim"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        let xml = dir.join("library.xml");
+        fs::write(
+            &xml,
+            r#"<library>
+                <book id="wikipedia" path="a.zim" />
+                <book id="new" path="b.zim" />
+                <book id="new" path="c.zim" />
+            </library>"#,
+        )
+        .unwrap();
+
+        let known_ids = HashSet::from(["wikipedia".to_string()]);
+        let (registered, skipped) = import_library_xml(&xml, &dir, &known_ids, &[]).unwrap();
+        assert_eq!(
+            registered.iter().map(|b| b.id.as_str()).collect::<Vec<_>>(),
+            ["new"]
+        );
+        assert_eq!(skipped.len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,206 @@
+//! Broken internal link report. Scans every HTML entry of an archive for
+//! links and embedded resources that resolve inside the archive but have no
+//! entry there, the usual symptom of a scraper bug, and writes them out as
+//! JSON and CSV.
+
+use crate::AppState;
+use crate::article_html;
+use crate::charset;
+use crate::compare::csv_field;
+use crate::jobs::{self, JobHandle};
+use actix_web::{HttpResponse, Responder, post, web};
+use anyhow::{Result, anyhow};
+use scraper::{Html, Selector};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use zim_rs::archive::Archive;
+
+const JSON_REPORT: &str = "broken_links.json";
+const CSV_REPORT: &str = "broken_links.csv";
+/// Elements and the attribute holding their target.
+const LINK_ATTRIBUTES: &[(&str, &str)] = &[
+    ("a[href]", "href"),
+    ("img[src]", "src"),
+    ("link[href]", "href"),
+    ("script[src]", "src"),
+];
+/// Broken links listed individually; past this only the count grows.
+const MAX_REPORTED_LINKS: usize = 100_000;
+/// Link targets whose existence is remembered at once; the memo starts over
+/// when full, so huge archives don't hold every path they link to.
+const MAX_MEMOIZED_TARGETS: usize = 100_000;
+
+#[derive(Serialize)]
+struct BrokenLink {
+    /// Entry containing the link.
+    source: String,
+    /// Entry path the link resolves to.
+    target: String,
+    /// The link as written in the HTML.
+    href: String,
+}
+
+#[derive(Serialize)]
+struct LinkReport {
+    uuid: String,
+    scanned_articles: u64,
+    checked_links: u64,
+    broken_links: u64,
+    links: Vec<BrokenLink>,
+}
+
+/// Whether `target` exists, from `memo` or else from `check`.
+fn memoized(memo: &mut HashMap<String, bool>, target: &str, check: impl FnOnce() -> bool) -> bool {
+    if let Some(found) = memo.get(target) {
+        return *found;
+    }
+    if memo.len() >= MAX_MEMOIZED_TARGETS {
+        memo.clear();
+    }
+    let found = check();
+    memo.insert(target.to_string(), found);
+    found
+}
+
+fn check_archive(zim_path: &Path, job: &JobHandle) -> Result<LinkReport> {
+    let zim = Archive::new(zim_path.to_str().unwrap())
+        .map_err(|e| anyhow!("Failed to open archive: {:?}", e))?;
+    let total = zim.get_all_entrycount();
+    job.set_total(total as u64);
+    job.set_phase("scanning");
+    let selectors: Vec<(Selector, &str)> = LINK_ATTRIBUTES
+        .iter()
+        .map(|(selector, attr)| (Selector::parse(selector).unwrap(), *attr))
+        .collect();
+    // Targets repeat across articles (navigation, shared assets).
+    let mut exists: HashMap<String, bool> = HashMap::new();
+
+    let mut report = LinkReport {
+        uuid: zim.get_uuid().to_string(),
+        scanned_articles: 0,
+        checked_links: 0,
+        broken_links: 0,
+        links: Vec::new(),
+    };
+    for idx in 0..total {
+        job.set_processed(idx as u64);
+        let Ok(entry) = zim.get_entry_bypath_index(idx) else {
+            continue;
+        };
+        if entry.is_redirect() {
+            continue;
+        }
+        let Ok(item) = entry.get_item(false) else {
+            continue;
+        };
+        let mimetype = item.get_mimetype().unwrap_or_default();
+        if !mimetype.starts_with("text/html") {
+            continue;
+        }
+        let Ok(blob) = item.get_data() else {
+            continue;
+        };
+        let source = item.get_path();
+        let document = Html::parse_document(&charset::decode(blob.data(), &mimetype));
+        report.scanned_articles += 1;
+
+        for (selector, attr) in &selectors {
+            for element in document.select(selector) {
+                let Some(href) = element.value().attr(attr) else {
+                    continue;
+                };
+                let Some(target) = article_html::resolve_entry_path(&source, href) else {
+                    continue;
+                };
+                report.checked_links += 1;
+                if memoized(&mut exists, &target, || zim.has_entry_bypath(&target)) {
+                    continue;
+                }
+                report.broken_links += 1;
+                if report.links.len() < MAX_REPORTED_LINKS {
+                    report.links.push(BrokenLink {
+                        source: source.clone(),
+                        target,
+                        href: href.to_string(),
+                    });
+                }
+            }
+        }
+    }
+    job.set_processed(total as u64);
+    Ok(report)
+}
+
+fn write_reports(job: &JobHandle, report: &LinkReport) -> Result<()> {
+    job.set_phase("writing reports");
+    fs::write(
+        job.output_dir().join(JSON_REPORT),
+        serde_json::to_vec_pretty(report)?,
+    )?;
+    job.add_output_file(JSON_REPORT);
+
+    let mut csv = BufWriter::new(File::create(job.output_dir().join(CSV_REPORT))?);
+    writeln!(csv, "source,target,href")?;
+    for link in &report.links {
+        writeln!(
+            csv,
+            "{},{},{}",
+            csv_field(&link.source),
+            csv_field(&link.target),
+            csv_field(&link.href)
+        )?;
+    }
+    csv.flush()?;
+    job.add_output_file(CSV_REPORT);
+    Ok(())
+}
+
+#[post("/archives/{id}/linkcheck", wrap = "crate::load_shed::Shed::job()")]
+async fn check_links(path: web::Path<String>, state: web::Data<AppState>) -> impl Responder {
+    let Some(zim_path): Option<PathBuf> = state.archive_path(&path.into_inner()) else {
+        return HttpResponse::NotFound().json(json!({"error": "Archive not found"}));
+    };
+    let lease = state.leases.acquire(&zim_path);
+
+    match state
+        .jobs
+        .spawn("link_check", &jobs::jobs_dir(&state), move |job| {
+            let _lease = lease;
+            let report = check_archive(&zim_path, job)?;
+            write_reports(job, &report)
+        }) {
+        Ok(job_id) => HttpResponse::Accepted().json(json!({ "job_id": job_id })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memoized_checks_each_target_once() {
+        let mut memo = HashMap::new();
+        let mut checks = 0;
+        for _ in 0..3 {
+            memoized(&mut memo, "A/Page", || {
+                checks += 1;
+                true
+            });
+        }
+        assert_eq!(checks, 1);
+    }
+
+    #[test]
+    fn memoized_stays_bounded() {
+        let mut memo = HashMap::new();
+        for i in 0..MAX_MEMOIZED_TARGETS + 10 {
+            assert!(!memoized(&mut memo, &i.to_string(), || false));
+        }
+        assert!(memo.len() <= MAX_MEMOIZED_TARGETS);
+    }
+}
@@ -0,0 +1,200 @@
+//! Load shedding for expensive endpoints. Searches, per-article analysis and
+//! job submissions are turned away with `503` and a `Retry-After` hint once
+//! `max_heavy_requests` of them are in flight or `max_running_jobs` jobs are
+//! busy, so a small machine keeps answering cheap requests (pages, assets)
+//! instead of queueing everything into timeouts. Each expensive route opts in
+//! where it is declared by wrapping itself in [`Shed`].
+
+use crate::AppState;
+use crate::jobs::JobStatus;
+use actix_web::body::EitherBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform, forward_ready};
+use actix_web::{Error, HttpResponse, web};
+use futures_util::future::{LocalBoxFuture, Ready, ready};
+use serde_json::json;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Seconds clients are asked to wait before retrying a shed request.
+pub const RETRY_AFTER_SECS: u64 = 5;
+
+/// Counts the heavy requests currently being served.
+#[derive(Clone, Default)]
+pub struct LoadShedder {
+    in_flight: Arc<AtomicUsize>,
+}
+
+struct HeavyPermit(Arc<AtomicUsize>);
+
+impl Drop for HeavyPermit {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl LoadShedder {
+    /// A permit if fewer than `limit` heavy requests are running; 0 means
+    /// no limit.
+    fn try_acquire(&self, limit: usize) -> Option<HeavyPermit> {
+        let previous = self.in_flight.fetch_add(1, Ordering::Relaxed);
+        let permit = HeavyPermit(self.in_flight.clone());
+        (limit == 0 || previous < limit).then_some(permit)
+    }
+}
+
+/// `503` with a `Retry-After` header.
+pub fn overloaded(message: &str) -> HttpResponse {
+    HttpResponse::ServiceUnavailable()
+        .insert_header(("Retry-After", RETRY_AFTER_SECS.to_string()))
+        .json(json!({"error": message}))
+}
+
+/// What a shed route costs.
+#[derive(Clone, Copy)]
+enum Cost {
+    /// Holds a blocking thread for a noticeable time.
+    Heavy,
+    /// Starts a background job.
+    Job,
+}
+
+/// Middleware shedding the route it wraps under load, given where the route
+/// is declared: `#[post("/search", wrap = "load_shed::Shed::heavy()")]`.
+#[derive(Clone, Copy)]
+pub struct Shed(Cost);
+
+impl Shed {
+    /// For searches and per-article tools, counted against
+    /// `max_heavy_requests`.
+    pub fn heavy() -> Shed {
+        Shed(Cost::Heavy)
+    }
+
+    /// For routes starting a job, refused while `max_running_jobs` run.
+    pub fn job() -> Shed {
+        Shed(Cost::Job)
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Shed
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = ShedMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ShedMiddleware {
+            service,
+            cost: self.0,
+        }))
+    }
+}
+
+pub struct ShedMiddleware<S> {
+    service: S,
+    cost: Cost,
+}
+
+impl<S, B> Service<ServiceRequest> for ShedMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let Some(state) = req.app_data::<web::Data<AppState>>().cloned() else {
+            let fut = self.service.call(req);
+            return Box::pin(async move { Ok(fut.await?.map_into_left_body()) });
+        };
+        let (max_heavy, max_jobs) = {
+            let config = state.config.read().unwrap();
+            (config.max_heavy_requests, config.max_running_jobs)
+        };
+
+        let permit = match self.cost {
+            Cost::Job => {
+                let running = state
+                    .jobs
+                    .list()
+                    .iter()
+                    .filter(|job| job.status == JobStatus::Running)
+                    .count();
+                if max_jobs > 0 && running >= max_jobs {
+                    let response = overloaded("Too many jobs running, try again later");
+                    return Box::pin(async move {
+                        Ok(req.into_response(response).map_into_right_body())
+                    });
+                }
+                None
+            }
+            Cost::Heavy => match state.load_shedder.try_acquire(max_heavy) {
+                Some(permit) => Some(permit),
+                None => {
+                    let response = overloaded("Server is busy, try again later");
+                    return Box::pin(async move {
+                        Ok(req.into_response(response).map_into_right_body())
+                    });
+                }
+            },
+        };
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let response = fut.await;
+            drop(permit);
+            Ok(response?.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permits_are_limited_and_released() {
+        let shedder = LoadShedder::default();
+        let first = shedder.try_acquire(2);
+        let second = shedder.try_acquire(2);
+        assert!(first.is_some() && second.is_some());
+        assert!(shedder.try_acquire(2).is_none());
+        drop(first);
+        assert!(shedder.try_acquire(2).is_some());
+    }
+
+    #[test]
+    fn zero_limit_never_sheds() {
+        let shedder = LoadShedder::default();
+        let permits: Vec<_> = (0..100).map(|_| shedder.try_acquire(0)).collect();
+        assert!(permits.iter().all(Option::is_some));
+    }
+
+    #[actix_web::test]
+    async fn routes_without_state_pass_through() {
+        use actix_web::{App, test};
+
+        let app = test::init_service(
+            App::new().route(
+                "/search",
+                web::get()
+                    .to(|| async { HttpResponse::Ok().finish() })
+                    .wrap(Shed::heavy()),
+            ),
+        )
+        .await;
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri("/search").to_request()).await;
+        assert!(response.status().is_success());
+    }
+}
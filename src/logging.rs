@@ -0,0 +1,192 @@
+use crate::config::LogFileConfig;
+use chrono::Local;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Target used by `actix_web::middleware::Logger`, routed to the access log.
+const ACCESS_LOG_TARGET: &str = "actix_web::middleware::logger";
+
+/// Logger writing to the console and, when configured, to rotating
+/// `access.log` / `app.log` files. Both the level and the file settings can be
+/// changed at runtime.
+struct AppLogger {
+    files: Mutex<Option<LogFiles>>,
+}
+
+struct LogFiles {
+    access: RotatingFile,
+    app: RotatingFile,
+}
+
+static LOGGER: AppLogger = AppLogger {
+    files: Mutex::new(None),
+};
+
+impl Log for AppLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format!(
+            "{} [{}] {}",
+            Local::now().format("%Y-%m-%d %H:%M:%S"),
+            record.level(),
+            record.args()
+        );
+
+        let mut files_guard = self.files.lock().unwrap();
+        if let Some(files) = files_guard.as_mut() {
+            let sink = if record.target() == ACCESS_LOG_TARGET {
+                &mut files.access
+            } else {
+                &mut files.app
+            };
+            if let Err(e) = sink.write_line(&line) {
+                eprintln!("Failed to write log file {}: {}", sink.path.display(), e);
+            }
+            return;
+        }
+
+        match record.level() {
+            Level::Error | Level::Warn => eprintln!("{}", line),
+            _ => println!("{}", line),
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(files) = self.files.lock().unwrap().as_mut() {
+            let _ = files.access.file.flush();
+            let _ = files.app.file.flush();
+        }
+    }
+}
+
+pub fn init(level: LevelFilter) {
+    if log::set_logger(&LOGGER).is_err() {
+        eprintln!("Logger already initialized");
+    }
+    set_level(level);
+}
+
+pub fn set_level(level: LevelFilter) {
+    log::set_max_level(level);
+}
+
+/// Log files opened by [`open_files`] but not yet written to.
+pub struct LogOutput(Option<LogFiles>);
+
+/// Opens the rotating files under `config.dir`, without switching to them
+/// yet; `None` stands for the console.
+pub fn open_files(config: Option<&LogFileConfig>) -> io::Result<LogOutput> {
+    let files = match config {
+        Some(config) => {
+            fs::create_dir_all(&config.dir)?;
+            Some(LogFiles {
+                access: RotatingFile::open(config.dir.join("access.log"), config)?,
+                app: RotatingFile::open(config.dir.join("app.log"), config)?,
+            })
+        }
+        None => None,
+    };
+    Ok(LogOutput(files))
+}
+
+/// Switches log output to files opened by [`open_files`].
+pub fn install_files(output: LogOutput) {
+    *LOGGER.files.lock().unwrap() = output.0;
+}
+
+/// Switches log output to rotating files under `config.dir`, or back to the
+/// console when `config` is `None`.
+pub fn configure_files(config: Option<&LogFileConfig>) -> io::Result<()> {
+    install_files(open_files(config)?);
+    Ok(())
+}
+
+/// Log file rotated once it grows past `max_bytes` or gets older than
+/// `max_age`. Rotated files are kept as `name.1` (newest) to `name.N`.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened_at: SystemTime,
+    max_bytes: u64,
+    max_age: Option<Duration>,
+    retention: usize,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, config: &LogFileConfig) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        Ok(RotatingFile {
+            size: metadata.len(),
+            opened_at: metadata.created().unwrap_or_else(|_| SystemTime::now()),
+            file,
+            path,
+            max_bytes: config.max_bytes,
+            max_age: (config.max_age_secs > 0).then(|| Duration::from_secs(config.max_age_secs)),
+            retention: config.retention,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.needs_rotation(line.len() as u64 + 1) {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn needs_rotation(&self, incoming: u64) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        if self.max_bytes > 0 && self.size + incoming > self.max_bytes {
+            return true;
+        }
+        match self.max_age {
+            Some(max_age) => self.opened_at.elapsed().is_ok_and(|age| age >= max_age),
+            None => false,
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.retention == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(rotated_path(&self.path, self.retention));
+            for n in (1..self.retention).rev() {
+                let from = rotated_path(&self.path, n);
+                if from.exists() {
+                    fs::rename(&from, rotated_path(&self.path, n + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        self.opened_at = SystemTime::now();
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
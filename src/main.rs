@@ -1,10 +1,81 @@
+mod analytics;
+mod archive_tree;
+mod archives;
+mod article_html;
+mod blocklist;
+mod body_cache;
+mod categories;
+mod charset;
+mod cjk;
+mod cluster;
+mod collections;
+mod compare;
+mod config;
+mod derive;
+mod desktop;
+mod downloads;
+mod epub;
+mod facets;
+mod favicon;
+mod federation;
+#[cfg(all(target_os = "linux", feature = "fuse"))]
+mod fuse;
+mod grep;
+mod history;
+mod jobs;
+mod json_store;
+mod kiwix;
+mod language;
+mod leases;
+mod library;
+mod library_xml;
+mod linkcheck;
+mod load_shed;
+mod logging;
+mod navigation;
+mod opengraph;
+mod popular;
+mod preferences;
+mod preview;
+mod references;
+mod search_queue;
+mod session;
+mod subset;
+mod tags;
+mod tls;
+#[cfg(feature = "torrent")]
+mod torrent;
+mod translate;
+mod warc;
+mod watcher;
+mod webdav;
+mod zim_writer;
+
 use actix_files::NamedFile;
 use actix_multipart::Multipart;
-use actix_web::{App, HttpResponse, HttpServer, Responder, get, post, web};
+use actix_web::middleware::Logger;
+use actix_web::{App, HttpRequest, HttpResponse, HttpServer, Responder, get, post, web};
+use analytics::QueryAnalytics;
 use anyhow::{Result, anyhow};
 use async_stream::stream;
+use blocklist::Blocklist;
+use body_cache::BodyCache;
+use categories::CategoryIndex;
+use cluster::ClusterSync;
+use collections::Collections;
+use config::Config;
+use facets::Facets;
 use futures_util::StreamExt;
 use hex;
+use history::SearchHistory;
+use jobs::{JobHandle, JobRegistry};
+use leases::ArchiveLeases;
+use library::Manifest;
+use load_shed::LoadShedder;
+use log::{error, info, warn};
+use popular::PopularTitles;
+use preferences::SessionPreferences;
+use search_queue::{QueueLimits, SearchQueue};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use sha2::{Digest, Sha256};
@@ -13,27 +84,141 @@ use std::fs::{self, File};
 use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 use std::sync::{
-    Arc, Mutex,
+    Arc, Mutex, RwLock,
     atomic::{AtomicU64, Ordering},
 };
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tempfile::NamedTempFile;
 use tokio::time::sleep;
 use zim_rs::archive::Archive;
+use zim_rs::entry::Entry;
 use zim_rs::search::{Query, Searcher};
 
 #[derive(Clone)]
 struct AppState {
     processed_bytes: Arc<AtomicU64>,
+    /// Content-Length of the upload in flight, 0 when unknown.
+    upload_total_bytes: Arc<AtomicU64>,
     uploaded_files: Arc<Mutex<HashMap<String, PathBuf>>>,
     current_zim_path: Arc<Mutex<Option<PathBuf>>>,
     file_cache: Arc<Mutex<HashMap<String, PathBuf>>>,
+    config: Arc<RwLock<Config>>,
+    config_path: PathBuf,
+    jobs: JobRegistry,
+    search_queue: SearchQueue,
+    search_history: SearchHistory,
+    collections: Collections,
+    preferences: SessionPreferences,
+    analytics: QueryAnalytics,
+    body_cache: BodyCache,
+    popular_titles: PopularTitles,
+    categories: CategoryIndex,
+    kiwix_books: kiwix::BookCache,
+    load_shedder: LoadShedder,
+    leases: ArchiveLeases,
+    cluster: ClusterSync,
+    /// Id of the only archive served in `--kiosk` mode.
+    kiosk_archive: Option<String>,
+    /// `--read-only`: nothing a request does is stored, not even per session.
+    read_only: bool,
+}
+
+impl AppState {
+    fn library_dir(&self) -> PathBuf {
+        self.config.read().unwrap().library_dir.clone()
+    }
+
+    /// The library root `id` was found in, `None` for archives registered
+    /// from elsewhere through the manifest.
+    fn archive_root(&self, id: &str) -> Option<PathBuf> {
+        let path = self.archive_path(id)?;
+        let roots = self.config.read().unwrap().library_roots();
+        roots
+            .into_iter()
+            .find(|root| path.parent() == Some(root.as_path()))
+    }
+
+    fn search_limits(&self) -> QueueLimits {
+        let config = self.config.read().unwrap();
+        QueueLimits {
+            max_running: config.max_concurrent_searches,
+            max_queued: config.max_queued_searches,
+            timeout: Duration::from_secs(config.search_queue_timeout_secs),
+        }
+    }
+
+    fn archive_path(&self, id: &str) -> Option<PathBuf> {
+        self.file_cache.lock().unwrap().get(id).cloned()
+    }
+
+    fn archive_id_for_path(&self, path: &Path) -> Option<String> {
+        let archives: Vec<(String, PathBuf)> = self
+            .file_cache
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, path)| (id.clone(), path.clone()))
+            .collect();
+        if let Some((id, _)) = archives.iter().find(|(_, p)| p.as_path() == path) {
+            return Some(id.clone());
+        }
+        // The same file spelled differently (relative, with `..` or through a
+        // symlink) must still get its archive's blocklist.
+        let canonical = fs::canonicalize(path).ok()?;
+        archives
+            .into_iter()
+            .find(|(_, p)| fs::canonicalize(p).is_ok_and(|p| p == canonical))
+            .map(|(id, _)| id)
+    }
+
+    /// Drops what is kept about an archive that left the library or is being
+    /// replaced: its view counts, its category tree and every reference to its
+    /// file, so `/article`, `/current_file` and the upload list stop pointing
+    /// at it. Takes the locks one at a time, so callers must not hold
+    /// `file_cache`'s.
+    fn forget_archive(&self, id: &str, path: &Path) {
+        self.popular_titles.forget(id);
+        self.categories.forget(path);
+        {
+            let mut current = self.current_zim_path.lock().unwrap();
+            if current.as_deref() == Some(path) {
+                *current = None;
+            }
+        }
+        self.uploaded_files
+            .lock()
+            .unwrap()
+            .retain(|_, uploaded| uploaded != path);
+    }
+
+    /// Whether the request carries one of the configured admin tokens, or no
+    /// tokens are configured.
+    fn is_admin(&self, req: &HttpRequest) -> bool {
+        let authorization = req
+            .headers()
+            .get("Authorization")
+            .and_then(|v| v.to_str().ok());
+        self.config.read().unwrap().is_authorized(authorization)
+    }
+
+    /// The global blocklist merged with the one configured for `archive_id`.
+    fn blocklist_for(&self, archive_id: Option<&str>) -> Blocklist {
+        let config = self.config.read().unwrap();
+        let global = config.blocklists.get("*").cloned().unwrap_or_default();
+        match archive_id.and_then(|id| config.blocklists.get(id)) {
+            Some(own) => global.merged(own),
+            None => global,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 struct ZimResponse {
     message: String,
     file_metadata: AppMetadata,
+    /// Job warming a newly added archive, to follow with `/jobs/{id}/events`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warm_job: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -43,10 +228,36 @@ struct AppMetadata {
     article_count: u64,
 }
 
+const DEFAULT_SEARCH_PAGE_SIZE: u32 = 50;
+
+fn default_search_page() -> u32 {
+    1
+}
+
 #[derive(Deserialize)]
 struct SearchRequest {
     query: String,
     file_path: PathBuf,
+    #[serde(default = "default_search_page")]
+    page: u32,
+    /// Defaults to the session's `results_per_page` preference.
+    page_size: Option<u32>,
+    /// Whether to return facet counts; by default only on the first page.
+    facets: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct SearchResponse {
+    total_estimate: u64,
+    took_ms: u64,
+    /// Position the search entered the queue at, when it had to wait.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queue_position: Option<usize>,
+    page: u32,
+    page_size: u32,
+    results: Vec<ArticleSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    facets: Option<Facets>,
 }
 
 #[derive(Deserialize)]
@@ -57,61 +268,193 @@ struct BrowseRequest {
 #[derive(Serialize)]
 struct ArticleSummary {
     title: String,
+    path: String,
+    /// Titles of redirects that were folded into this result.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    alternate_titles: Vec<String>,
 }
 
-fn search_zim_file(zim_file_path: &Path, query: &str) -> Result<Vec<ArticleSummary>> {
-    println!(
-        "Searching ZIM file '{}' for query '{}'",
-        zim_file_path.display(),
-        query
-    );
-    let zim = Archive::new(zim_file_path.to_str().unwrap())
-        .map_err(|e| anyhow!("Failed to open archive: {:?}", e))?;
-    let mut searcher =
-        Searcher::new(&zim).map_err(|e| anyhow!("Failed to create searcher: {:?}", e))?;
+/// Folds redirect entries into the article they point to, so a page reached
+/// through several redirects is listed once under its own title. The first
+/// occurrence keeps its position.
+fn collapse_redirects(entries: Vec<Entry>, blocklist: &Blocklist) -> Vec<ArticleSummary> {
+    let mut results: Vec<ArticleSummary> = Vec::new();
+    for entry in entries {
+        let title = entry.get_title();
+        if blocklist.is_blocked(&title, &entry.get_path()) {
+            continue;
+        }
+        let (target_title, target_path) = if entry.is_redirect() {
+            match entry.get_item(true) {
+                Ok(item) => (item.get_title(), item.get_path()),
+                Err(e) => {
+                    warn!("Dangling redirect '{}': {:?}", title, e);
+                    continue;
+                }
+            }
+        } else {
+            (title.clone(), entry.get_path())
+        };
+        if blocklist.is_blocked(&target_title, &target_path) {
+            continue;
+        }
+
+        match results.iter_mut().find(|r| r.path == target_path) {
+            Some(existing) => {
+                if title != existing.title && !existing.alternate_titles.contains(&title) {
+                    existing.alternate_titles.push(title);
+                }
+            }
+            None => {
+                let mut alternate_titles = Vec::new();
+                if title != target_title {
+                    alternate_titles.push(title);
+                }
+                results.push(ArticleSummary {
+                    title: target_title,
+                    path: target_path,
+                    alternate_titles,
+                });
+            }
+        }
+    }
+    results
+}
+
+/// Runs a full-text query, retrying in lowercase when the original finds
+/// nothing and, for CJK queries on CJK archives, falling back to a bigram
+/// title search. Redirects are collapsed into their target article. Returns the
+/// estimated number of matches and the results
+/// `start..start + count`; `facets`, when given, are counted from the top hits.
+fn run_fulltext_search(
+    zim: &Archive,
+    query: &str,
+    start: u32,
+    count: u32,
+    blocklist: &Blocklist,
+    facets: Option<&mut Facets>,
+) -> Result<(u64, Vec<ArticleSummary>)> {
+    // libzim takes `i32` offsets.
+    if start
+        .checked_add(count)
+        .is_none_or(|end| end > i32::MAX as u32)
+    {
+        return Err(anyhow!("Search offset out of range"));
+    }
+    let cjk_fallback = cjk::contains_cjk(query) && cjk::is_cjk_archive(zim);
+    let mut searcher = match Searcher::new(zim) {
+        Ok(searcher) => searcher,
+        Err(e) if cjk_fallback => {
+            warn!("No full-text index ({:?}), using CJK title search", e);
+            let (total, entries) = cjk::title_search(zim, query, start, count);
+            if let Some(facets) = facets {
+                entries.iter().for_each(|e| facets.record(e, blocklist));
+            }
+            return Ok((total, collapse_redirects(entries, blocklist)));
+        }
+        Err(e) => return Err(anyhow!("Failed to create searcher: {:?}", e)),
+    };
 
     let query_obj = Query::new(query).map_err(|e| anyhow!("Invalid query: {:?}", e))?;
-    let search = searcher
+    let mut search = searcher
         .search(&query_obj)
         .map_err(|e| anyhow!("Search failed: {:?}", e))?;
-    let mut result_vec: Vec<_> = search
-        .get_results(0, 50)
-        .map_err(|e| anyhow!("Failed to get results: {:?}", e))?
-        .into_iter()
-        .collect();
+    let mut total_estimate = search.get_estimated_matches().unwrap_or(0).max(0) as u64;
 
-    if result_vec.is_empty() {
-        println!("No results found for '{}', trying lowercase search", query);
+    if total_estimate == 0 {
+        info!("No results found for '{}', trying lowercase search", query);
         let lower_query = query.to_lowercase();
         let query_obj = Query::new(&lower_query).map_err(|e| anyhow!("Invalid query: {:?}", e))?;
-        let search = searcher
+        search = searcher
             .search(&query_obj)
             .map_err(|e| anyhow!("Search failed: {:?}", e))?;
-        result_vec = search
-            .get_results(0, 50)
-            .map_err(|e| anyhow!("Failed to get results: {:?}", e))?
-            .into_iter()
-            .collect();
+        total_estimate = search.get_estimated_matches().unwrap_or(0).max(0) as u64;
+    }
+
+    if total_estimate == 0 && cjk_fallback {
+        info!(
+            "No results found for '{}', trying CJK bigram title search",
+            query
+        );
+        let (total, entries) = cjk::title_search(zim, query, start, count);
+        if let Some(facets) = facets {
+            entries.iter().for_each(|e| facets.record(e, blocklist));
+        }
+        return Ok((total, collapse_redirects(entries, blocklist)));
+    }
+
+    if let Some(facets) = facets {
+        let sample = search
+            .get_results(0, facets::SAMPLE_SIZE as i32)
+            .map_err(|e| anyhow!("Failed to get results: {:?}", e))?;
+        for entry in sample.iter().flatten() {
+            facets.record(entry, blocklist);
+        }
     }
 
-    let results: Vec<ArticleSummary> = result_vec
+    let entries: Vec<Entry> = search
+        .get_results(start as i32, count as i32)
+        .map_err(|e| anyhow!("Failed to get results: {:?}", e))?
         .into_iter()
         .filter_map(|r| match r {
-            Ok(entry) => Some(ArticleSummary {
-                title: entry.get_title(),
-            }),
+            Ok(entry) => Some(entry),
             Err(e) => {
-                eprintln!("Search entry error: {:?}", e);
+                warn!("Search entry error: {:?}", e);
                 None
             }
         })
         .collect();
+    Ok((total_estimate, collapse_redirects(entries, blocklist)))
+}
+
+/// Index of the first result on `page` (counted from 1), `None` when the page
+/// is 0 or lies beyond what the search engine can address.
+fn search_offset(page: u32, page_size: u32) -> Option<u32> {
+    let start = page.checked_sub(1)?.checked_mul(page_size)?;
+    (start.checked_add(page_size)? <= i32::MAX as u32).then_some(start)
+}
+
+fn search_zim_file(
+    zim_file_path: &Path,
+    query: &str,
+    page: u32,
+    page_size: u32,
+    blocklist: &Blocklist,
+    with_facets: bool,
+) -> Result<SearchResponse> {
+    info!(
+        "Searching ZIM file '{}' for query '{}'",
+        zim_file_path.display(),
+        query
+    );
+    let started = Instant::now();
+    let start = search_offset(page, page_size)
+        .ok_or_else(|| anyhow!("Page {} of size {} is out of range", page, page_size))?;
+
+    let zim = Archive::new(zim_file_path.to_str().unwrap())
+        .map_err(|e| anyhow!("Failed to open archive: {:?}", e))?;
+    let mut facets = with_facets.then(Facets::default);
+    let (total_estimate, results) =
+        run_fulltext_search(&zim, query, start, page_size, blocklist, facets.as_mut())?;
 
-    println!("Search returned {} results", results.len());
-    Ok(results)
+    info!(
+        "Search returned {} results (page {}, ~{} total)",
+        results.len(),
+        page,
+        total_estimate
+    );
+    Ok(SearchResponse {
+        total_estimate,
+        took_ms: started.elapsed().as_millis() as u64,
+        queue_position: None,
+        page,
+        page_size,
+        results,
+        facets,
+    })
 }
 
-fn get_all_articles(file_path: &Path) -> Result<Vec<ArticleSummary>> {
+fn get_all_articles(file_path: &Path, blocklist: &Blocklist) -> Result<Vec<ArticleSummary>> {
     let zim = Archive::new(file_path.to_str().unwrap())
         .map_err(|e| anyhow!("failed to open zim file: {:?}", e))?;
     let total = zim.get_articlecount();
@@ -122,7 +465,14 @@ fn get_all_articles(file_path: &Path) -> Result<Vec<ArticleSummary>> {
                 if let Ok(mimetype) = item.get_mimetype() {
                     if mimetype.starts_with("text/html") {
                         let title = entry.get_title();
-                        articles.push(ArticleSummary { title });
+                        let path = entry.get_path();
+                        if !blocklist.is_blocked(&title, &path) {
+                            articles.push(ArticleSummary {
+                                title,
+                                path,
+                                alternate_titles: Vec::new(),
+                            });
+                        }
                     }
                 }
             }
@@ -134,10 +484,30 @@ fn get_all_articles(file_path: &Path) -> Result<Vec<ArticleSummary>> {
 #[get("/progress")]
 async fn progress(state: web::Data<AppState>) -> impl Responder {
     let processed = state.processed_bytes.clone();
+    let total = state.upload_total_bytes.clone();
     let s = stream! {
+        let mut last_bytes = processed.load(Ordering::Relaxed);
+        let mut last_at = Instant::now();
+        let mut rate = 0.0;
         loop {
             let p = processed.load(Ordering::Relaxed);
-            let line = format!("data: {{\"processed_bytes\":{}}}\n\n", p);
+            let total = total.load(Ordering::Relaxed);
+            // Smoothed like job rates; a counter reset means a new upload.
+            let elapsed = last_at.elapsed().as_secs_f64();
+            if p < last_bytes {
+                rate = 0.0;
+            } else if elapsed > 0.0 {
+                rate = 0.7 * rate + 0.3 * ((p - last_bytes) as f64 / elapsed);
+            }
+            (last_bytes, last_at) = (p, Instant::now());
+            let eta_secs = (total > p && rate >= 1.0).then(|| ((total - p) as f64 / rate) as u64);
+            let payload = json!({
+                "processed_bytes": p,
+                "total_bytes": (total > 0).then_some(total),
+                "bytes_per_sec": rate as u64,
+                "eta_secs": eta_secs,
+            });
+            let line = format!("data: {}\n\n", payload);
             yield Ok::<_, actix_web::Error>(web::Bytes::from(line));
             sleep(Duration::from_millis(500)).await;
         }
@@ -161,11 +531,13 @@ async fn article(path: web::Path<String>, state: web::Data<AppState>) -> impl Re
         Err(_) => title_enc,
     };
 
-    let guard = state.current_zim_path.lock().unwrap();
-    let path = match &*guard {
+    let path = match &*state.current_zim_path.lock().unwrap() {
         Some(p) => p.clone(),
         None => return HttpResponse::BadRequest().body("No ZIM loaded"),
     };
+    let archive_id = state.archive_id_for_path(&path);
+    let blocklist = state.blocklist_for(archive_id.as_deref());
+    let _lease = state.leases.acquire(&path);
 
     let path_str = match path.to_str() {
         Some(s) => s,
@@ -175,10 +547,19 @@ async fn article(path: web::Path<String>, state: web::Data<AppState>) -> impl Re
     match Archive::new(path_str) {
         Ok(zim) => match zim.get_entry_bytitle_str(&title) {
             Ok(entry) => {
+                if blocklist.is_blocked(&entry.get_title(), &entry.get_path()) {
+                    return HttpResponse::Forbidden().body(blocklist::BLOCKED_MESSAGE);
+                }
                 if let Ok(item) = entry.get_item(true) {
+                    if blocklist.is_blocked(&item.get_title(), &item.get_path()) {
+                        return HttpResponse::Forbidden().body(blocklist::BLOCKED_MESSAGE);
+                    }
                     if let Ok(blob) = item.get_data() {
-                        let bytes = blob.data().as_ref();
-                        let content = String::from_utf8_lossy(bytes).into_owned();
+                        if let Some(id) = &archive_id {
+                            state.popular_titles.record(id, &item.get_path());
+                        }
+                        let mimetype = item.get_mimetype().unwrap_or_default();
+                        let content = charset::decode(blob.data(), &mimetype).into_owned();
                         return HttpResponse::Ok()
                             .content_type("text/html; charset=utf-8")
                             .body(content);
@@ -194,19 +575,34 @@ async fn article(path: web::Path<String>, state: web::Data<AppState>) -> impl Re
 
 #[post("/upload")]
 async fn upload(
+    req: HttpRequest,
     mut payload: Multipart,
     state: web::Data<AppState>,
 ) -> Result<web::Json<ZimResponse>, actix_web::Error> {
     state.processed_bytes.store(0, Ordering::Relaxed);
-    let uploads_dir = Path::new("./uploads");
+    // The multipart framing makes this slightly larger than the file itself.
+    let content_length = req
+        .headers()
+        .get("Content-Length")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+    state
+        .upload_total_bytes
+        .store(content_length, Ordering::Relaxed);
+    let uploads_dir = state.library_dir();
+    let uploads_dir = uploads_dir.as_path();
     if !uploads_dir.exists() {
-        fs::create_dir(uploads_dir).map_err(|e| actix_web::error::ErrorInternalServerError(e))?;
+        fs::create_dir_all(uploads_dir)
+            .map_err(|e| actix_web::error::ErrorInternalServerError(e))?;
     }
+    let max_upload_bytes = state.config.read().unwrap().max_upload_bytes;
 
     let mut original_file_name: Option<String> = None;
     let mut hasher = Sha256::new();
-    let mut temp_file =
-        NamedTempFile::new().map_err(|e| actix_web::error::ErrorInternalServerError(e))?;
+    let mut temp_file = NamedTempFile::new_in(uploads_dir)
+        .map_err(|e| actix_web::error::ErrorInternalServerError(e))?;
+    let mut received: u64 = 0;
 
     while let Some(item) = payload.next().await {
         let mut field = item?;
@@ -217,6 +613,13 @@ async fn upload(
         }
         while let Some(chunk_res) = field.next().await {
             let chunk = chunk_res?;
+            received += chunk.len() as u64;
+            if received > max_upload_bytes {
+                return Err(actix_web::error::ErrorPayloadTooLarge(format!(
+                    "Upload exceeds the configured limit of {} bytes",
+                    max_upload_bytes
+                )));
+            }
             hasher.update(&chunk);
             io::copy(&mut chunk.as_ref(), &mut temp_file)
                 .map_err(|e| actix_web::error::ErrorInternalServerError(e))?;
@@ -243,16 +646,17 @@ async fn upload(
         article_count = match Archive::new(cached_path_str) {
             Ok(zim) => zim.get_articlecount() as u64,
             Err(e) => {
-                eprintln!("Failed to open cached ZIM archive: {:?}", e);
+                error!("Failed to open cached ZIM archive: {:?}", e);
                 0
             }
         };
 
         let mut path_guard = state.current_zim_path.lock().unwrap();
         *path_guard = Some(cached_path.clone());
+        remember_active_archive(uploads_dir, cached_path);
 
         fs::remove_file(temp_file.path())
-            .unwrap_or_else(|e| eprintln!("Failed to remove temp file: {:?}", e));
+            .unwrap_or_else(|e| warn!("Failed to remove temp file: {:?}", e));
 
         return Ok(web::Json(ZimResponse {
             message: "File found in cache, no re-upload needed.".to_string(),
@@ -261,6 +665,7 @@ async fn upload(
                 persisted_file_path: cached_path.clone(),
                 article_count,
             },
+            warm_job: None,
         }));
     }
 
@@ -271,7 +676,7 @@ async fn upload(
     article_count = match Archive::new(persisted_path.to_str().unwrap()) {
         Ok(zim) => zim.get_articlecount() as u64,
         Err(e) => {
-            eprintln!("Failed to open new ZIM archive to get metadata: {:?}", e);
+            error!("Failed to open new ZIM archive to get metadata: {:?}", e);
             0
         }
     };
@@ -282,8 +687,15 @@ async fn upload(
         let mut path_guard = state.current_zim_path.lock().unwrap();
         *path_guard = Some(persisted_path.clone());
     }
+    remember_active_archive(uploads_dir, &persisted_path);
 
-    file_cache_guard.insert(hash, persisted_path.clone());
+    file_cache_guard.insert(hash.clone(), persisted_path.clone());
+    drop(file_cache_guard);
+    cluster::publish(
+        &state,
+        vec![cluster::Change::Added(hash, persisted_path.clone())],
+    );
+    let warm_job = spawn_warm_job(&state, persisted_path.clone());
 
     Ok(web::Json(ZimResponse {
         message: "File uploaded successfully".to_string(),
@@ -292,6 +704,7 @@ async fn upload(
             persisted_file_path: persisted_path,
             article_count,
         },
+        warm_job,
     }))
 }
 
@@ -329,67 +742,196 @@ async fn viewer() -> actix_web::Result<NamedFile> {
     Ok(NamedFile::open("./static/viewer.html")?)
 }
 
-#[post("/search")]
-async fn search_articles(req: web::Json<SearchRequest>) -> impl Responder {
-    let file_path = req.file_path.clone();
+/// Answer for a `file_path` that isn't a library archive, e.g. one that was
+/// removed or is being replaced on disk.
+const NOT_IN_LIBRARY: &str = "Archive not found in the library";
+
+#[post("/search", wrap = "crate::load_shed::Shed::heavy()")]
+async fn search_articles(
+    http_req: HttpRequest,
+    req: web::Json<SearchRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
     let query = req.query.clone();
+    let max_page_size = state.config.read().unwrap().max_search_page_size;
+    // Lease and open the library's own path, not however the client spelled it.
+    let archive_id = state.archive_id_for_path(&req.file_path);
+    let Some(file_path) = archive_id.as_deref().and_then(|id| state.archive_path(id)) else {
+        return HttpResponse::NotFound().body(NOT_IN_LIBRARY);
+    };
+    let blocklist = state.blocklist_for(archive_id.as_deref());
+    let (session, session_cookie) = session::session_id(&http_req);
+    let page_size = req
+        .page_size
+        .or_else(|| state.preferences.get(&session).results_per_page)
+        .unwrap_or(DEFAULT_SEARCH_PAGE_SIZE);
+    let (page, page_size) = (req.page, page_size.clamp(1, max_page_size.max(1)));
+    if search_offset(page, page_size).is_none() {
+        return HttpResponse::BadRequest().json(json!({
+            "error": format!("page must be between 1 and {}", i32::MAX as u32 / page_size)
+        }));
+    }
+    let _lease = state.leases.acquire(&file_path);
 
-    match web::block(move || search_zim_file(&file_path, &query)).await {
-        Ok(Ok(results)) => HttpResponse::Ok().json(results),
+    let client = search_queue::client_key(&http_req, &state);
+    let permit = match state
+        .search_queue
+        .acquire(&client, state.search_limits())
+        .await
+    {
+        Ok(permit) => permit,
+        Err(e) => return load_shed::overloaded(&e.to_string()),
+    };
+    let queue_position = permit.queued_at;
+    let (search_path, search_query) = (file_path.clone(), query.clone());
+    let with_facets = req.facets.unwrap_or(page <= 1);
+    let result = web::block(move || {
+        search_zim_file(
+            &search_path,
+            &search_query,
+            page,
+            page_size,
+            &blocklist,
+            with_facets,
+        )
+    })
+    .await;
+    drop(permit);
+    match result {
+        Ok(Ok(response)) => {
+            // Only the first page counts as a new query.
+            if page <= 1 && state.config.read().unwrap().query_analytics {
+                state
+                    .analytics
+                    .record(&query, response.took_ms, response.total_estimate);
+            }
+            if page <= 1 && !state.read_only {
+                let entry =
+                    history::new_entry(&query, archive_id, &file_path, response.total_estimate);
+                state.search_history.record(&session, entry);
+            }
+            let mut builder = HttpResponse::Ok();
+            if let Some(cookie) = session_cookie {
+                builder.cookie(cookie);
+            }
+            builder.json(SearchResponse {
+                queue_position,
+                ..response
+            })
+        }
         Ok(Err(e)) => HttpResponse::InternalServerError().body(e.to_string()),
         Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
     }
 }
 
-#[post("/browse")]
-async fn browse_articles(req: web::Json<BrowseRequest>) -> impl Responder {
-    let file_path = req.file_path.clone();
-    match web::block(move || get_all_articles(&file_path)).await {
+#[post("/browse", wrap = "crate::load_shed::Shed::heavy()")]
+async fn browse_articles(
+    req: web::Json<BrowseRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let Some(archive_id) = state.archive_id_for_path(&req.file_path) else {
+        return HttpResponse::NotFound().body(NOT_IN_LIBRARY);
+    };
+    let Some(file_path) = state.archive_path(&archive_id) else {
+        return HttpResponse::NotFound().body(NOT_IN_LIBRARY);
+    };
+    let blocklist = state.blocklist_for(Some(&archive_id));
+    let _lease = state.leases.acquire(&file_path);
+    match web::block(move || get_all_articles(&file_path, &blocklist)).await {
         Ok(Ok(articles)) => HttpResponse::Ok().json(articles),
         Ok(Err(e)) => HttpResponse::InternalServerError().body(e.to_string()),
         Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
     }
 }
 
+/// Deletes the archives uploaded or downloaded into the library directory.
+/// Archives from other roots, registered books and the server's own files
+/// (manifest, history, collections, ...) are kept, as are archives still being
+/// read.
 #[post("/clean_cache")]
 async fn clean_cache(state: web::Data<AppState>) -> impl Responder {
-    let uploads_dir = Path::new("./uploads");
-    if uploads_dir.exists() {
-        match fs::remove_dir_all(uploads_dir) {
-            Ok(_) => {
-                // Clear the state after deleting the directory
-                let mut files_guard = state.uploaded_files.lock().unwrap();
-                files_guard.clear();
-                let mut path_guard = state.current_zim_path.lock().unwrap();
-                *path_guard = None;
-                // Clear the file cache
-                let mut cache_guard = state.file_cache.lock().unwrap();
-                cache_guard.clear();
-                HttpResponse::Ok().body("Cache cleaned successfully")
+    let library_dir = state.library_dir();
+    let scan_dir = library_dir.clone();
+    let uploaded = match web::block(move || scan_library_dir(&scan_dir)).await {
+        Ok(Ok(uploaded)) => uploaded,
+        Ok(Err(e)) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to clean cache: {}", e));
+        }
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to clean cache: {}", e));
+        }
+    };
+
+    // Taken out of the library first so no new reader can start on them.
+    let mut removing = Vec::new();
+    let mut in_use = 0;
+    {
+        let mut file_cache = state.file_cache.lock().unwrap();
+        for (id, path) in uploaded {
+            if file_cache.get(&id) != Some(&path) {
+                continue;
             }
-            Err(e) => {
-                HttpResponse::InternalServerError().body(format!("Failed to clean cache: {}", e))
+            if state.leases.in_use(&path) > 0 {
+                in_use += 1;
+                continue;
             }
+            file_cache.remove(&id);
+            removing.push((id, path));
         }
-    } else {
-        HttpResponse::Ok().body("Cache directory not found, nothing to clean")
     }
-}
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    let uploads_dir = Path::new("./uploads");
-    if !uploads_dir.exists() {
-        fs::create_dir(uploads_dir)?;
+    let files = removing.clone();
+    let result = web::block(move || {
+        Manifest::update(&library_dir, |manifest| {
+            let mut changed = false;
+            for (id, path) in &files {
+                fs::remove_file(path)?;
+                changed |= manifest.remove(id);
+                if manifest.active.as_ref() == Some(path) {
+                    manifest.active = None;
+                    changed = true;
+                }
+            }
+            Ok(changed)
+        })
+    })
+    .await;
+
+    let mut changes = Vec::new();
+    for (id, path) in removing {
+        if path.exists() {
+            state.file_cache.lock().unwrap().insert(id, path);
+        } else {
+            state.forget_archive(&id, &path);
+            changes.push(cluster::Change::Removed(id));
+        }
+    }
+    cluster::publish(&state, changes);
+
+    match result {
+        Ok(Ok(())) if in_use > 0 => HttpResponse::Ok().body(format!(
+            "Cache cleaned, {} archives still in use were kept",
+            in_use
+        )),
+        Ok(Ok(())) => HttpResponse::Ok().body("Cache cleaned successfully"),
+        Ok(Err(e)) => {
+            HttpResponse::InternalServerError().body(format!("Failed to clean cache: {}", e))
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("Failed to clean cache: {}", e)),
     }
+}
 
-    // Load existing files into the cache on startup
+/// Collects every `.zim` file in `dir` keyed by its stem, which for uploads is
+/// the SHA-256 of the content.
+fn scan_library_dir(dir: &Path) -> io::Result<HashMap<String, PathBuf>> {
     let mut file_cache = HashMap::new();
-    if uploads_dir.exists() {
-        for entry in fs::read_dir(uploads_dir)? {
+    if dir.exists() {
+        for entry in fs::read_dir(dir)? {
             let entry = entry?;
             let path = entry.path();
-            if path.is_file() {
+            if path.is_file() && path.extension().is_some_and(|ext| ext == "zim") {
                 let file_name = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
                 if !file_name.is_empty() {
                     file_cache.insert(file_name.to_string(), path);
@@ -397,31 +939,503 @@ async fn main() -> std::io::Result<()> {
             }
         }
     }
+    Ok(file_cache)
+}
+
+/// Every archive in the library roots plus the books registered in the
+/// manifest of the first root. When an id appears in several roots the first
+/// one wins.
+fn load_library(roots: &[PathBuf]) -> Result<HashMap<String, PathBuf>> {
+    let mut file_cache: HashMap<String, PathBuf> = HashMap::new();
+    for root in roots {
+        if !root.exists() {
+            warn!(
+                "Library directory {} does not exist, skipping",
+                root.display()
+            );
+            continue;
+        }
+        for (id, path) in scan_library_dir(root)? {
+            match file_cache.get(&id) {
+                Some(existing) => warn!(
+                    "Ignoring {}: archive {} is already loaded from {}",
+                    path.display(),
+                    id,
+                    existing.display()
+                ),
+                None => {
+                    file_cache.insert(id, path);
+                }
+            }
+        }
+    }
+    if let Some(primary) = roots.first() {
+        for book in Manifest::load(primary)?.books {
+            file_cache.insert(book.id, book.path);
+        }
+    }
+    Ok(file_cache)
+}
+
+/// Records `path` as the active archive so the next start can reopen it.
+fn remember_active_archive(library_dir: &Path, path: &Path) {
+    let result = Manifest::update(library_dir, |manifest| {
+        manifest.active = Some(path.to_path_buf());
+        Ok(true)
+    });
+    if let Err(e) = result {
+        warn!("Failed to remember active archive: {}", e);
+    }
+}
+
+/// Titles touched when warming an archive, spread evenly over the title index.
+const WARM_TITLE_SAMPLES: u32 = 50_000;
+const WARM_JOB: &str = "warm";
+
+/// Opens the archive and reads its main page and a sample of its title index,
+/// so the OS page cache holds them before the first request arrives.
+fn warm_archive(job: &JobHandle, path: &Path) -> Result<()> {
+    let started = Instant::now();
+    job.set_phase("opening");
+    let zim = Archive::new(path.to_str().unwrap())
+        .map_err(|e| anyhow!("Failed to open archive: {:?}", e))?;
+    job.set_phase("main page");
+    if let Ok(main) = zim.get_main_entry() {
+        if let Ok(item) = main.get_item(true) {
+            let _ = item.get_data();
+        }
+    }
+    job.set_phase("title index");
+    let count = zim.get_entrycount();
+    let step = (count / WARM_TITLE_SAMPLES).max(1);
+    job.set_total(count.div_ceil(step) as u64);
+    for (done, idx) in (0..count).step_by(step as usize).enumerate() {
+        if job.is_cancelled() {
+            return Err(anyhow!("Warming {} cancelled", path.display()));
+        }
+        let _ = zim.get_entry_bytitle_index(idx);
+        if done % 1000 == 0 {
+            job.set_processed(done as u64);
+        }
+    }
+    job.set_processed(count.div_ceil(step) as u64);
+    info!(
+        "Warmed {} in {} ms",
+        path.display(),
+        started.elapsed().as_millis()
+    );
+    Ok(())
+}
+
+/// Warms `path` as a background job, so clients can follow it through
+/// `/jobs/{id}/events`. Returns the job id.
+fn spawn_warm_job(state: &AppState, path: PathBuf) -> Option<String> {
+    let result = state
+        .jobs
+        .spawn(WARM_JOB, &jobs::jobs_dir(state), move |job| {
+            warm_archive(job, &path)
+        });
+    match result {
+        Ok(id) => Some(id),
+        Err(e) => {
+            warn!("Failed to start warming job: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Re-reads the config file and applies everything that can change without
+/// rebinding the listener. Uploaded files and the currently open archive are
+/// left untouched. Everything that can fail is done before anything is
+/// applied, so a config that can't be used leaves the running one in place.
+fn reload_config(state: &AppState) -> Result<Vec<&'static str>> {
+    let new_config = Config::load(&state.config_path)?;
+    let mut config_guard = state.config.write().unwrap();
+
+    let log_output = if new_config.log_files != config_guard.log_files {
+        Some(logging::open_files(new_config.log_files.as_ref())?)
+    } else {
+        None
+    };
+    let roots_changed =
+        new_config.library_roots() != config_guard.library_roots() && state.kiosk_archive.is_none();
+    let file_cache = if roots_changed {
+        fs::create_dir_all(&new_config.library_dir)?;
+        Some(load_library(&new_config.library_roots())?)
+    } else {
+        None
+    };
+
+    for field in config_guard.restart_required(&new_config) {
+        warn!(
+            "Config field '{}' changed, restart required to apply it",
+            field
+        );
+    }
+    let changed = config_guard.changed_fields(&new_config);
+    if new_config.log_level != config_guard.log_level {
+        logging::set_level(new_config.log_level.to_filter());
+    }
+    if let Some(log_output) = log_output {
+        logging::install_files(log_output);
+    }
+    if let Some(file_cache) = file_cache {
+        *state.file_cache.lock().unwrap() = file_cache;
+    }
+    let (bind_address, port) = (config_guard.bind_address.clone(), config_guard.port);
+    *config_guard = Config {
+        bind_address,
+        port,
+        ..new_config
+    };
+    drop(config_guard);
+    if roots_changed {
+        cluster::library_reloaded(state);
+    }
+    info!("Configuration reloaded, changed: {:?}", changed);
+    Ok(changed)
+}
+
+#[post("/admin/reload_config")]
+async fn admin_reload_config(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    if !state.is_admin(&req) {
+        return HttpResponse::Unauthorized().json(json!({"error": "Invalid or missing token"}));
+    }
+
+    match reload_config(&state) {
+        Ok(changed) => HttpResponse::Ok().json(json!({ "changed": changed })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "error": e.to_string() })),
+    }
+}
 
+#[cfg(unix)]
+fn spawn_sighup_reloader(state: AppState) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    actix_web::rt::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Failed to install SIGHUP handler: {:?}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            info!("SIGHUP received, reloading configuration");
+            if let Err(e) = reload_config(&state) {
+                error!("Failed to reload configuration: {:?}", e);
+            }
+        }
+    });
+}
+
+/// `mount <file> <dir>`: serves one archive as a FUSE filesystem instead of
+/// starting the HTTP server.
+#[cfg(all(target_os = "linux", feature = "fuse"))]
+fn run_mount(args: &[String]) -> io::Result<()> {
+    match args {
+        [zim_file, mountpoint, ..] => fuse::mount(Path::new(zim_file), Path::new(mountpoint)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Usage: zim-viewer mount <file.zim> <mountpoint>",
+        )),
+    }
+}
+
+#[cfg(not(all(target_os = "linux", feature = "fuse")))]
+fn run_mount(_args: &[String]) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "This build has no FUSE support, rebuild on Linux with `--features fuse`",
+    ))
+}
+
+/// Value following the command line flag `name`, e.g. `--config <file>`.
+fn arg_value(name: &str) -> Option<String> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == name {
+            return args.next();
+        }
+    }
+    None
+}
+
+fn config_path_from_args() -> PathBuf {
+    arg_value("--config")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(config::DEFAULT_CONFIG_PATH))
+}
+
+/// In kiosk mode `/` goes straight to the archive's main page.
+#[get("/")]
+async fn kiosk_index(state: web::Data<AppState>) -> impl Responder {
+    let id = state.kiosk_archive.clone().unwrap_or_default();
+    HttpResponse::Found()
+        .insert_header(("Location", format!("/content/{}/", id)))
+        .finish()
+}
+
+/// Routes that change the library or the server, left out in kiosk and
+/// read-only mode.
+fn configure_management(cfg: &mut web::ServiceConfig) {
+    cfg.service(upload)
+        .service(clean_cache)
+        .service(admin_reload_config)
+        .service(history::purge_history)
+        .service(analytics::analytics_report)
+        .service(analytics::reset_analytics)
+        .service(downloads::start_download)
+        .service(downloads::control_download)
+        .service(warc::export_warc)
+        .service(compare::compare)
+        .service(linkcheck::check_links)
+        .service(derive::derive)
+        .service(subset::export_subset_archive)
+        .service(archives::delete_archive)
+        .service(tags::set_archive_tags)
+        .service(library_xml::import_library);
+}
+
+/// Routes that store per-session data (history, collections, preferences,
+/// pairing), left out in read-only mode.
+fn configure_session_writes(cfg: &mut web::ServiceConfig) {
+    cfg.service(history::clear_history)
+        .service(collections::create_collection)
+        .service(collections::update_collection)
+        .service(collections::delete_collection)
+        .service(collections::share_collection)
+        .service(collections::unshare_collection)
+        .service(preferences::update_preferences)
+        .service(preferences::clear_preferences)
+        .service(preferences::create_pairing_code)
+        .service(preferences::redeem_pairing_code);
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let config_path = config_path_from_args();
+    let config = Config::load(&config_path)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    logging::init(config.log_level.to_filter());
+    logging::configure_files(config.log_files.as_ref())?;
+
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("mount") {
+        return run_mount(&args[2..]);
+    }
+
+    let uploads_dir = config.library_dir.clone();
+    if !uploads_dir.exists() {
+        fs::create_dir_all(&uploads_dir)?;
+    }
+
+    // Only one desktop instance runs; starting another just reopens the
+    // browser on it.
+    let desktop = std::env::args().any(|arg| arg == "--desktop");
+    let _instance_lock = if desktop {
+        match desktop::acquire_instance_lock(&uploads_dir)? {
+            Some(lock) => Some(lock),
+            None => return Ok(()),
+        }
+    } else {
+        None
+    };
+
+    // A read-only instance serves the library without any route that changes
+    // it, for exposing it to networks that can't be trusted.
+    let read_only = std::env::args().any(|arg| arg == "--read-only");
+    if read_only {
+        info!("Read-only mode: upload, deletion, admin and per-session write routes are disabled");
+    }
+
+    // Load existing files into the cache on startup. A kiosk serves only the
+    // archive it was started with.
+    let kiosk_zim = arg_value("--kiosk").map(PathBuf::from);
+    let (file_cache, kiosk_archive) = match &kiosk_zim {
+        Some(zim_path) => {
+            if !zim_path.is_file() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Kiosk archive {} not found", zim_path.display()),
+                ));
+            }
+            let id = zim_path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or("kiosk")
+                .to_string();
+            info!("Kiosk mode: serving only {}", zim_path.display());
+            (HashMap::from([(id.clone(), zim_path.clone())]), Some(id))
+        }
+        None => (
+            load_library(&config.library_roots())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?,
+            None,
+        ),
+    };
+    // Reopen whatever was active before the restart, warming it in the
+    // background so startup is not delayed.
+    let current_zim_path = kiosk_zim.or_else(|| {
+        let active = Manifest::load(&uploads_dir).ok()?.active?;
+        active.is_file().then_some(active)
+    });
+    let search_history = SearchHistory::load(&uploads_dir)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    let collections = Collections::load(&uploads_dir)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    let preferences = SessionPreferences::load(&uploads_dir)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+    // A desktop instance is local-only and takes any free port.
+    let (bind_address, port) = if desktop {
+        ("127.0.0.1".to_string(), 0)
+    } else {
+        (config.bind_address.clone(), config.port)
+    };
+    let tls_config = config.tls.as_ref().map(tls::server_config).transpose()?;
     let state = AppState {
         processed_bytes: Arc::new(AtomicU64::new(0)),
+        upload_total_bytes: Arc::new(AtomicU64::new(0)),
         uploaded_files: Arc::new(Mutex::new(HashMap::new())),
-        current_zim_path: Arc::new(Mutex::new(None)),
+        current_zim_path: Arc::new(Mutex::new(current_zim_path)),
         file_cache: Arc::new(Mutex::new(file_cache)),
+        config: Arc::new(RwLock::new(config)),
+        config_path,
+        jobs: JobRegistry::default(),
+        search_queue: SearchQueue::default(),
+        search_history,
+        collections,
+        preferences,
+        analytics: QueryAnalytics::default(),
+        body_cache: BodyCache::default(),
+        popular_titles: PopularTitles::default(),
+        categories: CategoryIndex::default(),
+        kiwix_books: kiwix::BookCache::default(),
+        load_shedder: LoadShedder::default(),
+        leases: ArchiveLeases::default(),
+        cluster: ClusterSync::default(),
+        kiosk_archive,
+        read_only,
     };
+    if let Some(path) = state.current_zim_path.lock().unwrap().clone() {
+        spawn_warm_job(&state, path);
+    }
 
-    println!("Server running on http://127.0.0.1:8080");
+    #[cfg(unix)]
+    spawn_sighup_reloader(state.clone());
+    watcher::spawn(state.clone());
+    cluster::spawn(state.clone());
 
-    HttpServer::new(move || {
-        App::new()
-            .app_data(web::Data::new(state.clone()))
-            .service(index)
-            .service(viewer)
+    let scheme = if tls_config.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+
+    let (search_history, collections, preferences) = (
+        state.search_history.clone(),
+        state.collections.clone(),
+        state.preferences.clone(),
+    );
+    let server = HttpServer::new(move || {
+        let kiosk = state.kiosk_archive.is_some();
+        let app = App::new()
+            .wrap(Logger::default())
+            .app_data(web::Data::new(state.clone()));
+        let app = if kiosk && read_only {
+            app.service(kiosk_index)
+        } else if kiosk {
+            app.service(kiosk_index).configure(configure_session_writes)
+        } else if read_only {
+            app.service(index)
+        } else {
+            app.service(index)
+                .configure(configure_management)
+                .configure(configure_session_writes)
+        };
+        app.service(viewer)
             .service(get_current_file)
+            .service(favicon::favicon)
+            .service(favicon::archive_favicon)
             .service(progress)
-            .service(upload)
             .service(article)
             .service(search_articles)
+            .service(search_queue::queue_status)
+            .service(kiwix::kiwix_search)
+            .service(federation::federated_search)
+            .service(kiwix::kiwix_suggest)
+            .service(kiwix::kiwix_content)
             .service(browse_articles)
-            .service(clean_cache)
+            .service(history::list_history)
+            .service(collections::list_collections)
+            .service(collections::get_collection)
+            .service(collections::export_collection)
+            .service(collections::get_shared_collection)
+            .service(collections::export_shared_collection)
+            .service(preferences::get_preferences)
+            .service(jobs::list_jobs)
+            .service(jobs::get_job)
+            .service(jobs::job_events)
+            .service(jobs::download_job_file)
+            .service(downloads::list_downloads)
+            .service(archives::batch_metadata)
+            .service(archives::archive_capabilities)
+            .service(tags::list_tags)
+            .service(categories::category_roots)
+            .service(categories::category_detail)
+            .service(navigation::archive_tree_listing)
+            .service(references::article_references)
+            .service(language::article_language)
+            .service(opengraph::article_opengraph)
+            .service(preview::link_preview)
+            .service(translate::translate_article)
+            .service(grep::grep)
+            .service(library_xml::export_library)
+            .configure(webdav::configure)
             .service(actix_files::Files::new("/", "./static").index_file("index.html"))
-    })
-    .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+    });
+    let server = match tls_config {
+        Some(tls_config) => server.bind_rustls_0_23((bind_address.as_str(), port), tls_config)?,
+        None => server.bind((bind_address.as_str(), port))?,
+    };
+    for addr in server.addrs() {
+        info!("Server running on {}://{}", scheme, addr);
+    }
+    if desktop {
+        if let Some(addr) = server.addrs().first() {
+            desktop::announce(&uploads_dir, &format!("{}://{}/", scheme, addr))?;
+        }
+    }
+    server.run().await?;
+    // Changes made just before shutting down may not be saved yet.
+    if let Err(e) = search_history.flush() {
+        error!("Failed to save search history: {:?}", e);
+    }
+    if let Err(e) = collections.flush() {
+        error!("Failed to save collections: {:?}", e);
+    }
+    if let Err(e) = preferences.flush() {
+        error!("Failed to save preferences: {:?}", e);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_offset_counts_pages_from_one() {
+        assert_eq!(search_offset(1, 50), Some(0));
+        assert_eq!(search_offset(3, 20), Some(40));
+        assert_eq!(search_offset(0, 20), None);
+    }
+
+    #[test]
+    fn search_offset_rejects_unaddressable_pages() {
+        assert_eq!(search_offset(u32::MAX, 2), None);
+        assert_eq!(search_offset(i32::MAX as u32 / 100 + 1, 100), None);
+        assert!(search_offset(i32::MAX as u32 / 100, 100).is_some());
+    }
 }
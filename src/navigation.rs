@@ -0,0 +1,198 @@
+//! Path-based navigation for archives whose entry paths encode a hierarchy
+//! (devdocs, wikibooks, documentation sites): a directory-like listing at a
+//! path plus breadcrumbs from the root down to it, built on `archive_tree`.
+
+use crate::AppState;
+use crate::archive_tree::{self, DirEntry, Node};
+use crate::blocklist::Blocklist;
+use actix_web::{HttpResponse, Responder, get, web};
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::path::Path;
+use zim_rs::archive::Archive;
+
+const DEFAULT_PAGE_SIZE: usize = 200;
+const MAX_PAGE_SIZE: usize = 1000;
+
+#[derive(Serialize)]
+struct Crumb {
+    name: String,
+    path: String,
+    /// Title of the entry at this path, when there is one (a section's index
+    /// page, or the current entry itself).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum Child {
+    Dir {
+        name: String,
+        path: String,
+    },
+    Entry {
+        name: String,
+        path: String,
+        title: String,
+        mimetype: String,
+        size: u64,
+    },
+}
+
+#[derive(Serialize)]
+struct Listing {
+    /// The directory listed: `path` itself, or its parent when `path` is an
+    /// entry without children.
+    directory: String,
+    /// Set when `path` names an entry.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entry: Option<Crumb>,
+    breadcrumbs: Vec<Crumb>,
+    children: Vec<Child>,
+    /// Pass as `start` to get the next page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_start: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct ListingQuery {
+    start: Option<u32>,
+    limit: Option<usize>,
+}
+
+fn entry_title(zim: &Archive, path: &str) -> Option<String> {
+    let entry = zim.get_entry_bypath_str(path).ok()?;
+    let title = entry.get_title();
+    Some(if title.is_empty() {
+        path.to_string()
+    } else {
+        title
+    })
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+fn breadcrumbs(zim: &Archive, path: &str) -> Vec<Crumb> {
+    let mut crumbs = vec![Crumb {
+        name: String::new(),
+        path: String::new(),
+        title: None,
+    }];
+    let mut current = String::new();
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        current = join(&current, segment);
+        crumbs.push(Crumb {
+            name: segment.to_string(),
+            path: current.clone(),
+            title: entry_title(zim, &current),
+        });
+    }
+    crumbs
+}
+
+fn list(
+    zim_path: &Path,
+    path: &str,
+    start: Option<u32>,
+    limit: usize,
+    blocklist: &Blocklist,
+) -> Result<Option<Listing>> {
+    let zim = Archive::new(zim_path.to_str().unwrap())
+        .map_err(|e| anyhow!("Failed to open archive: {:?}", e))?;
+    let path = archive_tree::normalize(path);
+    let Some(node) = archive_tree::stat(&zim, path) else {
+        return Ok(None);
+    };
+    let is_entry = matches!(node, Node::File { .. });
+    if is_entry && blocklist.is_blocked(&entry_title(&zim, path).unwrap_or_default(), path) {
+        return Ok(None);
+    }
+    // An entry can also be a directory (`guide` next to `guide/intro`).
+    let directory = match list_children(&zim, path, start, limit, blocklist) {
+        Ok(page) if !is_entry || !page.0.is_empty() => Some((path.to_string(), page)),
+        _ => None,
+    };
+    let (directory, (children, next_start)) = match directory {
+        Some(found) => found,
+        None => {
+            let parent = path.rsplit_once('/').map_or("", |(parent, _)| parent);
+            let page = list_children(&zim, parent, start, limit, blocklist)?;
+            (parent.to_string(), page)
+        }
+    };
+
+    Ok(Some(Listing {
+        entry: is_entry.then(|| Crumb {
+            name: path.rsplit('/').next().unwrap_or(path).to_string(),
+            path: path.to_string(),
+            title: entry_title(&zim, path),
+        }),
+        breadcrumbs: breadcrumbs(&zim, path),
+        directory,
+        children,
+        next_start,
+    }))
+}
+
+fn list_children(
+    zim: &Archive,
+    dir: &str,
+    start: Option<u32>,
+    limit: usize,
+    blocklist: &Blocklist,
+) -> Result<(Vec<Child>, Option<u32>)> {
+    let (entries, next_start) = archive_tree::list_dir_page(zim, dir, start, limit)?;
+    let children = entries
+        .into_iter()
+        .filter_map(|DirEntry { name, node }| {
+            let path = join(dir, &name);
+            match node {
+                Node::Dir => Some(Child::Dir { name, path }),
+                Node::File { size, mimetype } => {
+                    let title = entry_title(zim, &path).unwrap_or_else(|| path.clone());
+                    (!blocklist.is_blocked(&title, &path)).then_some(Child::Entry {
+                        name,
+                        path,
+                        title,
+                        mimetype,
+                        size,
+                    })
+                }
+            }
+        })
+        .collect();
+    Ok((children, next_start))
+}
+
+#[get("/archives/{id}/tree/{path:.*}")]
+async fn archive_tree_listing(
+    path: web::Path<(String, String)>,
+    query: web::Query<ListingQuery>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let (id, entry_path) = path.into_inner();
+    let Some(zim_path) = state.archive_path(&id) else {
+        return HttpResponse::NotFound().json(json!({"error": "Archive not found"}));
+    };
+    let _lease = state.leases.acquire(&zim_path);
+    let blocklist = state.blocklist_for(Some(&id));
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let start = query.start;
+    match web::block(move || list(&zim_path, &entry_path, start, limit, &blocklist)).await {
+        Ok(Ok(Some(listing))) => HttpResponse::Ok().json(listing),
+        Ok(Ok(None)) => HttpResponse::NotFound().json(json!({"error": "Path not found"})),
+        Ok(Err(e)) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+    }
+}
@@ -0,0 +1,130 @@
+//! Link-unfurling metadata for an article: OpenGraph/Twitter tags from its
+//! `<head>`, falling back to the `<title>`, the first paragraph and the first
+//! image when an archive's HTML carries none (most scraped ZIMs don't).
+
+use crate::AppState;
+use crate::article_html;
+use actix_web::{HttpResponse, Responder, get, web};
+use scraper::{Html, Selector};
+use serde::Serialize;
+
+/// Descriptions taken from the article text are cut to about this length.
+const MAX_DESCRIPTION_CHARS: usize = 300;
+
+#[derive(Serialize)]
+struct OpenGraph {
+    title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    /// Lead image, as a `/content` URL when it lives in the archive.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image: Option<String>,
+    /// Where the article itself is served.
+    url: String,
+}
+
+/// Content of the first `<meta>` with one of `names` as its `property` or
+/// `name`.
+pub(crate) fn meta(document: &Html, names: &[&str]) -> Option<String> {
+    let selector = Selector::parse("head meta").unwrap();
+    names.iter().find_map(|name| {
+        document
+            .select(&selector)
+            .find(|m| {
+                let m = m.value();
+                m.attr("property") == Some(name) || m.attr("name") == Some(name)
+            })
+            .and_then(|m| m.value().attr("content"))
+            .map(str::trim)
+            .filter(|c| !c.is_empty())
+            .map(str::to_string)
+    })
+}
+
+pub(crate) fn first_paragraph(document: &Html) -> Option<String> {
+    let selector = Selector::parse("body p").unwrap();
+    document.select(&selector).find_map(|p| {
+        let text = p.text().flat_map(str::split_whitespace).collect::<Vec<_>>();
+        (!text.is_empty()).then(|| truncate_words(&text, MAX_DESCRIPTION_CHARS))
+    })
+}
+
+fn truncate_words(words: &[&str], max_chars: usize) -> String {
+    let mut out = String::new();
+    for word in words {
+        if !out.is_empty() && out.chars().count() + 1 + word.chars().count() > max_chars {
+            out.push('…');
+            break;
+        }
+        if !out.is_empty() {
+            out.push(' ');
+        }
+        out.push_str(word);
+    }
+    out
+}
+
+pub(crate) fn content_url(id: &str, entry_path: &str) -> String {
+    let encoded: Vec<String> = entry_path
+        .split('/')
+        .map(|s| urlencoding::encode(s).into_owned())
+        .collect();
+    format!("/content/{}/{}", urlencoding::encode(id), encoded.join("/"))
+}
+
+fn extract(id: &str, title: &str, path: &str, html: &str) -> OpenGraph {
+    let document = Html::parse_document(html);
+    let title = meta(&document, &["og:title", "twitter:title"]).unwrap_or_else(|| {
+        let selector = Selector::parse("head title").unwrap();
+        document
+            .select(&selector)
+            .next()
+            .map(|t| t.text().collect::<String>().trim().to_string())
+            .filter(|t| !t.is_empty())
+            .unwrap_or_else(|| title.to_string())
+    });
+    let description = meta(
+        &document,
+        &["og:description", "twitter:description", "description"],
+    )
+    .or_else(|| first_paragraph(&document));
+    let image = meta(&document, &["og:image", "twitter:image"]).or_else(|| {
+        let selector = Selector::parse("body img[src]").unwrap();
+        document
+            .select(&selector)
+            .next()
+            .and_then(|img| img.value().attr("src"))
+            .map(str::to_string)
+    });
+    let image = image.map(|src| match article_html::resolve_entry_path(path, &src) {
+        Some(entry_path) => content_url(id, &entry_path),
+        None => src,
+    });
+
+    OpenGraph {
+        title,
+        description,
+        image,
+        url: content_url(id, path),
+    }
+}
+
+#[get(
+    "/archives/{id}/opengraph/{path:.*}",
+    wrap = "crate::load_shed::Shed::heavy()"
+)]
+async fn article_opengraph(
+    path: web::Path<(String, String)>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let (id, entry_path) = path.into_inner();
+    let archive_id = id.clone();
+    let result = article_html::with_article(&state, &id, entry_path, move |title, path, html| {
+        extract(&archive_id, title, path, html)
+    })
+    .await;
+    match result {
+        Ok(opengraph) => HttpResponse::Ok().json(opengraph),
+        Err(response) => response,
+    }
+}
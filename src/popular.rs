@@ -0,0 +1,44 @@
+//! Per-archive article view counts, kept in memory, used as the default
+//! suggestions when the search box is (nearly) empty.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// New distinct paths are no longer counted past this many per archive.
+const MAX_TRACKED_PATHS: usize = 10_000;
+
+#[derive(Clone, Default)]
+pub struct PopularTitles {
+    views: Arc<Mutex<HashMap<String, HashMap<String, u64>>>>,
+}
+
+impl PopularTitles {
+    pub fn record(&self, archive_id: &str, path: &str) {
+        let mut views = self.views.lock().unwrap();
+        let counts = views.entry(archive_id.to_string()).or_default();
+        if !counts.contains_key(path) && counts.len() >= MAX_TRACKED_PATHS {
+            return;
+        }
+        *counts.entry(path.to_string()).or_default() += 1;
+    }
+
+    /// Paths of the archive's most viewed entries, most viewed first.
+    pub fn top(&self, archive_id: &str, limit: usize) -> Vec<String> {
+        let views = self.views.lock().unwrap();
+        let Some(counts) = views.get(archive_id) else {
+            return Vec::new();
+        };
+        let mut paths: Vec<(&String, &u64)> = counts.iter().collect();
+        paths.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        paths
+            .into_iter()
+            .take(limit)
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+
+    /// Drops the counts of an archive whose file was replaced.
+    pub fn forget(&self, archive_id: &str) {
+        self.views.lock().unwrap().remove(archive_id);
+    }
+}
@@ -0,0 +1,366 @@
+//! Per-session viewer preferences (theme, font size, language, default
+//! archive, results per page), persisted as `preferences.json` in the library
+//! directory so they are kept server-side rather than in one browser's
+//! storage. Another device on the network takes them over by redeeming a
+//! short pairing code, which binds its session cookie to the same session.
+
+use crate::AppState;
+use crate::json_store::{self, JsonStore};
+use crate::search_queue;
+use crate::session;
+use actix_web::{HttpRequest, HttpResponse, Responder, delete, get, post, put, web};
+use anyhow::Result;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const PREFERENCES_FILE: &str = "preferences.json";
+/// Sessions kept; the ones that changed their preferences least recently are
+/// dropped first.
+const MAX_SESSIONS: usize = 5000;
+/// How long a pairing code can be redeemed.
+const PAIRING_TTL: Duration = Duration::from_secs(10 * 60);
+/// Characters of pairing codes: Crockford's base32, which leaves out letters
+/// easily mistaken for digits.
+const CODE_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+/// 26 characters of 5 bits each: 130 random bits.
+const CODE_LENGTH: usize = 26;
+/// Failed redemptions a client may make before it is locked out, for
+/// `LOCKOUT` after the first of them.
+const MAX_FAILED_REDEMPTIONS: u32 = 5;
+const LOCKOUT: Duration = Duration::from_secs(15 * 60);
+/// Font sizes accepted, in percent of the default.
+const FONT_SIZE_RANGE: std::ops::RangeInclusive<u32> = 50..=300;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    Light,
+    Dark,
+    /// Follow the system setting.
+    Auto,
+}
+
+/// Unset fields fall back to the frontend's own defaults.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(default)]
+pub struct Preferences {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub theme: Option<Theme>,
+    /// Percent of the default font size.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub font_size: Option<u32>,
+    /// BCP 47 tag, e.g. `en` or `pt-BR`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_archive: Option<String>,
+    /// Page size used by `POST /search` when the request doesn't give one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub results_per_page: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<i64>,
+}
+
+struct PairingCode {
+    session: String,
+    expires: Instant,
+}
+
+struct FailedRedemptions {
+    count: u32,
+    since: Instant,
+}
+
+#[derive(Debug, PartialEq)]
+enum RedeemError {
+    Unknown,
+    /// Too many failed attempts; retry after this long.
+    LockedOut(Duration),
+}
+
+#[derive(Clone)]
+pub struct SessionPreferences {
+    store: JsonStore<Preferences>,
+    pairing_codes: Arc<Mutex<HashMap<String, PairingCode>>>,
+    /// Failed redemptions by client address.
+    failed_redemptions: Arc<Mutex<HashMap<String, FailedRedemptions>>>,
+}
+
+impl SessionPreferences {
+    pub fn load(library_dir: &Path) -> Result<SessionPreferences> {
+        Ok(SessionPreferences {
+            store: JsonStore::load(library_dir, PREFERENCES_FILE)?,
+            pairing_codes: Arc::new(Mutex::new(HashMap::new())),
+            failed_redemptions: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.store.flush()
+    }
+
+    pub fn get(&self, session: &str) -> Preferences {
+        self.store
+            .read(|sessions| sessions.get(session).cloned().unwrap_or_default())
+    }
+
+    pub fn set(&self, session: &str, mut preferences: Preferences) -> Preferences {
+        preferences.updated_at = Some(Utc::now().timestamp());
+        self.store.write(|sessions| {
+            sessions.insert(session.to_string(), preferences.clone());
+            json_store::evict_oldest(sessions, MAX_SESSIONS, |preferences| {
+                preferences.updated_at.unwrap_or(0)
+            });
+        });
+        preferences
+    }
+
+    pub fn clear(&self, session: &str) -> bool {
+        self.store
+            .write(|sessions| sessions.remove(session).is_some())
+    }
+
+    /// A new code for `session`, replacing any earlier one.
+    fn pairing_code(&self, session: &str) -> String {
+        let code = new_code();
+        let mut codes = self.pairing_codes.lock().unwrap();
+        let now = Instant::now();
+        codes.retain(|_, pairing| pairing.expires > now && pairing.session != session);
+        codes.insert(
+            code.clone(),
+            PairingCode {
+                session: session.to_string(),
+                expires: now + PAIRING_TTL,
+            },
+        );
+        code
+    }
+
+    /// The session `code` was issued for. A code works once, and a client
+    /// guessing codes is locked out after a few failures.
+    fn redeem(&self, client: &str, code: &str) -> Result<String, RedeemError> {
+        let now = Instant::now();
+        let mut failures = self.failed_redemptions.lock().unwrap();
+        failures.retain(|_, failed| now.duration_since(failed.since) < LOCKOUT);
+        if let Some(failed) = failures.get(client) {
+            if failed.count >= MAX_FAILED_REDEMPTIONS {
+                return Err(RedeemError::LockedOut(
+                    LOCKOUT - now.duration_since(failed.since),
+                ));
+            }
+        }
+
+        let pairing = self
+            .pairing_codes
+            .lock()
+            .unwrap()
+            .remove(&normalize_code(code));
+        match pairing.filter(|pairing| pairing.expires > now) {
+            Some(pairing) => Ok(pairing.session),
+            None => {
+                failures
+                    .entry(client.to_string())
+                    .or_insert(FailedRedemptions {
+                        count: 0,
+                        since: now,
+                    })
+                    .count += 1;
+                Err(RedeemError::Unknown)
+            }
+        }
+    }
+}
+
+/// The 122 random bits of a v4 UUID.
+fn random_bits() -> u128 {
+    let value = uuid::Uuid::new_v4().as_u128();
+    ((value >> 80) << 74) | (((value >> 64) & 0xfff) << 62) | (value & ((1 << 62) - 1))
+}
+
+fn new_code() -> String {
+    let (mut high, mut low) = (random_bits(), random_bits());
+    (0..CODE_LENGTH)
+        .map(|n| {
+            // 24 characters from the first 120 bits, the rest from the second.
+            let bits = if n < 24 { &mut high } else { &mut low };
+            let c = CODE_ALPHABET[(*bits & 31) as usize];
+            *bits >>= 5;
+            c as char
+        })
+        .collect()
+}
+
+/// A code as typed: any case, grouped with spaces or dashes, and with the
+/// letters Crockford's base32 reads as digits.
+fn normalize_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .map(|c| match c.to_ascii_uppercase() {
+            'I' | 'L' => '1',
+            'O' => '0',
+            c => c,
+        })
+        .collect()
+}
+
+fn is_language_tag(tag: &str) -> bool {
+    !tag.is_empty()
+        && tag.len() <= 35
+        && tag
+            .split('-')
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+fn validate(preferences: &Preferences, state: &AppState) -> Result<(), String> {
+    if let Some(font_size) = preferences.font_size {
+        if !FONT_SIZE_RANGE.contains(&font_size) {
+            return Err(format!(
+                "font_size must be between {} and {}",
+                FONT_SIZE_RANGE.start(),
+                FONT_SIZE_RANGE.end()
+            ));
+        }
+    }
+    if let Some(language) = &preferences.language {
+        if !is_language_tag(language) {
+            return Err(format!("Invalid language tag: {}", language));
+        }
+    }
+    if let Some(id) = &preferences.default_archive {
+        if state.archive_path(id).is_none() {
+            return Err(format!("Unknown archive: {}", id));
+        }
+    }
+    if let Some(results_per_page) = preferences.results_per_page {
+        let max = state.config.read().unwrap().max_search_page_size;
+        if results_per_page == 0 || results_per_page > max {
+            return Err(format!("results_per_page must be between 1 and {}", max));
+        }
+    }
+    Ok(())
+}
+
+#[get("/preferences")]
+async fn get_preferences(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    let (session, cookie) = session::session_id(&req);
+    session::with_session(cookie, HttpResponse::Ok()).json(state.preferences.get(&session))
+}
+
+/// Replaces the session's preferences; fields left out are unset.
+#[put("/preferences")]
+async fn update_preferences(
+    req: HttpRequest,
+    body: web::Json<Preferences>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let preferences = body.into_inner();
+    if let Err(e) = validate(&preferences, &state) {
+        return HttpResponse::BadRequest().json(json!({"error": e}));
+    }
+    let (session, cookie) = session::session_id(&req);
+    let saved = state.preferences.set(&session, preferences);
+    session::with_session(cookie, HttpResponse::Ok()).json(saved)
+}
+
+#[delete("/preferences")]
+async fn clear_preferences(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    let (session, _) = session::session_id(&req);
+    state.preferences.clear(&session);
+    HttpResponse::NoContent().finish()
+}
+
+/// Issues a code another device can redeem to share this session.
+#[post("/preferences/pairing")]
+async fn create_pairing_code(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    let (session, cookie) = session::session_id(&req);
+    let code = state.preferences.pairing_code(&session);
+    session::with_session(cookie, HttpResponse::Created()).json(json!({
+        "code": code,
+        "expires_in_secs": PAIRING_TTL.as_secs(),
+    }))
+}
+
+/// Binds the caller to the session the code was issued for, so it shares
+/// that session's preferences, search history and collections.
+#[post("/preferences/pairing/{code}")]
+async fn redeem_pairing_code(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let client = search_queue::client_key(&req, &state);
+    match state.preferences.redeem(&client, &path) {
+        Ok(session) => HttpResponse::Ok()
+            .cookie(session::cookie(&session))
+            .json(state.preferences.get(&session)),
+        Err(RedeemError::Unknown) => {
+            HttpResponse::NotFound().json(json!({"error": "Unknown or expired code"}))
+        }
+        Err(RedeemError::LockedOut(retry_after)) => HttpResponse::TooManyRequests()
+            .insert_header(("Retry-After", retry_after.as_secs().max(1).to_string()))
+            .json(json!({"error": "Too many failed attempts, try again later"})),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn preferences() -> (PathBuf, SessionPreferences) {
+        let dir = std::env::temp_dir().join(format!("preferences-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let preferences = SessionPreferences::load(&dir).unwrap();
+        (dir, preferences)
+    }
+
+    #[test]
+    fn codes_are_long_and_in_the_alphabet() {
+        let code = new_code();
+        assert_eq!(code.len(), CODE_LENGTH);
+        assert!(code.bytes().all(|c| CODE_ALPHABET.contains(&c)));
+        assert_ne!(code, new_code());
+    }
+
+    #[test]
+    fn codes_are_redeemed_once_as_typed() {
+        let (dir, preferences) = preferences();
+        let code = preferences.pairing_code("session");
+        let typed = format!("{}-{}", &code[..13], code[13..].to_lowercase());
+        assert_eq!(
+            preferences.redeem("client", &typed),
+            Ok("session".to_string())
+        );
+        assert_eq!(
+            preferences.redeem("client", &code),
+            Err(RedeemError::Unknown)
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn guessing_clients_are_locked_out() {
+        let (dir, preferences) = preferences();
+        let code = preferences.pairing_code("session");
+        for _ in 0..MAX_FAILED_REDEMPTIONS {
+            assert_eq!(
+                preferences.redeem("guesser", "WRONG"),
+                Err(RedeemError::Unknown)
+            );
+        }
+        assert!(matches!(
+            preferences.redeem("guesser", &code),
+            Err(RedeemError::LockedOut(_))
+        ));
+        assert_eq!(
+            preferences.redeem("other", &code),
+            Ok("session".to_string())
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
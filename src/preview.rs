@@ -0,0 +1,175 @@
+//! Hover-card previews of internal links: the target article's title, the
+//! start of its first paragraph and a thumbnail, in one request so a reader
+//! can show Wikipedia-style previews while offline. Built from the same
+//! article lookups as the OpenGraph endpoint.
+
+use crate::AppState;
+use crate::article_html;
+use crate::kiwix;
+use crate::opengraph;
+use actix_web::{HttpResponse, Responder, get, web};
+use scraper::{Html, Selector};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// Images narrower or shorter than this (by their attributes) are icons, not
+/// thumbnails.
+const MIN_THUMBNAIL_PX: u32 = 50;
+
+#[derive(Deserialize)]
+struct PreviewQuery {
+    /// The link as found in the page: relative to `from`, or a `/content/…`
+    /// URL as served by this server.
+    link: String,
+    /// Entry path of the article the link appears in.
+    #[serde(default)]
+    from: String,
+}
+
+#[derive(Serialize)]
+struct Preview {
+    title: String,
+    /// Entry path of the target, after following redirects.
+    path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    snippet: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnail: Option<String>,
+    url: String,
+}
+
+/// Entry path a link points at, with the book named by `/content/{book}/…`
+/// URLs; `None` for links leaving the archive.
+fn link_target(link: &str, from: &str) -> Option<(Option<String>, String)> {
+    match link.strip_prefix("/content/") {
+        Some(rest) => {
+            let (book, path) = rest.split_once('/')?;
+            let book = urlencoding::decode(book).ok()?.into_owned();
+            let path = path.split(['#', '?']).next().unwrap_or("");
+            let path = urlencoding::decode(path).ok()?.into_owned();
+            (!book.is_empty() && !path.is_empty()).then_some((Some(book), path))
+        }
+        None => article_html::resolve_entry_path(from, link).map(|path| (None, path)),
+    }
+}
+
+/// Whether `book` (a name, library id or UUID) is the archive `id`.
+async fn is_archive(state: &web::Data<AppState>, id: &str, book: &str) -> bool {
+    if book == id {
+        return true;
+    }
+    let Some(zim_path) = state.archive_path(id) else {
+        return false;
+    };
+    let (book_cache, id, book) = (state.kiwix_books.clone(), id.to_string(), book.to_string());
+    web::block(move || !kiwix::resolve_books(&book_cache, vec![(id, zim_path)], &[book]).is_empty())
+        .await
+        .unwrap_or(false)
+}
+
+fn dimension(value: Option<&str>) -> Option<u32> {
+    value?.trim().trim_end_matches("px").parse().ok()
+}
+
+/// The lead image: the page's declared one, else the first image in the
+/// body that isn't an icon or inline data.
+fn thumbnail(document: &Html) -> Option<String> {
+    opengraph::meta(document, &["og:image", "twitter:image"]).or_else(|| {
+        let selector = Selector::parse("body img[src]").unwrap();
+        document
+            .select(&selector)
+            .map(|img| img.value())
+            .filter(|img| {
+                let small =
+                    |attr| dimension(img.attr(attr)).is_some_and(|px| px < MIN_THUMBNAIL_PX);
+                !small("width") && !small("height")
+            })
+            .filter_map(|img| img.attr("src"))
+            .find(|src| !src.starts_with("data:"))
+            .map(str::to_string)
+    })
+}
+
+fn build(id: &str, title: &str, path: &str, html: &str) -> Preview {
+    let document = Html::parse_document(html);
+    let snippet = opengraph::first_paragraph(&document)
+        .or_else(|| opengraph::meta(&document, &["og:description", "description"]));
+    let thumbnail =
+        thumbnail(&document).map(|src| match article_html::resolve_entry_path(path, &src) {
+            Some(entry_path) => opengraph::content_url(id, &entry_path),
+            None => src,
+        });
+    Preview {
+        title: if title.is_empty() {
+            path.to_string()
+        } else {
+            title.to_string()
+        },
+        path: path.to_string(),
+        snippet,
+        thumbnail,
+        url: opengraph::content_url(id, path),
+    }
+}
+
+#[get("/archives/{id}/preview", wrap = "crate::load_shed::Shed::heavy()")]
+async fn link_preview(
+    path: web::Path<String>,
+    query: web::Query<PreviewQuery>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let outside =
+        || HttpResponse::BadRequest().json(json!({"error": "Not a link inside the archive"}));
+    let Some((book, entry_path)) = link_target(&query.link, &query.from) else {
+        return outside();
+    };
+    if let Some(book) = book {
+        if !is_archive(&state, &id, &book).await {
+            return outside();
+        }
+    }
+    let archive_id = id.clone();
+    let result = article_html::with_article(&state, &id, entry_path, move |title, path, html| {
+        build(&archive_id, title, path, html)
+    })
+    .await;
+    match result {
+        Ok(preview) => HttpResponse::Ok().json(preview),
+        Err(response) => response,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_urls_keep_their_book() {
+        assert_eq!(
+            link_target(
+                "/content/wikipedia_en/A/Gravity%20well#History",
+                "A/Physics"
+            ),
+            Some((
+                Some("wikipedia_en".to_string()),
+                "A/Gravity well".to_string()
+            ))
+        );
+        assert_eq!(
+            link_target("/content/my%20book/Page?x=1", ""),
+            Some((Some("my book".to_string()), "Page".to_string()))
+        );
+        assert_eq!(link_target("/content/wikipedia_en/", ""), None);
+        assert_eq!(link_target("/content/wikipedia_en", ""), None);
+    }
+
+    #[test]
+    fn relative_links_stay_in_the_archive() {
+        assert_eq!(
+            link_target("Gravity", "A/Physics"),
+            article_html::resolve_entry_path("A/Physics", "Gravity").map(|path| (None, path))
+        );
+        assert_eq!(link_target("https://example.org/", "A/Physics"), None);
+    }
+}
@@ -0,0 +1,138 @@
+//! Extraction of an article's references/footnotes as structured data, so
+//! clients can render them in a separate panel. Understands MediaWiki's
+//! `ol.references` markup (Wikipedia and other mwoffliner ZIMs) as well as
+//! the generic `doc-endnotes`/`.footnotes` conventions.
+
+use crate::AppState;
+use crate::article_html;
+use actix_web::{HttpResponse, Responder, get, web};
+use scraper::{ElementRef, Html, Selector};
+use serde::Serialize;
+
+/// Lists of notes, most specific first; the first selector that matches
+/// anything wins.
+const REFERENCE_ITEMS: &[&str] = &[
+    "ol.references > li",
+    "[role=doc-endnotes] li",
+    "[role=doc-endnote]",
+    ".footnotes li",
+];
+
+#[derive(Serialize)]
+struct Reference {
+    /// Anchor of the note itself, e.g. `cite_note-3`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    text: String,
+    /// First external link inside the note.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    /// Anchors in the article body that cite this note.
+    backlinks: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ReferencesResponse {
+    title: String,
+    path: String,
+    references: Vec<Reference>,
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn is_backlink(element: ElementRef) -> bool {
+    let element = element.value();
+    element
+        .classes()
+        .any(|c| c == "mw-cite-backlink" || c == "footnote-backref")
+        || element.attr("role") == Some("doc-backlink")
+}
+
+fn parse_reference(
+    item: ElementRef,
+    backlink: &Selector,
+    body: &Selector,
+    link: &Selector,
+) -> Reference {
+    let backlinks: Vec<String> = item
+        .select(backlink)
+        .filter_map(|a| a.value().attr("href"))
+        .filter_map(|href| href.strip_prefix('#'))
+        .map(str::to_string)
+        .collect();
+
+    // Prefer the dedicated text span; otherwise use the whole item minus the
+    // back-reference arrows.
+    let text = match item.select(body).next() {
+        Some(body) => body.text().collect::<String>(),
+        None => item
+            .descendants()
+            .filter(|node| {
+                !node
+                    .ancestors()
+                    .filter_map(ElementRef::wrap)
+                    .any(is_backlink)
+            })
+            .filter_map(|node| node.value().as_text().map(|t| &**t))
+            .collect::<String>(),
+    };
+
+    let url = item
+        .select(link)
+        .filter_map(|a| a.value().attr("href"))
+        .find(|href| href.starts_with("http://") || href.starts_with("https://"))
+        .map(str::to_string);
+
+    Reference {
+        id: item.value().attr("id").map(str::to_string),
+        text: collapse_whitespace(&text),
+        url,
+        backlinks,
+    }
+}
+
+fn extract_references(html: &str) -> Vec<Reference> {
+    let document = Html::parse_document(html);
+    let backlink =
+        Selector::parse(".mw-cite-backlink a, a[role=doc-backlink], a.footnote-backref").unwrap();
+    let body = Selector::parse(".reference-text").unwrap();
+    let link = Selector::parse("a[href]").unwrap();
+
+    for items in REFERENCE_ITEMS {
+        let items = Selector::parse(items).unwrap();
+        let references: Vec<Reference> = document
+            .select(&items)
+            .map(|item| parse_reference(item, &backlink, &body, &link))
+            .filter(|r| !r.text.is_empty())
+            .collect();
+        if !references.is_empty() {
+            return references;
+        }
+    }
+    Vec::new()
+}
+
+#[get(
+    "/archives/{id}/references/{path:.*}",
+    wrap = "crate::load_shed::Shed::heavy()"
+)]
+async fn article_references(
+    path: web::Path<(String, String)>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let (id, entry_path) = path.into_inner();
+    let result = article_html::with_article(&state, &id, entry_path, |title, path, html| {
+        ReferencesResponse {
+            title: title.to_string(),
+            path: path.to_string(),
+            references: extract_references(html),
+        }
+    })
+    .await;
+    match result {
+        Ok(references) => HttpResponse::Ok().json(references),
+        Err(response) => response,
+    }
+}
@@ -0,0 +1,257 @@
+//! Admission control for searches. At most `max_concurrent_searches` run at
+//! once; the rest wait in per-client queues that are served round-robin, so a
+//! single client firing many queries cannot starve everyone else. Searches
+//! that cannot be queued or wait too long are rejected instead of piling up in
+//! actix's blocking pool until they time out.
+
+use crate::AppState;
+use actix_web::{HttpRequest, HttpResponse, Responder, get, web};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+#[derive(Debug)]
+pub enum QueueError {
+    Full,
+    TimedOut,
+}
+
+impl std::fmt::Display for QueueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueueError::Full => write!(f, "Search queue is full, try again later"),
+            QueueError::TimedOut => write!(f, "Timed out waiting for a search slot"),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct QueueLimits {
+    pub max_running: usize,
+    pub max_queued: usize,
+    pub timeout: Duration,
+}
+
+#[derive(Serialize)]
+pub struct QueueStatus {
+    pub running: usize,
+    pub max_running: usize,
+    pub queued: usize,
+    /// 1-based position of the caller's oldest waiting search, if any.
+    pub position: Option<usize>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    running: usize,
+    max_running: usize,
+    /// Waiting searches per client, in round-robin order.
+    clients: VecDeque<(String, VecDeque<oneshot::Sender<()>>)>,
+}
+
+impl QueueState {
+    /// Drops waiters whose request has gone away.
+    fn prune(&mut self) {
+        for (_, waiters) in self.clients.iter_mut() {
+            waiters.retain(|tx| !tx.is_closed());
+        }
+        self.clients.retain(|(_, waiters)| !waiters.is_empty());
+    }
+
+    fn queued(&self) -> usize {
+        self.clients.iter().map(|(_, w)| w.len()).sum()
+    }
+
+    /// 1-based position of `client`'s `nth` waiter in round-robin order.
+    fn position(&self, client: &str, nth: usize) -> Option<usize> {
+        let mut position = 0;
+        for round in 0..=nth {
+            for (name, waiters) in &self.clients {
+                if waiters.len() > round {
+                    position += 1;
+                    if name == client && round == nth {
+                        return Some(position);
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct SearchQueue {
+    state: Arc<Mutex<QueueState>>,
+}
+
+/// Held while a search runs; frees the slot for the next waiter on drop.
+pub struct SearchPermit {
+    queue: SearchQueue,
+    /// Where the search entered the queue, `None` if it started immediately.
+    pub queued_at: Option<usize>,
+}
+
+impl Drop for SearchPermit {
+    fn drop(&mut self) {
+        self.queue.release();
+    }
+}
+
+/// A queued search. If it is dropped after being handed a slot but before
+/// claiming it (the request was cancelled or timed out at that moment), the
+/// slot is passed on.
+struct Pending {
+    rx: oneshot::Receiver<()>,
+    queue: SearchQueue,
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.rx.close();
+        if self.rx.try_recv().is_ok() {
+            self.queue.release();
+        }
+    }
+}
+
+impl SearchQueue {
+    pub async fn acquire(
+        &self,
+        client: &str,
+        limits: QueueLimits,
+    ) -> Result<SearchPermit, QueueError> {
+        let (mut pending, position) = {
+            let mut state = self.state.lock().unwrap();
+            state.max_running = limits.max_running.max(1);
+            state.prune();
+            if state.running < state.max_running && state.clients.is_empty() {
+                state.running += 1;
+                return Ok(SearchPermit {
+                    queue: self.clone(),
+                    queued_at: None,
+                });
+            }
+            if state.queued() >= limits.max_queued {
+                return Err(QueueError::Full);
+            }
+
+            let (tx, rx) = oneshot::channel();
+            let nth = match state.clients.iter_mut().find(|(name, _)| name == client) {
+                Some((_, waiters)) => {
+                    waiters.push_back(tx);
+                    waiters.len() - 1
+                }
+                None => {
+                    state
+                        .clients
+                        .push_back((client.to_string(), VecDeque::from([tx])));
+                    0
+                }
+            };
+            let pending = Pending {
+                rx,
+                queue: self.clone(),
+            };
+            (pending, state.position(client, nth))
+        };
+
+        match tokio::time::timeout(limits.timeout, &mut pending.rx).await {
+            Ok(Ok(())) => Ok(SearchPermit {
+                queue: self.clone(),
+                queued_at: position,
+            }),
+            _ => Err(QueueError::TimedOut),
+        }
+    }
+
+    /// Hands the slot to the next client in turn, or frees it.
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.running <= state.max_running {
+            while let Some((client, mut waiters)) = state.clients.pop_front() {
+                let next = waiters.pop_front();
+                if !waiters.is_empty() {
+                    state.clients.push_back((client, waiters));
+                }
+                if next.is_some_and(|tx| tx.send(()).is_ok()) {
+                    return;
+                }
+            }
+        }
+        state.running -= 1;
+    }
+
+    pub fn status(&self, client: &str) -> QueueStatus {
+        let mut state = self.state.lock().unwrap();
+        state.prune();
+        QueueStatus {
+            running: state.running,
+            max_running: state.max_running,
+            queued: state.queued(),
+            position: state.position(client, 0),
+        }
+    }
+}
+
+/// Identifies the client for fairness purposes: the connecting address, or
+/// the one a trusted proxy forwarded the request for.
+pub fn client_key(req: &HttpRequest, state: &AppState) -> String {
+    client_address(req, &state.config.read().unwrap().trusted_proxies)
+}
+
+fn client_address(req: &HttpRequest, trusted_proxies: &[IpAddr]) -> String {
+    let peer = req.peer_addr().map(|addr| addr.ip());
+    if peer.is_some_and(|ip| trusted_proxies.contains(&ip)) {
+        if let Some(forwarded) = req.connection_info().realip_remote_addr() {
+            return forwarded.to_string();
+        }
+    }
+    peer.map(|ip| ip.to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+#[get("/search/queue")]
+async fn queue_status(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(state.search_queue.status(&client_key(&req, &state)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn request(peer: &str) -> HttpRequest {
+        TestRequest::default()
+            .peer_addr(peer.parse().unwrap())
+            .insert_header(("X-Forwarded-For", "203.0.113.7"))
+            .to_http_request()
+    }
+
+    #[test]
+    fn forwarded_header_ignored_from_untrusted_peers() {
+        assert_eq!(
+            client_address(&request("198.51.100.1:5000"), &[]),
+            "198.51.100.1"
+        );
+    }
+
+    #[test]
+    fn forwarded_header_used_from_trusted_proxies() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        assert_eq!(
+            client_address(&request("10.0.0.1:5000"), &[proxy]),
+            "203.0.113.7"
+        );
+    }
+
+    #[test]
+    fn client_port_is_not_part_of_the_key() {
+        assert_eq!(
+            client_address(&request("198.51.100.1:5000"), &[]),
+            client_address(&request("198.51.100.1:5001"), &[])
+        );
+    }
+}
@@ -0,0 +1,41 @@
+//! Anonymous browser sessions, identified by a random id in a cookie. Used to
+//! keep per-user data (search history, preferences) without accounts.
+
+use actix_web::cookie::{Cookie, SameSite, time::Duration};
+use actix_web::{HttpRequest, HttpResponseBuilder};
+
+pub const SESSION_COOKIE: &str = "zv_session";
+
+/// The caller's session id, plus a cookie to set on the response when the
+/// request did not carry one yet.
+pub fn session_id(req: &HttpRequest) -> (String, Option<Cookie<'static>>) {
+    if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+        if !cookie.value().is_empty() {
+            return (cookie.value().to_string(), None);
+        }
+    }
+    let id = uuid::Uuid::new_v4().to_string();
+    let cookie = cookie(&id);
+    (id, Some(cookie))
+}
+
+/// Cookie binding the browser to session `id`.
+pub fn cookie(id: &str) -> Cookie<'static> {
+    Cookie::build(SESSION_COOKIE, id.to_string())
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .max_age(Duration::days(365))
+        .finish()
+}
+
+/// A response builder carrying the session cookie when one was just issued.
+pub fn with_session(
+    cookie: Option<Cookie<'static>>,
+    mut response: HttpResponseBuilder,
+) -> HttpResponseBuilder {
+    if let Some(cookie) = cookie {
+        response.cookie(cookie);
+    }
+    response
+}
@@ -0,0 +1,329 @@
+//! Smaller archives cut from a book. `POST /archives/{id}/subset` selects
+//! articles by path list, path/title prefix or full-text query and writes a
+//! new ZIM holding just those articles, the images, stylesheets and scripts
+//! they use, and the redirects pointing at them. Entries the archive's
+//! blocklist hides are left out, assets included.
+
+use crate::AppState;
+use crate::article_html;
+use crate::blocklist::Blocklist;
+use crate::charset;
+use crate::derive::{copy_metadata, is_blocked_path, main_path};
+use crate::jobs::{self, JobHandle};
+use crate::zim_writer::ZimWriter;
+use actix_web::{HttpResponse, Responder, post, web};
+use anyhow::{Result, anyhow, bail};
+use regex::Regex;
+use scraper::{Html, Selector};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use zim_rs::archive::Archive;
+
+/// Elements and the attribute holding the resource they embed. Other `<link>`s
+/// (canonical, alternate, next, ...) point at pages, not assets.
+const ASSET_ATTRIBUTES: &[(&str, &str)] = &[
+    ("img[src]", "src"),
+    ("link[rel~=\"stylesheet\"][href]", "href"),
+    ("link[rel~=\"icon\"][href]", "href"),
+    ("script[src]", "src"),
+    ("source[src]", "src"),
+    ("video[poster]", "poster"),
+];
+const DEFAULT_MAX_ARTICLES: u32 = 10_000;
+/// Upper bound on `max_articles`, so one request can't copy a whole archive.
+const MAX_ARTICLES: u32 = 50_000;
+
+/// `url(...)` references in stylesheets (fonts, background images).
+static CSS_URL: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"url\(\s*['"]?([^'")]+)['"]?\s*\)"#).unwrap());
+
+#[derive(Deserialize)]
+struct SubsetRequest {
+    /// Entry paths to include.
+    #[serde(default)]
+    paths: Vec<String>,
+    /// Includes articles whose path or title starts with this.
+    prefix: Option<String>,
+    /// Includes the results of a full-text search.
+    query: Option<String>,
+    /// Cap on selected articles, assets not counted; at most `MAX_ARTICLES`.
+    max_articles: Option<u32>,
+    /// Metadata overrides, as for `/derive`.
+    #[serde(default)]
+    metadata: BTreeMap<String, String>,
+}
+
+fn article_limit(requested: Option<u32>) -> usize {
+    requested
+        .unwrap_or(DEFAULT_MAX_ARTICLES)
+        .clamp(1, MAX_ARTICLES) as usize
+}
+
+/// Paths of the HTML articles the request selects.
+fn select_articles(
+    zim: &Archive,
+    request: &SubsetRequest,
+    blocklist: &Blocklist,
+    job: &JobHandle,
+) -> Result<Vec<String>> {
+    let max = article_limit(request.max_articles);
+    let mut selected = Vec::new();
+    let mut seen = HashSet::new();
+    let mut select = |path: String, title: String| {
+        if selected.len() < max && !blocklist.is_blocked(&title, &path) && seen.insert(path.clone())
+        {
+            selected.push(path);
+        }
+    };
+
+    for path in &request.paths {
+        let entry = zim
+            .get_entry_bypath_str(path)
+            .map_err(|_| anyhow!("Entry not found: {}", path))?;
+        let item = entry
+            .get_item(true)
+            .map_err(|e| anyhow!("Failed to read {}: {:?}", path, e))?;
+        select(item.get_path(), item.get_title());
+    }
+    if let Some(query) = &request.query {
+        job.set_phase("searching");
+        let (_, results) = crate::run_fulltext_search(zim, query, 0, max as u32, blocklist, None)?;
+        for result in results {
+            select(result.path, result.title);
+        }
+    }
+    if let Some(prefix) = request.prefix.as_deref().filter(|p| !p.is_empty()) {
+        job.set_phase("matching prefix");
+        for idx in 0..zim.get_entrycount() {
+            let Ok(entry) = zim.get_entry_bypath_index(idx) else {
+                continue;
+            };
+            if entry.is_redirect() {
+                continue;
+            }
+            let (path, title) = (entry.get_path(), entry.get_title());
+            if !path.starts_with(prefix) && !title.starts_with(prefix) {
+                continue;
+            }
+            let is_html = entry
+                .get_item(false)
+                .ok()
+                .and_then(|item| item.get_mimetype().ok())
+                .is_some_and(|m| m.starts_with("text/html"));
+            if is_html {
+                select(path, title);
+            }
+        }
+    }
+    Ok(selected)
+}
+
+/// Resources embedded by an HTML page or referenced from a stylesheet.
+fn referenced_assets(
+    path: &str,
+    mimetype: &str,
+    text: &str,
+    selectors: &[(Selector, &str)],
+) -> Vec<String> {
+    let hrefs: Vec<String> = if mimetype.starts_with("text/html") {
+        let document = Html::parse_document(text);
+        let mut hrefs: Vec<String> = selectors
+            .iter()
+            .flat_map(|(selector, attr)| {
+                document
+                    .select(selector)
+                    .filter_map(|element| element.value().attr(attr).map(str::to_string))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        // Inline styles and <style> blocks can pull in images too.
+        hrefs.extend(CSS_URL.captures_iter(text).map(|c| c[1].to_string()));
+        hrefs
+    } else if mimetype.starts_with("text/css") {
+        CSS_URL
+            .captures_iter(text)
+            .map(|c| c[1].to_string())
+            .collect()
+    } else {
+        Vec::new()
+    };
+    hrefs
+        .iter()
+        .filter_map(|href| article_html::resolve_entry_path(path, href))
+        .collect()
+}
+
+fn export_subset(
+    zim_path: &Path,
+    request: &SubsetRequest,
+    blocklist: &Blocklist,
+    job: &JobHandle,
+) -> Result<()> {
+    let zim = Archive::new(zim_path.to_str().unwrap())
+        .map_err(|e| anyhow!("Failed to open archive: {:?}", e))?;
+    let articles = select_articles(&zim, request, blocklist, job)?;
+    if articles.is_empty() {
+        bail!("No articles matched the selection");
+    }
+    let selectors: Vec<(Selector, &str)> = ASSET_ATTRIBUTES
+        .iter()
+        .map(|(selector, attr)| (Selector::parse(selector).unwrap(), *attr))
+        .collect();
+
+    job.set_phase("copying entries");
+    job.set_total(articles.len() as u64);
+    let mut writer = ZimWriter::new(job.output_dir())?;
+    let selected: HashSet<&str> = articles.iter().map(String::as_str).collect();
+    let mut included: HashSet<String> = articles.iter().cloned().collect();
+    let mut queue: VecDeque<String> = articles.iter().cloned().collect();
+    let mut copied_articles = 0;
+    while let Some(path) = queue.pop_front() {
+        if job.is_cancelled() {
+            bail!("Cancelled");
+        }
+        // Assets may themselves be redirects; copy what they point at.
+        let Ok(entry) = zim.get_entry_bypath_str(&path) else {
+            continue;
+        };
+        let Ok(item) = entry.get_item(true) else {
+            continue;
+        };
+        let (target, mimetype) = (item.get_path(), item.get_mimetype().unwrap_or_default());
+        // Pages reached as assets weren't selected; copying them (and what
+        // they embed) would grow the subset past `max_articles`.
+        if mimetype.starts_with("text/html") && !selected.contains(target.as_str()) {
+            included.remove(&path);
+            continue;
+        }
+        if target != path {
+            if is_blocked_path(&zim, blocklist, &target) {
+                continue;
+            }
+            writer.add_redirect(&path, &entry.get_title(), &target);
+            if included.insert(target.clone()) {
+                queue.push_back(target);
+            }
+            continue;
+        }
+        let blob = item
+            .get_data()
+            .map_err(|e| anyhow!("Failed to read {}: {:?}", path, e))?;
+        if charset::is_text(&mimetype) {
+            let text = charset::decode(blob.data(), &mimetype);
+            for asset in referenced_assets(&path, &mimetype, &text, &selectors) {
+                if zim.has_entry_bypath(&asset)
+                    && !is_blocked_path(&zim, blocklist, &asset)
+                    && included.insert(asset.clone())
+                {
+                    queue.push_back(asset);
+                }
+            }
+        }
+        writer.add_item(&path, &item.get_title(), &mimetype, blob.data())?;
+        if copied_articles < articles.len() {
+            copied_articles += 1;
+            job.set_processed(copied_articles as u64);
+        }
+    }
+
+    // Alternative titles, so title search finds the articles by them too.
+    job.set_phase("copying redirects");
+    for idx in 0..zim.get_entrycount() {
+        let Ok(entry) = zim.get_entry_bypath_index(idx) else {
+            continue;
+        };
+        if !entry.is_redirect()
+            || included.contains(&entry.get_path())
+            || blocklist.is_blocked(&entry.get_title(), &entry.get_path())
+        {
+            continue;
+        }
+        let Ok(target) = entry.get_redirect_entry() else {
+            continue;
+        };
+        if included.contains(&target.get_path())
+            && !target.is_redirect()
+            && !blocklist.is_blocked(&target.get_title(), &target.get_path())
+        {
+            writer.add_redirect(&entry.get_path(), &entry.get_title(), &target.get_path());
+        }
+    }
+
+    job.set_phase("writing archive");
+    copy_metadata(&zim, &mut writer, &request.metadata, false)?;
+    let main = main_path(&zim)
+        .filter(|path| included.contains(path))
+        .unwrap_or_else(|| articles[0].clone());
+    writer.set_main_path(&main);
+    let file_name = format!(
+        "{}_subset.zim",
+        zim_path
+            .file_stem()
+            .and_then(|n| n.to_str())
+            .unwrap_or("archive")
+    );
+    writer.finish(&job.output_dir().join(&file_name))?;
+    job.add_output_file(&file_name);
+    Ok(())
+}
+
+#[post("/archives/{id}/subset", wrap = "crate::load_shed::Shed::job()")]
+async fn export_subset_archive(
+    path: web::Path<String>,
+    req: web::Json<SubsetRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let Some(zim_path): Option<PathBuf> = state.archive_path(&id) else {
+        return HttpResponse::NotFound().json(json!({"error": "Archive not found"}));
+    };
+    let request = req.into_inner();
+    if request.paths.is_empty() && request.prefix.is_none() && request.query.is_none() {
+        return HttpResponse::BadRequest()
+            .json(json!({"error": "Select articles with paths, prefix or query"}));
+    }
+    let blocklist = state.blocklist_for(Some(&id));
+    let lease = state.leases.acquire(&zim_path);
+
+    match state
+        .jobs
+        .spawn("zim_subset", &jobs::jobs_dir(&state), move |job| {
+            let _lease = lease;
+            export_subset(&zim_path, &request, &blocklist, job)
+        }) {
+        Ok(job_id) => HttpResponse::Accepted().json(json!({ "job_id": job_id })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn article_limit_is_clamped() {
+        assert_eq!(article_limit(None), DEFAULT_MAX_ARTICLES as usize);
+        assert_eq!(article_limit(Some(0)), 1);
+        assert_eq!(article_limit(Some(u32::MAX)), MAX_ARTICLES as usize);
+    }
+
+    #[test]
+    fn only_asset_links_are_followed() {
+        let selectors: Vec<(Selector, &str)> = ASSET_ATTRIBUTES
+            .iter()
+            .map(|(selector, attr)| (Selector::parse(selector).unwrap(), *attr))
+            .collect();
+        let html = r#"<html><head>
+            <link rel="stylesheet" href="style.css">
+            <link rel="shortcut icon" href="favicon.png">
+            <link rel="canonical" href="Other_article">
+            <link rel="next" href="Next_article">
+            </head><body><img src="pic.png"></body></html>"#;
+        let mut assets = referenced_assets("A/Page", "text/html", html, &selectors);
+        assets.sort();
+        assert_eq!(assets, ["A/favicon.png", "A/pic.png", "A/style.css"]);
+    }
+}
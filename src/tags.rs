@@ -0,0 +1,186 @@
+//! Tags on library archives ("kids", "medicine", "offline-dev"), so large
+//! libraries can be narrowed down. Tags assigned through the API are kept in
+//! the manifest; an archive's own `Tags` metadata counts as well, except for
+//! Kiwix's internal `_key:value` entries. `/library.xml?tag=` and the Kiwix
+//! search's `books.filter.tag` only include archives carrying every tag asked
+//! for.
+
+use crate::AppState;
+use crate::library::Manifest;
+use actix_web::{HttpRequest, HttpResponse, Responder, get, put, web};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use zim_rs::archive::Archive;
+
+const MAX_TAGS_PER_ARCHIVE: usize = 32;
+const MAX_TAG_CHARS: usize = 64;
+
+/// Lowercased and trimmed form of `tag`, `None` if it can't be a tag. `;`
+/// separates tags in `library.xml`, so it is not allowed inside one.
+pub fn normalize(tag: &str) -> Option<String> {
+    let tag = tag.trim().to_lowercase();
+    let valid = !tag.is_empty()
+        && tag.chars().count() <= MAX_TAG_CHARS
+        && !tag.chars().any(|c| c == ';' || c.is_control());
+    valid.then_some(tag)
+}
+
+/// Tags asked for in query values, each of which may hold several separated
+/// by `;` as in Kiwix's catalog filters.
+pub fn wanted<'a>(values: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    values
+        .into_iter()
+        .flat_map(|value| value.split(';'))
+        .filter_map(normalize)
+        .collect()
+}
+
+/// The archive's own `Tags` metadata, without Kiwix's internal ones.
+fn metadata_tags(zim: &Archive) -> Vec<String> {
+    let raw = zim.get_metadata("Tags").unwrap_or_default();
+    raw.split(';')
+        .filter(|tag| !tag.trim_start().starts_with('_'))
+        .filter_map(normalize)
+        .collect()
+}
+
+/// Every tag of an archive: the assigned ones and its own.
+pub fn archive_tags(zim: &Archive, assigned: Option<&BTreeSet<String>>) -> BTreeSet<String> {
+    let mut tags: BTreeSet<String> = assigned.cloned().unwrap_or_default();
+    tags.extend(metadata_tags(zim));
+    tags
+}
+
+/// The archives carrying every tag in `wanted`; all of them when it's empty.
+pub fn filter(
+    archives: Vec<(String, PathBuf)>,
+    manifest: &Manifest,
+    wanted: &[String],
+) -> Vec<(String, PathBuf)> {
+    if wanted.is_empty() {
+        return archives;
+    }
+    archives
+        .into_iter()
+        .filter(|(id, path)| {
+            let assigned = manifest.tags.get(id);
+            // Assigned tags alone may already be enough.
+            if assigned.is_some_and(|tags| wanted.iter().all(|tag| tags.contains(tag))) {
+                return true;
+            }
+            let Ok(zim) = Archive::new(path.to_str().unwrap()) else {
+                return false;
+            };
+            let tags = archive_tags(&zim, assigned);
+            wanted.iter().all(|tag| tags.contains(tag))
+        })
+        .collect()
+}
+
+#[derive(Serialize)]
+struct TagSummary {
+    tag: String,
+    archives: Vec<String>,
+}
+
+/// Every tag in the library with the archives carrying it.
+#[get("/archives/tags")]
+async fn list_tags(state: web::Data<AppState>) -> impl Responder {
+    let archives: Vec<(String, PathBuf)> = state
+        .file_cache
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(id, path)| (id.clone(), path.clone()))
+        .collect();
+    let library_dir = state.library_dir();
+    let result = web::block(move || {
+        let manifest = Manifest::load(&library_dir)?;
+        let mut by_tag: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (id, path) in archives {
+            let assigned = manifest.tags.get(&id);
+            let tags = match Archive::new(path.to_str().unwrap()) {
+                Ok(zim) => archive_tags(&zim, assigned),
+                Err(_) => assigned.cloned().unwrap_or_default(),
+            };
+            for tag in tags {
+                by_tag.entry(tag).or_default().push(id.clone());
+            }
+        }
+        Ok::<_, anyhow::Error>(
+            by_tag
+                .into_iter()
+                .map(|(tag, mut archives)| {
+                    archives.sort();
+                    TagSummary { tag, archives }
+                })
+                .collect::<Vec<_>>(),
+        )
+    })
+    .await;
+    match result {
+        Ok(Ok(tags)) => HttpResponse::Ok().json(json!({ "tags": tags })),
+        Ok(Err(e)) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+    }
+}
+
+#[derive(Deserialize)]
+struct TagsRequest {
+    tags: Vec<String>,
+}
+
+/// Replaces the tags assigned to an archive. Its own `Tags` metadata is not
+/// affected.
+#[put("/archives/{id}/tags")]
+async fn set_archive_tags(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: web::Json<TagsRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    if !state.is_admin(&req) {
+        return HttpResponse::Unauthorized().json(json!({"error": "Invalid or missing token"}));
+    }
+    let id = path.into_inner();
+    if state.archive_path(&id).is_none() {
+        return HttpResponse::NotFound().json(json!({"error": "Archive not found"}));
+    }
+    let mut tags = BTreeSet::new();
+    for tag in &body.tags {
+        match normalize(tag) {
+            Some(tag) => {
+                tags.insert(tag);
+            }
+            None => {
+                return HttpResponse::BadRequest()
+                    .json(json!({"error": format!("Invalid tag: {:?}", tag)}));
+            }
+        }
+    }
+    if tags.len() > MAX_TAGS_PER_ARCHIVE {
+        return HttpResponse::BadRequest().json(json!({
+            "error": format!("At most {} tags per archive", MAX_TAGS_PER_ARCHIVE)
+        }));
+    }
+
+    let library_dir = state.library_dir();
+    let (tag_id, assigned) = (id.clone(), tags.clone());
+    let result = web::block(move || {
+        Manifest::update(&library_dir, |manifest| {
+            if assigned.is_empty() {
+                Ok(manifest.tags.remove(&tag_id).is_some())
+            } else {
+                Ok(manifest.tags.insert(tag_id, assigned.clone()) != Some(assigned))
+            }
+        })
+    })
+    .await;
+    match result {
+        Ok(Ok(())) => HttpResponse::Ok().json(json!({ "id": id, "tags": tags })),
+        Ok(Err(e)) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+    }
+}
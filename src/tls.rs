@@ -0,0 +1,42 @@
+//! TLS setup. Serving over TLS also enables HTTP/2 (negotiated through ALPN),
+//! so a page's many archive assets share one multiplexed connection.
+
+use crate::config::TlsConfig;
+use rustls::ServerConfig;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::Arc;
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Builds the rustls config from the PEM certificate chain and private key.
+pub fn server_config(tls: &TlsConfig) -> io::Result<ServerConfig> {
+    let mut cert_reader = BufReader::new(File::open(&tls.cert_file)?);
+    let certs: Vec<CertificateDer<'static>> =
+        rustls_pemfile::certs(&mut cert_reader).collect::<io::Result<_>>()?;
+    if certs.is_empty() {
+        return Err(invalid_data(format!(
+            "No certificates found in {}",
+            tls.cert_file.display()
+        )));
+    }
+
+    let mut key_reader = BufReader::new(File::open(&tls.key_file)?);
+    let key: PrivateKeyDer<'static> =
+        rustls_pemfile::private_key(&mut key_reader)?.ok_or_else(|| {
+            invalid_data(format!(
+                "No private key found in {}",
+                tls.key_file.display()
+            ))
+        })?;
+
+    ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| invalid_data(e.to_string()))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid_data(format!("Invalid certificate or key: {}", e)))
+}
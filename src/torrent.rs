@@ -0,0 +1,91 @@
+//! Fetching archives over BitTorrent (Kiwix publishes a torrent for every
+//! ZIM). Built only with the `torrent` feature.
+//!
+//! Torrents download into `<library_dir>/torrents`, which survives restarts:
+//! adding the same torrent again re-checks the pieces already on disk against
+//! their hashes and only fetches what is missing.
+
+use crate::jobs::JobHandle;
+use anyhow::{Result, anyhow, bail};
+use librqbit::{AddTorrent, AddTorrentOptions, Session, TorrentStatsState};
+use log::info;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const TORRENT_DIR: &str = "torrents";
+
+/// Whether `source` names a torrent rather than a plain download.
+pub fn is_torrent_source(source: &str) -> bool {
+    source.starts_with("magnet:") || source.ends_with(".torrent")
+}
+
+/// Downloads the torrent at `source` (a magnet link or `.torrent` URL) into
+/// `download_dir`, reporting progress in bytes. Returns the `.zim` files it
+/// contained.
+pub fn download(job: &JobHandle, source: &str, download_dir: &Path) -> Result<Vec<PathBuf>> {
+    fs::create_dir_all(download_dir)?;
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let session = Session::new(download_dir.to_path_buf()).await?;
+        let options = AddTorrentOptions {
+            // Keep whatever an earlier, interrupted run already verified.
+            overwrite: true,
+            // Files land at `download_dir/<their path in the torrent>`.
+            output_folder: Some(download_dir.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let handle = session
+            .add_torrent(AddTorrent::from_url(source), Some(options))
+            .await?
+            .into_handle()
+            .ok_or_else(|| anyhow!("Torrent was not added"))?;
+        let name = handle.name().unwrap_or_else(|| source.to_string());
+        info!("Downloading torrent {}", name);
+
+        let mut paused = false;
+        loop {
+            if job.is_cancelled() {
+                session.delete(handle.id().into(), false).await?;
+                session.stop().await;
+                bail!("Download of {} cancelled", name);
+            }
+            if job.is_paused() != paused {
+                paused = !paused;
+                if paused {
+                    session.pause(&handle).await?;
+                } else {
+                    session.unpause(&handle).await?;
+                }
+            }
+
+            let stats = handle.stats();
+            if let Some(error) = stats.error {
+                bail!("Torrent {} failed: {}", name, error);
+            }
+            job.set_phase(match stats.state {
+                TorrentStatsState::Initializing => "verifying",
+                TorrentStatsState::Paused => "paused",
+                _ => "downloading",
+            });
+            job.set_total(stats.total_bytes);
+            job.set_processed(stats.progress_bytes);
+            if stats.finished {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        session.stop().await;
+        info!("Torrent {} completed", name);
+        // Only this torrent's files: the directory is shared with others.
+        handle.with_metadata(|metadata| {
+            metadata
+                .file_infos
+                .iter()
+                .filter(|file| !file.attrs.padding)
+                .map(|file| download_dir.join(&file.relative_filename))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "zim"))
+                .collect()
+        })
+    })
+}
@@ -0,0 +1,294 @@
+//! Export of an archive's entries as WARC/1.0 files. Every entry becomes a
+//! synthetic `request` + `response` record pair targeting `<base_url><path>`,
+//! redirects become `301` responses, so the output can be fed to pywb and
+//! other web-archiving tools. Entries the archive's blocklist hides are left
+//! out.
+
+use crate::AppState;
+use crate::blocklist::Blocklist;
+use crate::jobs::{self, JobHandle};
+use actix_web::{HttpResponse, Responder, post, web};
+use anyhow::{Result, anyhow};
+use chrono::{NaiveDate, Utc};
+use serde::Deserialize;
+use serde_json::json;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+use zim_rs::archive::Archive;
+
+const DEFAULT_MAX_FILE_BYTES: u64 = 1024 * 1024 * 1024;
+
+#[derive(Deserialize)]
+struct WarcExportRequest {
+    /// Prefix for every record's target URI, e.g. `https://en.wikipedia.org/`.
+    base_url: Option<String>,
+    max_file_bytes: Option<u64>,
+}
+
+/// Splits output into `<name>-00000.warc`, `<name>-00001.warc`, ...
+struct WarcWriter<'a> {
+    job: &'a JobHandle,
+    name: String,
+    max_file_bytes: u64,
+    file_index: usize,
+    written: u64,
+    out: Option<BufWriter<File>>,
+}
+
+impl<'a> WarcWriter<'a> {
+    fn new(job: &'a JobHandle, name: &str, max_file_bytes: u64) -> WarcWriter<'a> {
+        WarcWriter {
+            job,
+            name: name.to_string(),
+            max_file_bytes,
+            file_index: 0,
+            written: 0,
+            out: None,
+        }
+    }
+
+    fn write_record(&mut self, headers: &[(&str, String)], block: &[u8]) -> Result<()> {
+        let mut record = String::from("WARC/1.0\r\n");
+        for (name, value) in headers {
+            record.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        record.push_str(&format!("Content-Length: {}\r\n\r\n", block.len()));
+        let record_len = (record.len() + block.len() + 4) as u64;
+
+        if self.out.is_none()
+            || (self.written > 0 && self.written + record_len > self.max_file_bytes)
+        {
+            self.rotate()?;
+        }
+        let out = self.out.as_mut().unwrap();
+        out.write_all(record.as_bytes())?;
+        out.write_all(block)?;
+        out.write_all(b"\r\n\r\n")?;
+        self.written += record_len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        if let Some(mut out) = self.out.take() {
+            out.flush()?;
+            self.file_index += 1;
+        }
+        let file_name = format!("{}-{:05}.warc", self.name, self.file_index);
+        let file = File::create(self.job.output_dir().join(&file_name))?;
+        self.out = Some(BufWriter::new(file));
+        self.written = 0;
+        self.job.add_output_file(&file_name);
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        if let Some(mut out) = self.out.take() {
+            out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+fn record_id() -> String {
+    format!("<urn:uuid:{}>", Uuid::new_v4())
+}
+
+/// WARC-Date for every record: the archive's `Date` metadata when present,
+/// since that is when the content was scraped, otherwise now.
+fn capture_date(zim: &Archive) -> String {
+    zim.get_metadata("Date")
+        .ok()
+        .and_then(|d| NaiveDate::parse_from_str(d.trim(), "%Y-%m-%d").ok())
+        .map(|d| format!("{}T00:00:00Z", d))
+        .unwrap_or_else(|| Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string())
+}
+
+fn default_base_url(zim: &Archive) -> String {
+    match zim.get_metadata("Source") {
+        Ok(source) if !source.trim().is_empty() => {
+            let source = source.trim().trim_end_matches('/');
+            if source.contains("://") {
+                format!("{}/", source)
+            } else {
+                format!("https://{}/", source)
+            }
+        }
+        _ => format!("zim://{}/", zim.get_uuid()),
+    }
+}
+
+/// Splits `https://host/some/prefix/` into `("host", "some/prefix/")`.
+fn split_base_url(base_url: &str) -> (&str, &str) {
+    let without_scheme = base_url
+        .split_once("://")
+        .map_or(base_url, |(_, rest)| rest);
+    without_scheme
+        .split_once('/')
+        .unwrap_or((without_scheme, ""))
+}
+
+/// Writes the records of one entry; blocked entries, and redirects to them,
+/// are left out.
+fn write_entry_records(
+    writer: &mut WarcWriter,
+    zim: &Archive,
+    idx: u32,
+    base_url: &str,
+    date: &str,
+    blocklist: &Blocklist,
+) -> Result<()> {
+    let entry = zim
+        .get_entry_bypath_index(idx)
+        .map_err(|e| anyhow!("Failed to read entry {}: {:?}", idx, e))?;
+    let path = entry.get_path();
+    if blocklist.is_blocked(&entry.get_title(), &path) {
+        return Ok(());
+    }
+    let target_uri = format!("{}{}", base_url, path);
+
+    let response = if entry.is_redirect() {
+        let target = entry
+            .get_redirect_entry()
+            .map_err(|e| anyhow!("Failed to resolve redirect {}: {:?}", path, e))?;
+        if blocklist.is_blocked(&target.get_title(), &target.get_path()) {
+            return Ok(());
+        }
+        format!(
+            "HTTP/1.1 301 Moved Permanently\r\nLocation: {}{}\r\nContent-Length: 0\r\n\r\n",
+            base_url,
+            target.get_path()
+        )
+        .into_bytes()
+    } else {
+        let item = entry
+            .get_item(false)
+            .map_err(|e| anyhow!("Failed to read item {}: {:?}", path, e))?;
+        let mimetype = item
+            .get_mimetype()
+            .unwrap_or_else(|_| "application/octet-stream".to_string());
+        let blob = item
+            .get_data()
+            .map_err(|e| anyhow!("Failed to read data of {}: {:?}", path, e))?;
+        let data = blob.data();
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+            mimetype,
+            data.len()
+        )
+        .into_bytes();
+        response.extend_from_slice(data);
+        response
+    };
+
+    let response_id = record_id();
+    writer.write_record(
+        &[
+            ("WARC-Type", "response".to_string()),
+            ("WARC-Record-ID", response_id.clone()),
+            ("WARC-Date", date.to_string()),
+            ("WARC-Target-URI", target_uri.clone()),
+            (
+                "Content-Type",
+                "application/http; msgtype=response".to_string(),
+            ),
+        ],
+        &response,
+    )?;
+
+    let (host, base_path) = split_base_url(base_url);
+    let request = format!(
+        "GET /{}{} HTTP/1.1\r\nHost: {}\r\n\r\n",
+        base_path, path, host
+    );
+    writer.write_record(
+        &[
+            ("WARC-Type", "request".to_string()),
+            ("WARC-Record-ID", record_id()),
+            ("WARC-Date", date.to_string()),
+            ("WARC-Target-URI", target_uri),
+            ("WARC-Concurrent-To", response_id),
+            (
+                "Content-Type",
+                "application/http; msgtype=request".to_string(),
+            ),
+        ],
+        request.as_bytes(),
+    )
+}
+
+fn export_archive(
+    job: &JobHandle,
+    zim_path: &Path,
+    base_url: Option<String>,
+    max_file_bytes: u64,
+    blocklist: &Blocklist,
+) -> Result<()> {
+    let zim = Archive::new(zim_path.to_str().unwrap())
+        .map_err(|e| anyhow!("Failed to open archive: {:?}", e))?;
+    let base_url = base_url.unwrap_or_else(|| default_base_url(&zim));
+    let date = capture_date(&zim);
+    let total = zim.get_all_entrycount();
+    job.set_total(total as u64);
+    job.set_phase("exporting");
+
+    let name = zim_path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("archive");
+    let mut writer = WarcWriter::new(job, name, max_file_bytes);
+
+    let warcinfo = format!(
+        "software: Zim-viewer/{}\r\nformat: WARC File Format 1.0\r\nsource-archive: {}\r\n",
+        env!("CARGO_PKG_VERSION"),
+        zim_path.file_name().and_then(|s| s.to_str()).unwrap_or("")
+    );
+    writer.write_record(
+        &[
+            ("WARC-Type", "warcinfo".to_string()),
+            ("WARC-Record-ID", record_id()),
+            (
+                "WARC-Date",
+                Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+            ),
+            ("Content-Type", "application/warc-fields".to_string()),
+        ],
+        warcinfo.as_bytes(),
+    )?;
+
+    for idx in 0..total {
+        write_entry_records(&mut writer, &zim, idx, &base_url, &date, blocklist)?;
+        if idx % 100 == 0 {
+            job.set_processed(idx as u64);
+        }
+    }
+    job.set_processed(total as u64);
+    writer.finish()
+}
+
+#[post("/archives/{id}/export/warc", wrap = "crate::load_shed::Shed::job()")]
+async fn export_warc(
+    path: web::Path<String>,
+    req: web::Json<WarcExportRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let Some(zim_path): Option<PathBuf> = state.archive_path(&id) else {
+        return HttpResponse::NotFound().json(json!({"error": "Archive not found"}));
+    };
+    let blocklist = state.blocklist_for(Some(&id));
+    let lease = state.leases.acquire(&zim_path);
+    let base_url = req.base_url.clone();
+    let max_file_bytes = req.max_file_bytes.unwrap_or(DEFAULT_MAX_FILE_BYTES).max(1);
+
+    match state
+        .jobs
+        .spawn("warc_export", &jobs::jobs_dir(&state), move |job| {
+            let _lease = lease;
+            export_archive(job, &zim_path, base_url, max_file_bytes, &blocklist)
+        }) {
+        Ok(job_id) => HttpResponse::Accepted().json(json!({ "job_id": job_id })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+    }
+}
@@ -0,0 +1,145 @@
+//! Polls the library roots for archives that were added, removed or replaced
+//! on disk (`library_poll_secs` in the config). A file whose size or mtime
+//! changed is hidden from the library until it stops changing, so a dump
+//! being copied over an old one is never read half-written; once stable it
+//! is served again. Whatever pointed at the old file (view counts, the open
+//! archive, the upload list) is dropped as soon as it starts changing, and
+//! `/search` and `/browse` refuse it while it is hidden. In cluster mode only
+//! the indexer polls, and publishes what it finds to the other instances.
+
+use crate::cluster::{self, Change};
+use crate::{AppState, load_library};
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// How often a disabled watcher checks whether it was enabled by a reload.
+const DISABLED_RECHECK: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct FileStamp {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+fn stamp(path: &Path) -> Option<FileStamp> {
+    let metadata = fs::metadata(path).ok()?;
+    Some(FileStamp {
+        len: metadata.len(),
+        modified: metadata.modified().ok(),
+    })
+}
+
+struct Watcher {
+    state: AppState,
+    known: HashMap<PathBuf, FileStamp>,
+    /// The archive a kiosk was started with; it is never rescanned for.
+    kiosk: Option<HashMap<String, PathBuf>>,
+    /// Archives seen changing on the last poll, held back until stable.
+    pending: HashSet<PathBuf>,
+}
+
+impl Watcher {
+    /// Archives currently on disk. A kiosk keeps its single archive.
+    fn on_disk(&self) -> HashMap<String, PathBuf> {
+        if let Some(kiosk) = &self.kiosk {
+            return kiosk.clone();
+        }
+        let roots = self.state.config.read().unwrap().library_roots();
+        match load_library(&roots) {
+            Ok(archives) => archives,
+            Err(e) => {
+                warn!("Failed to rescan library: {}", e);
+                self.state.file_cache.lock().unwrap().clone()
+            }
+        }
+    }
+
+    fn poll(&mut self) {
+        let on_disk = self.on_disk();
+        let mut file_cache = self.state.file_cache.lock().unwrap();
+        let mut changes = Vec::new();
+        // Archives whose old state is dropped once `file_cache` is unlocked.
+        let mut forgotten = Vec::new();
+
+        for (id, path) in &on_disk {
+            let Some(current) = stamp(path) else {
+                continue;
+            };
+            match self.known.insert(path.clone(), current) {
+                // Unchanged since the last poll.
+                Some(previous) if previous == current => {
+                    if self.pending.remove(path) {
+                        info!("Archive {} changed on disk, serving it again", id);
+                        file_cache.insert(id.clone(), path.clone());
+                        changes.push(Change::Added(id.clone(), path.clone()));
+                    }
+                }
+                // Registered elsewhere (upload, download, import) meanwhile.
+                None if file_cache.get(id) == Some(path) => {}
+                Some(_) | None => {
+                    if self.pending.insert(path.clone()) {
+                        info!("Archive {} is changing on disk, hiding it until stable", id);
+                    }
+                    if let Some(old_path) = file_cache.remove(id) {
+                        forgotten.push((id.clone(), old_path));
+                        changes.push(Change::Removed(id.clone()));
+                    }
+                }
+            }
+        }
+
+        if self.kiosk.is_none() {
+            let removed: Vec<String> = file_cache
+                .iter()
+                .filter(|(id, path)| !on_disk.contains_key(*id) && !path.exists())
+                .map(|(id, _)| id.clone())
+                .collect();
+            for id in removed {
+                info!("Archive {} was removed from disk", id);
+                forgotten.extend(file_cache.remove_entry(&id));
+                changes.push(Change::Removed(id));
+            }
+            self.known.retain(|path, _| path.exists());
+            self.pending.retain(|path| path.exists());
+        }
+        drop(file_cache);
+        for (id, path) in forgotten {
+            self.state.forget_archive(&id, &path);
+        }
+        cluster::publish(&self.state, changes);
+    }
+}
+
+/// Starts polling in the background. The archives loaded at startup are
+/// taken as the baseline.
+pub fn spawn(state: AppState) {
+    let archives = state.file_cache.lock().unwrap().clone();
+    let known = archives
+        .values()
+        .filter_map(|path| Some((path.clone(), stamp(path)?)))
+        .collect();
+    let mut watcher = Watcher {
+        kiosk: state.kiosk_archive.is_some().then_some(archives),
+        state,
+        known,
+        pending: HashSet::new(),
+    };
+    thread::spawn(move || {
+        loop {
+            let (interval, indexer) = {
+                let config = watcher.state.config.read().unwrap();
+                (config.library_poll_secs, config.is_indexer())
+            };
+            if interval == 0 || !indexer {
+                thread::sleep(DISABLED_RECHECK);
+                continue;
+            }
+            thread::sleep(Duration::from_secs(interval));
+            watcher.poll();
+        }
+    });
+}
@@ -0,0 +1,315 @@
+//! Read-only WebDAV (class 1) view of the loaded archives, mounted at `/dav`.
+//! Each archive is a top-level collection named after its id; inside it the
+//! entries are laid out by path as described in `archive_tree`.
+
+use crate::AppState;
+use crate::archive_tree::{self, DirEntry, Node};
+use crate::blocklist::{self, Blocklist};
+use crate::load_shed::Shed;
+use actix_web::http::{Method, StatusCode};
+use actix_web::{HttpRequest, HttpResponse, web};
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Utc};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use zim_rs::archive::Archive;
+
+const DAV_PREFIX: &str = "/dav";
+/// Children listed by a Depth-1 PROPFIND; larger directories are cut off and
+/// answered with `TRUNCATED_HEADER`.
+const MAX_LISTED_CHILDREN: usize = 5000;
+const TRUNCATED_HEADER: &str = "X-Listing-Truncated";
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    let propfind = Method::from_bytes(b"PROPFIND").unwrap();
+    for pattern in [
+        DAV_PREFIX.to_string(),
+        format!("{}/{{tail:.*}}", DAV_PREFIX),
+    ] {
+        cfg.service(
+            web::resource(pattern)
+                .route(web::method(Method::OPTIONS).to(options))
+                .route(
+                    web::method(propfind.clone())
+                        .to(propfind_handler)
+                        .wrap(Shed::heavy()),
+                )
+                .route(web::get().to(get_handler))
+                .route(web::head().to(get_handler))
+                .default_service(web::to(read_only)),
+        );
+    }
+}
+
+async fn options() -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(("DAV", "1"))
+        .insert_header(("Allow", "OPTIONS, PROPFIND, GET, HEAD"))
+        .finish()
+}
+
+async fn read_only() -> HttpResponse {
+    HttpResponse::MethodNotAllowed()
+        .insert_header(("Allow", "OPTIONS, PROPFIND, GET, HEAD"))
+        .body("This WebDAV share is read-only")
+}
+
+/// Splits `/dav/<id>/<entry path>` into the archive id and the entry path.
+fn split_dav_path(req: &HttpRequest) -> (Option<String>, String) {
+    let tail = req.match_info().get("tail").unwrap_or("");
+    let tail = urlencoding::decode(tail)
+        .map(|s| s.into_owned())
+        .unwrap_or_else(|_| tail.to_string());
+    let tail = archive_tree::normalize(&tail);
+    match tail.split_once('/') {
+        Some((id, rest)) => (Some(id.to_string()), rest.to_string()),
+        None if tail.is_empty() => (None, String::new()),
+        None => (Some(tail.to_string()), String::new()),
+    }
+}
+
+fn http_date(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+fn modified_time(path: &Path) -> SystemTime {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .unwrap_or(SystemTime::UNIX_EPOCH)
+}
+
+fn href(segments: &[&str], is_dir: bool) -> String {
+    let mut href = DAV_PREFIX.to_string();
+    for segment in segments.iter().flat_map(|s| s.split('/')) {
+        if !segment.is_empty() {
+            href.push('/');
+            href.push_str(&urlencoding::encode(segment));
+        }
+    }
+    if is_dir {
+        href.push('/');
+    }
+    href
+}
+
+fn response_xml(href: &str, name: &str, node: &Node, modified: &str) -> String {
+    let props = match node {
+        Node::Dir => "<D:resourcetype><D:collection/></D:resourcetype>".to_string(),
+        Node::File { size, mimetype } => format!(
+            "<D:resourcetype/><D:getcontentlength>{}</D:getcontentlength>\
+             <D:getcontenttype>{}</D:getcontenttype>",
+            size,
+            html_escape::encode_text(mimetype)
+        ),
+    };
+    format!(
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop>\
+         <D:displayname>{}</D:displayname>{}<D:getlastmodified>{}</D:getlastmodified>\
+         </D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+        html_escape::encode_text(href),
+        html_escape::encode_text(name),
+        props,
+        modified
+    )
+}
+
+fn multistatus(responses: Vec<String>, truncated: bool) -> HttpResponse {
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">{}</D:multistatus>",
+        responses.concat()
+    );
+    let mut response = HttpResponse::build(StatusCode::MULTI_STATUS);
+    if truncated {
+        response.insert_header((TRUNCATED_HEADER, "true"));
+    }
+    response
+        .content_type("application/xml; charset=utf-8")
+        .body(body)
+}
+
+fn is_blocked(zim: &Archive, entry_path: &str, blocklist: &Blocklist) -> bool {
+    if blocklist.is_empty() {
+        return false;
+    }
+    let entry_path = archive_tree::normalize(entry_path);
+    let Ok(entry) = zim.get_entry_bypath_str(entry_path) else {
+        return blocklist.is_blocked("", entry_path);
+    };
+    if blocklist.is_blocked(&entry.get_title(), entry_path) {
+        return true;
+    }
+    // A redirect serves its target's data, so the target is checked as well.
+    entry.is_redirect()
+        && entry
+            .get_item(true)
+            .is_ok_and(|item| blocklist.is_blocked(&item.get_title(), &item.get_path()))
+}
+
+/// Builds the PROPFIND responses for one entry path inside an archive, and
+/// whether the listing was cut off at `MAX_LISTED_CHILDREN`.
+fn propfind_archive(
+    id: &str,
+    zim_path: &Path,
+    entry_path: &str,
+    depth_one: bool,
+    blocklist: &Blocklist,
+) -> Result<Option<(Vec<String>, bool)>> {
+    let zim = Archive::new(zim_path.to_str().unwrap())
+        .map_err(|e| anyhow!("Failed to open archive: {:?}", e))?;
+    let modified = http_date(modified_time(zim_path));
+
+    let Some(node) = archive_tree::stat(&zim, entry_path) else {
+        return Ok(None);
+    };
+    if matches!(node, Node::File { .. }) && is_blocked(&zim, entry_path, blocklist) {
+        return Ok(None);
+    }
+    let is_dir = matches!(node, Node::Dir);
+    let name = entry_path
+        .rsplit('/')
+        .next()
+        .filter(|n| !n.is_empty())
+        .unwrap_or(id);
+    let mut responses = vec![response_xml(
+        &href(&[id, entry_path], is_dir),
+        name,
+        &node,
+        &modified,
+    )];
+
+    let mut truncated = false;
+    if is_dir && depth_one {
+        let (children, next) =
+            archive_tree::list_dir_page(&zim, entry_path, None, MAX_LISTED_CHILDREN)?;
+        truncated = next.is_some();
+        for DirEntry { name, node } in children {
+            let child_path = format!("{}/{}", archive_tree::normalize(entry_path), name);
+            let is_dir = matches!(node, Node::Dir);
+            if !is_dir && is_blocked(&zim, &child_path, blocklist) {
+                continue;
+            }
+            responses.push(response_xml(
+                &href(&[id, &child_path], is_dir),
+                &name,
+                &node,
+                &modified,
+            ));
+        }
+    }
+    Ok(Some((responses, truncated)))
+}
+
+async fn propfind_handler(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    let depth_one = req
+        .headers()
+        .get("Depth")
+        .and_then(|v| v.to_str().ok())
+        .is_none_or(|d| d != "0");
+
+    let (id, entry_path) = split_dav_path(&req);
+    let Some(id) = id else {
+        // Root collection: one child collection per archive.
+        let mut responses = vec![response_xml(
+            &href(&[], true),
+            "dav",
+            &Node::Dir,
+            &http_date(SystemTime::now()),
+        )];
+        if depth_one {
+            let archives: Vec<(String, PathBuf)> = state
+                .file_cache
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(id, path)| (id.clone(), path.clone()))
+                .collect();
+            for (id, path) in archives {
+                responses.push(response_xml(
+                    &href(&[&id], true),
+                    &id,
+                    &Node::Dir,
+                    &http_date(modified_time(&path)),
+                ));
+            }
+        }
+        return multistatus(responses, false);
+    };
+
+    let Some(zim_path) = state.archive_path(&id) else {
+        return HttpResponse::NotFound().body("Archive not found");
+    };
+    let blocklist = state.blocklist_for(Some(&id));
+    let result =
+        web::block(move || propfind_archive(&id, &zim_path, &entry_path, depth_one, &blocklist))
+            .await;
+    match result {
+        Ok(Ok(Some((responses, truncated)))) => multistatus(responses, truncated),
+        Ok(Ok(None)) => HttpResponse::NotFound().body("Entry not found"),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+enum DavFile {
+    Data { data: Vec<u8>, mimetype: String },
+    Blocked,
+    Missing,
+}
+
+fn read_archive_file(zim_path: &Path, entry_path: &str, blocklist: &Blocklist) -> Result<DavFile> {
+    let zim = Archive::new(zim_path.to_str().unwrap())
+        .map_err(|e| anyhow!("Failed to open archive: {:?}", e))?;
+    match archive_tree::stat(&zim, entry_path) {
+        Some(Node::File { .. }) if is_blocked(&zim, entry_path, blocklist) => Ok(DavFile::Blocked),
+        Some(Node::File { mimetype, .. }) => Ok(DavFile::Data {
+            data: archive_tree::read_file(&zim, entry_path)?,
+            mimetype,
+        }),
+        _ => Ok(DavFile::Missing),
+    }
+}
+
+async fn get_handler(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    let (Some(id), entry_path) = split_dav_path(&req) else {
+        return HttpResponse::Ok()
+            .content_type("text/plain; charset=utf-8")
+            .body("Read-only WebDAV share of the loaded ZIM archives");
+    };
+    let Some(zim_path) = state.archive_path(&id) else {
+        return HttpResponse::NotFound().body("Archive not found");
+    };
+
+    let modified = http_date(modified_time(&zim_path));
+    let blocklist = state.blocklist_for(Some(&id));
+    match web::block(move || read_archive_file(&zim_path, &entry_path, &blocklist)).await {
+        Ok(Ok(DavFile::Data { data, mimetype })) => HttpResponse::Ok()
+            .content_type(mimetype)
+            .insert_header(("Last-Modified", modified))
+            .body(data),
+        Ok(Ok(DavFile::Blocked)) => HttpResponse::Forbidden().body(blocklist::BLOCKED_MESSAGE),
+        Ok(Ok(DavFile::Missing)) => HttpResponse::NotFound().body("Entry not found"),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncated_listings_are_flagged() {
+        let cut = multistatus(Vec::new(), true);
+        assert_eq!(cut.status(), StatusCode::MULTI_STATUS);
+        assert_eq!(cut.headers().get(TRUNCATED_HEADER).unwrap(), "true");
+        assert!(
+            multistatus(Vec::new(), false)
+                .headers()
+                .get(TRUNCATED_HEADER)
+                .is_none()
+        );
+    }
+}
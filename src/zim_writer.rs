@@ -0,0 +1,528 @@
+//! Minimal writer for ZIM files (format 6.1, the `C`/`M`/`W`/`X` namespace
+//! layout), used by the jobs that derive new archives from existing ones.
+//! The bindings only read archives, so the file is assembled here: content is
+//! packed into clusters as it is added (zstd for text, stored as-is for
+//! media), then the header, dirents and pointer lists are written around them
+//! together with the MD5 checksum.
+//!
+//! Full-text indexes are not written; derived archives rely on title search.
+
+use crate::body_cache::is_compressible;
+use anyhow::{Context, Result, anyhow, bail};
+use md5::{Digest, Md5};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use tempfile::{NamedTempFile, tempfile_in};
+
+const MAGIC: u32 = 0x044D_495A;
+const MAJOR_VERSION: u16 = 6;
+const MINOR_VERSION: u16 = 1;
+const HEADER_SIZE: u64 = 80;
+const NO_PAGE: u32 = u32::MAX;
+const REDIRECT_MIMETYPE: u16 = 0xffff;
+const COMPRESSION_NONE: u8 = 1;
+const COMPRESSION_ZSTD: u8 = 5;
+/// Archives are written by jobs on the serving machine, where the top levels
+/// cost many times the CPU for a few percent of size.
+const ZSTD_LEVEL: i32 = 6;
+/// Clusters are closed once their blobs reach this size.
+const CLUSTER_SIZE: usize = 2 * 1024 * 1024;
+const TITLE_LISTING: &str = "listing/titleOrdered/v1";
+const TITLE_LISTING_MIMETYPE: &str = "application/octet-stream+zimlisting";
+
+enum Target {
+    Item {
+        mimetype: u16,
+        cluster: u32,
+        blob: u32,
+    },
+    Redirect {
+        namespace: u8,
+        path: String,
+    },
+}
+
+struct PendingEntry {
+    namespace: u8,
+    path: String,
+    title: String,
+    target: Target,
+}
+
+#[derive(Default)]
+struct OpenCluster {
+    blobs: Vec<Vec<u8>>,
+    size: usize,
+}
+
+pub struct ZimWriter {
+    /// Finished clusters, concatenated; copied into the archive by `finish`.
+    clusters: File,
+    cluster_offsets: Vec<u64>,
+    text: OpenCluster,
+    binary: OpenCluster,
+    /// Cluster number each open cluster will get, assigned when it is opened.
+    text_number: Option<u32>,
+    binary_number: Option<u32>,
+    mimetypes: Vec<String>,
+    entries: Vec<PendingEntry>,
+    main_path: Option<String>,
+}
+
+impl ZimWriter {
+    /// `tmp_dir` holds the clusters until the archive is written.
+    pub fn new(tmp_dir: &Path) -> Result<ZimWriter> {
+        Ok(ZimWriter {
+            clusters: tempfile_in(tmp_dir)?,
+            cluster_offsets: Vec::new(),
+            text: OpenCluster::default(),
+            binary: OpenCluster::default(),
+            text_number: None,
+            binary_number: None,
+            mimetypes: Vec::new(),
+            entries: Vec::new(),
+            main_path: None,
+        })
+    }
+
+    fn mimetype_index(&mut self, mimetype: &str) -> Result<u16> {
+        if let Some(idx) = self.mimetypes.iter().position(|m| m == mimetype) {
+            return Ok(idx as u16);
+        }
+        if self.mimetypes.len() >= REDIRECT_MIMETYPE as usize {
+            bail!("Too many distinct mimetypes");
+        }
+        self.mimetypes.push(mimetype.to_string());
+        Ok((self.mimetypes.len() - 1) as u16)
+    }
+
+    fn next_cluster_number(&self) -> u32 {
+        let open = [self.text_number, self.binary_number]
+            .iter()
+            .filter(|n| n.is_some())
+            .count();
+        (self.cluster_offsets.len() + open) as u32
+    }
+
+    /// Closes the open cluster of one kind, writing it to the cluster file.
+    /// Cluster numbers are handed out when a cluster opens, so they are
+    /// written in number order by closing the lower-numbered one first.
+    fn close_cluster(&mut self, compressed: bool) -> Result<()> {
+        let (number, cluster) = if compressed {
+            (self.text_number.take(), std::mem::take(&mut self.text))
+        } else {
+            (self.binary_number.take(), std::mem::take(&mut self.binary))
+        };
+        let Some(number) = number else {
+            return Ok(());
+        };
+        // The other open cluster has a lower number and must be written first.
+        let other = if compressed {
+            self.binary_number
+        } else {
+            self.text_number
+        };
+        if other.is_some_and(|other| other < number) {
+            self.close_cluster(!compressed)?;
+        }
+        debug_assert_eq!(number as usize, self.cluster_offsets.len());
+
+        let mut body = Vec::with_capacity(cluster.size + 4 * (cluster.blobs.len() + 1));
+        let mut offset = 4 * (cluster.blobs.len() as u32 + 1);
+        body.extend_from_slice(&offset.to_le_bytes());
+        for blob in &cluster.blobs {
+            offset += blob.len() as u32;
+            body.extend_from_slice(&offset.to_le_bytes());
+        }
+        for blob in &cluster.blobs {
+            body.extend_from_slice(blob);
+        }
+
+        let position = self.clusters.seek(SeekFrom::End(0))?;
+        self.cluster_offsets.push(position);
+        if compressed {
+            self.clusters.write_all(&[COMPRESSION_ZSTD])?;
+            zstd::stream::copy_encode(&body[..], &mut self.clusters, ZSTD_LEVEL)?;
+        } else {
+            self.clusters.write_all(&[COMPRESSION_NONE])?;
+            self.clusters.write_all(&body)?;
+        }
+        Ok(())
+    }
+
+    /// Stores `data` in a cluster, returning its cluster and blob number.
+    fn add_blob(&mut self, data: &[u8], compressed: bool) -> Result<(u32, u32)> {
+        let full = if compressed {
+            self.text.size
+        } else {
+            self.binary.size
+        };
+        if full > 0 && full + data.len() > CLUSTER_SIZE {
+            self.close_cluster(compressed)?;
+        }
+        let next = self.next_cluster_number();
+        let (number, cluster) = if compressed {
+            (self.text_number.get_or_insert(next), &mut self.text)
+        } else {
+            (self.binary_number.get_or_insert(next), &mut self.binary)
+        };
+        if cluster.size + data.len() > u32::MAX as usize / 2 {
+            bail!("Entry too large for a cluster");
+        }
+        cluster.blobs.push(data.to_vec());
+        cluster.size += data.len();
+        Ok((*number, cluster.blobs.len() as u32 - 1))
+    }
+
+    fn add_entry(&mut self, namespace: char, path: &str, title: &str, target: Target) {
+        self.entries.push(PendingEntry {
+            namespace: namespace as u8,
+            path: path.to_string(),
+            title: title.to_string(),
+            target,
+        });
+    }
+
+    /// Adds a content entry (namespace `C`).
+    pub fn add_item(&mut self, path: &str, title: &str, mimetype: &str, data: &[u8]) -> Result<()> {
+        self.add_namespaced_item('C', path, title, mimetype, data)
+    }
+
+    fn add_namespaced_item(
+        &mut self,
+        namespace: char,
+        path: &str,
+        title: &str,
+        mimetype: &str,
+        data: &[u8],
+    ) -> Result<()> {
+        let mimetype_idx = self.mimetype_index(mimetype)?;
+        let (cluster, blob) = self.add_blob(data, is_compressible(mimetype))?;
+        self.add_entry(
+            namespace,
+            path,
+            title,
+            Target::Item {
+                mimetype: mimetype_idx,
+                cluster,
+                blob,
+            },
+        );
+        Ok(())
+    }
+
+    /// Adds a content redirect from `path` to the content entry `target`.
+    pub fn add_redirect(&mut self, path: &str, title: &str, target: &str) {
+        self.add_entry(
+            'C',
+            path,
+            title,
+            Target::Redirect {
+                namespace: b'C',
+                path: target.to_string(),
+            },
+        );
+    }
+
+    /// Adds a metadata entry such as `Title` or `Illustration_48x48@1`.
+    pub fn add_metadata(&mut self, name: &str, mimetype: &str, value: &[u8]) -> Result<()> {
+        self.add_namespaced_item('M', name, "", mimetype, value)
+    }
+
+    /// The content entry served as the archive's main page.
+    pub fn set_main_path(&mut self, path: &str) {
+        self.main_path = Some(path.to_string());
+    }
+
+    /// Writes the archive to `out`, through a temporary file next to it.
+    pub fn finish(mut self, out: &Path) -> Result<()> {
+        if let Some(main_path) = self.main_path.take() {
+            self.add_entry(
+                'W',
+                "mainPage",
+                "",
+                Target::Redirect {
+                    namespace: b'C',
+                    path: main_path,
+                },
+            );
+        }
+        let mut keys: Vec<(u8, String)> = self
+            .entries
+            .iter()
+            .map(|e| (e.namespace, e.path.clone()))
+            .collect();
+        keys.push((b'X', TITLE_LISTING.to_string()));
+        keys.sort();
+        if let Some(pair) = keys.windows(2).find(|pair| pair[0] == pair[1]) {
+            bail!("Duplicate entry {}/{}", pair[0].0 as char, pair[0].1);
+        }
+        let index: HashMap<(u8, String), u32> = keys
+            .into_iter()
+            .enumerate()
+            .map(|(idx, key)| (key, idx as u32))
+            .collect();
+
+        // Front articles (HTML content) in title order, for title search.
+        let html: Vec<u16> = self
+            .mimetypes
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| m.starts_with("text/html"))
+            .map(|(idx, _)| idx as u16)
+            .collect();
+        let mut front: Vec<(&str, u32)> = self
+            .entries
+            .iter()
+            .filter(|e| {
+                e.namespace == b'C'
+                    && matches!(e.target, Target::Item { mimetype, .. } if html.contains(&mimetype))
+            })
+            .map(|e| {
+                let title = if e.title.is_empty() {
+                    &e.path
+                } else {
+                    &e.title
+                };
+                (title.as_str(), index[&(e.namespace, e.path.clone())])
+            })
+            .collect();
+        front.sort();
+        let listing: Vec<u8> = front
+            .iter()
+            .flat_map(|(_, idx)| idx.to_le_bytes())
+            .collect();
+        let mimetype = self.mimetype_index(TITLE_LISTING_MIMETYPE)?;
+        let (cluster, blob) = self.add_blob(&listing, false)?;
+        self.add_entry(
+            'X',
+            TITLE_LISTING,
+            "",
+            Target::Item {
+                mimetype,
+                cluster,
+                blob,
+            },
+        );
+        self.close_cluster(true)?;
+        self.close_cluster(false)?;
+
+        let mut entries = std::mem::take(&mut self.entries);
+        entries.sort_by(|a, b| (a.namespace, &a.path).cmp(&(b.namespace, &b.path)));
+        let main_page = index
+            .get(&(b'W', "mainPage".to_string()))
+            .copied()
+            .unwrap_or(NO_PAGE);
+        let mut by_title: Vec<u32> = (0..entries.len() as u32).collect();
+        by_title.sort_by(|&a, &b| {
+            let key = |e: &PendingEntry| {
+                let title = if e.title.is_empty() {
+                    &e.path
+                } else {
+                    &e.title
+                };
+                (e.namespace, title.clone())
+            };
+            key(&entries[a as usize]).cmp(&key(&entries[b as usize]))
+        });
+
+        let dirents: Vec<Vec<u8>> = entries
+            .iter()
+            .map(|e| dirent(e, &index))
+            .collect::<Result<_>>()?;
+        let mimetype_list: Vec<u8> = self
+            .mimetypes
+            .iter()
+            .flat_map(|m| m.bytes().chain([0]))
+            .chain([0])
+            .collect();
+
+        let entry_count = entries.len() as u64;
+        let path_ptr_pos = HEADER_SIZE + mimetype_list.len() as u64;
+        let title_ptr_pos = path_ptr_pos + 8 * entry_count;
+        let dirents_pos = title_ptr_pos + 4 * entry_count;
+        let dirents_size: u64 = dirents.iter().map(|d| d.len() as u64).sum();
+        let cluster_ptr_pos = dirents_pos + dirents_size;
+        let clusters_pos = cluster_ptr_pos + 8 * self.cluster_offsets.len() as u64;
+        let clusters_size = self.clusters.seek(SeekFrom::End(0))?;
+        let checksum_pos = clusters_pos + clusters_size;
+
+        let dir = out
+            .parent()
+            .ok_or_else(|| anyhow!("Invalid output path {}", out.display()))?;
+        fs::create_dir_all(dir)?;
+        let tmp = NamedTempFile::new_in(dir)?;
+        let mut writer = HashingWriter {
+            inner: BufWriter::new(tmp.as_file().try_clone()?),
+            hasher: Md5::new(),
+        };
+
+        writer.write_all(&MAGIC.to_le_bytes())?;
+        writer.write_all(&MAJOR_VERSION.to_le_bytes())?;
+        writer.write_all(&MINOR_VERSION.to_le_bytes())?;
+        writer.write_all(uuid::Uuid::new_v4().as_bytes())?;
+        writer.write_all(&(entry_count as u32).to_le_bytes())?;
+        writer.write_all(&(self.cluster_offsets.len() as u32).to_le_bytes())?;
+        writer.write_all(&path_ptr_pos.to_le_bytes())?;
+        writer.write_all(&title_ptr_pos.to_le_bytes())?;
+        writer.write_all(&cluster_ptr_pos.to_le_bytes())?;
+        writer.write_all(&HEADER_SIZE.to_le_bytes())?;
+        writer.write_all(&main_page.to_le_bytes())?;
+        writer.write_all(&NO_PAGE.to_le_bytes())?;
+        writer.write_all(&checksum_pos.to_le_bytes())?;
+        writer.write_all(&mimetype_list)?;
+
+        let mut offset = dirents_pos;
+        for dirent in &dirents {
+            writer.write_all(&offset.to_le_bytes())?;
+            offset += dirent.len() as u64;
+        }
+        for idx in &by_title {
+            writer.write_all(&idx.to_le_bytes())?;
+        }
+        for dirent in &dirents {
+            writer.write_all(dirent)?;
+        }
+        for cluster_offset in &self.cluster_offsets {
+            writer.write_all(&(clusters_pos + cluster_offset).to_le_bytes())?;
+        }
+        self.clusters.seek(SeekFrom::Start(0))?;
+        io::copy(&mut BufReader::new(&self.clusters), &mut writer)?;
+
+        let HashingWriter { mut inner, hasher } = writer;
+        inner.write_all(&hasher.finalize())?;
+        inner.flush()?;
+        drop(inner);
+        tmp.persist(out)
+            .with_context(|| format!("Failed to write {}", out.display()))?;
+        Ok(())
+    }
+}
+
+fn dirent(entry: &PendingEntry, index: &HashMap<(u8, String), u32>) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(16 + entry.path.len() + entry.title.len() + 2);
+    match &entry.target {
+        Target::Item {
+            mimetype,
+            cluster,
+            blob,
+        } => {
+            out.extend_from_slice(&mimetype.to_le_bytes());
+            out.push(0); // no extra parameters
+            out.push(entry.namespace);
+            out.extend_from_slice(&0u32.to_le_bytes()); // revision
+            out.extend_from_slice(&cluster.to_le_bytes());
+            out.extend_from_slice(&blob.to_le_bytes());
+        }
+        Target::Redirect { namespace, path } => {
+            let target = index.get(&(*namespace, path.clone())).ok_or_else(|| {
+                anyhow!("Redirect {} points to missing entry {}", entry.path, path)
+            })?;
+            out.extend_from_slice(&REDIRECT_MIMETYPE.to_le_bytes());
+            out.push(0);
+            out.push(entry.namespace);
+            out.extend_from_slice(&0u32.to_le_bytes());
+            out.extend_from_slice(&target.to_le_bytes());
+        }
+    }
+    out.extend_from_slice(entry.path.as_bytes());
+    out.push(0);
+    // An empty title means "same as the path".
+    if entry.title != entry.path {
+        out.extend_from_slice(entry.title.as_bytes());
+    }
+    out.push(0);
+    Ok(out)
+}
+
+/// Feeds everything written through it into the archive checksum.
+struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Md5,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zim_rs::archive::Archive;
+
+    fn write_sample(dir: &Path) -> std::path::PathBuf {
+        let mut writer = ZimWriter::new(dir).unwrap();
+        writer
+            .add_item("A/Alpha", "Alpha", "text/html", b"<p>alpha</p>")
+            .unwrap();
+        writer
+            .add_item("I/dot.png", "", "image/png", &[0x89, b'P', b'N', b'G'])
+            .unwrap();
+        writer.add_redirect("A/First", "First", "A/Alpha");
+        writer
+            .add_metadata("Title", "text/plain", b"Sample")
+            .unwrap();
+        writer.set_main_path("A/Alpha");
+        let out = dir.join("sample.zim");
+        writer.finish(&out).unwrap();
+        out
+    }
+
+    fn temp_dir() -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("zim-writer-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn header_and_checksum_are_consistent() {
+        let dir = temp_dir();
+        let raw = fs::read(write_sample(&dir)).unwrap();
+        let u32_at = |at: usize| u32::from_le_bytes(raw[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(raw[at..at + 8].try_into().unwrap());
+        assert_eq!(u32_at(0), MAGIC);
+        // Two items, a redirect, metadata, the main page and the title listing.
+        assert_eq!(u32_at(24), 6);
+        let checksum_pos = u64_at(72) as usize;
+        assert_eq!(raw.len(), checksum_pos + 16);
+        assert_eq!(
+            &raw[checksum_pos..],
+            Md5::digest(&raw[..checksum_pos]).as_slice()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Needs the real libzim behind the bindings.
+    #[test]
+    fn libzim_reads_written_archives() {
+        let dir = temp_dir();
+        let out = write_sample(&dir);
+        let zim = Archive::new(out.to_str().unwrap()).unwrap();
+        assert_eq!(zim.get_metadata("Title").unwrap(), "Sample");
+        assert!(zim.has_checksum());
+
+        let alpha = zim.get_entry_bypath_str("A/Alpha").unwrap();
+        let item = alpha.get_item(false).unwrap();
+        assert_eq!(item.get_mimetype().unwrap(), "text/html");
+        assert_eq!(item.get_data().unwrap().data(), b"<p>alpha</p>");
+        let image = zim.get_entry_bypath_str("I/dot.png").unwrap();
+        let image = image.get_item(false).unwrap();
+        assert_eq!(image.get_data().unwrap().data(), &[0x89, b'P', b'N', b'G']);
+
+        let first = zim.get_entry_bypath_str("A/First").unwrap();
+        assert!(first.is_redirect());
+        assert_eq!(first.get_redirect_entry().unwrap().get_path(), "A/Alpha");
+        assert_eq!(zim.get_main_entry().unwrap().get_path(), "A/Alpha");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}